@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use gpio::GpioValue;
+use serde::Deserialize;
+
+use crate::gpioline::{self, PinConfig};
+use crate::outsched::{OutputId, OutputScheduler};
+
+const PWM_CLASS_DIR: &str = "/sys/class/pwm";
+/// Time for udev to set permissions of freshly exported PWM channel
+const PWM_EXPORT_DELAY: Duration = Duration::from_millis(100);
+/// Output enable of 74HC595 is active low
+const OE_ACTIVE: GpioValue = GpioValue::Low;
+pub const BRIGHTNESS_MAX: u8 = 100;
+
+/// Channel of hardware PWM, `/sys/class/pwm/pwmchip<chip>/pwm<channel>`
+#[derive(Deserialize, Debug, Clone)]
+pub struct HwPwmConfig {
+	chip: u32,
+	channel: u32
+}
+
+/// Brightness from time of day until next entry, `from` is "HH:MM" local time
+#[derive(Deserialize, Debug, Clone)]
+pub struct DimmingEntry {
+	from: String,
+	brightness: u8
+}
+
+/// Drives output enable line, brightness is percent of time outputs are enabled
+pub trait Dimmer: Send {
+	fn set(&mut self, percent: u8) -> Result<(), String>;
+}
+
+fn write_attr(path: &PathBuf, value: &str) -> Result<(), String> {
+	fs::write(path, value).map_err(|e| format!("Fail to write {}: {}", path.display(), e))
+}
+
+/// PWM of SoC through sysfs, output is high for `duty_cycle` of period, which disables outputs
+pub struct SysfsPwm {
+	dir: PathBuf,
+	period_ns: u64
+}
+
+impl SysfsPwm {
+	pub fn open(cfg: &HwPwmConfig, period: Duration) -> Result<Self, String> {
+		let chip = PathBuf::from(PWM_CLASS_DIR).join(format!("pwmchip{}", cfg.chip));
+		let dir = chip.join(format!("pwm{}", cfg.channel));
+		if !dir.exists() {
+			write_attr(&chip.join("export"), &cfg.channel.to_string())?;
+			thread::sleep(PWM_EXPORT_DELAY);
+		}
+		let period_ns = period.as_nanos() as u64;
+		// duty cycle may not exceed period at any moment
+		write_attr(&dir.join("duty_cycle"), "0")?;
+		write_attr(&dir.join("period"), &period_ns.to_string())?;
+		write_attr(&dir.join("enable"), "1")?;
+		Ok(Self { dir, period_ns })
+	}
+}
+
+impl Dimmer for SysfsPwm {
+	fn set(&mut self, percent: u8) -> Result<(), String> {
+		let off = self.period_ns * (BRIGHTNESS_MAX - percent.min(BRIGHTNESS_MAX)) as u64 / BRIGHTNESS_MAX as u64;
+		write_attr(&self.dir.join("duty_cycle"), &off.to_string())
+	}
+}
+
+/// Output enable GPIO toggled by `OutputScheduler`
+pub struct SoftPwm {
+	sched: OutputScheduler,
+	id: OutputId,
+	period: Duration
+}
+
+impl SoftPwm {
+	pub fn open(pin: &PinConfig, period: Duration) -> Result<Self, String> {
+		let out = gpioline::open_output(pin, OE_ACTIVE)
+			.map_err(|e| format!("Fail to open OE pin {}: {}", pin, e))?;
+		let mut sched = OutputScheduler::new();
+		let id = sched.add(out, OE_ACTIVE)?;
+		Ok(Self { sched, id, period })
+	}
+}
+
+impl Dimmer for SoftPwm {
+	fn set(&mut self, percent: u8) -> Result<(), String> {
+		self.sched.pwm(self.id, self.period, percent.min(BRIGHTNESS_MAX) as f64 / BRIGHTNESS_MAX as f64)
+	}
+}
+
+/// Minutes since midnight of "HH:MM"
+fn parse_time(s: &str) -> Result<u32, String> {
+	let (h, m) = s.split_once(':').ok_or_else(|| format!("Time '{}' is not HH:MM", s))?;
+	match (h.parse::<u32>(), m.parse::<u32>()) {
+		(Ok(h), Ok(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+		_ => Err(format!("Time '{}' is not HH:MM", s))
+	}
+}
+
+/// Dimming schedule ordered by time of day
+pub struct Schedule {
+	entries: Vec<(u32, u8)>
+}
+
+impl Schedule {
+	pub fn new(entries: &[DimmingEntry]) -> Result<Self, String> {
+		let mut parsed = Vec::with_capacity(entries.len());
+		for e in entries {
+			if e.brightness > BRIGHTNESS_MAX {
+				return Err(format!("Brightness {} at {} is above {}", e.brightness, e.from, BRIGHTNESS_MAX));
+			}
+			parsed.push((parse_time(&e.from)?, e.brightness));
+		}
+		parsed.sort();
+		Ok(Self { entries: parsed })
+	}
+
+	/// Brightness at minute of day, before the first entry the last one of previous day holds
+	pub fn at(&self, minute: u32) -> Option<u8> {
+		self.entries.iter().rev()
+			.find(|(from, _)| *from <= minute)
+			.or(self.entries.last())
+			.map(|(_, b)| *b)
+	}
+}
+
+/// Minutes since local midnight
+pub fn local_minute() -> u32 {
+	let now = unsafe { libc::time(std::ptr::null_mut()) };
+	let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+	// localtime_r only writes into `tm`, null result leaves it at midnight
+	unsafe { libc::localtime_r(&now, &mut tm) };
+	(tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(from: &str, brightness: u8) -> DimmingEntry {
+		DimmingEntry { from: from.to_string(), brightness }
+	}
+
+	#[test]
+	fn schedule_wraps_around_midnight() {
+		let sched = Schedule::new(&[entry("21:30", 20), entry("07:00", 100), entry("19:00", 60)]).unwrap();
+		assert_eq!(sched.at(0), Some(20));
+		assert_eq!(sched.at(7 * 60), Some(100));
+		assert_eq!(sched.at(20 * 60), Some(60));
+		assert_eq!(sched.at(23 * 60), Some(20));
+		assert_eq!(Schedule::new(&[]).unwrap().at(600), None);
+		assert!(Schedule::new(&[entry("24:00", 10)]).is_err());
+		assert!(Schedule::new(&[entry("12:00", 150)]).is_err());
+	}
+}
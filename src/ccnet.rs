@@ -54,7 +54,7 @@ mod status {
 	pub const RETURNED: u8 = 0x82;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy)]
 pub enum BaudRate {
 	Slow = 9600,
 	Fast = 19200
@@ -0,0 +1,170 @@
+use std::{thread, time::{Duration, Instant}};
+
+use serde::Deserialize;
+
+use crate::i2cbus::I2cBus;
+
+/// Internal write cycle is 5 ms max for 24Cxx, poll a bit longer
+const WRITE_CYCLE_TIMEOUT: Duration = Duration::from_millis(20);
+const ACK_POLL_DELAY: Duration = Duration::from_micros(500);
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum EepromModel {
+	C02,
+	C04,
+	C08,
+	C16,
+	C32,
+	C64,
+	C128,
+	C256,
+	C512
+}
+
+impl EepromModel {
+	/// Size in bytes
+	pub fn size(&self) -> u32 {
+		match self {
+			Self::C02 => 256,
+			Self::C04 => 512,
+			Self::C08 => 1024,
+			Self::C16 => 2048,
+			Self::C32 => 4096,
+			Self::C64 => 8192,
+			Self::C128 => 16384,
+			Self::C256 => 32768,
+			Self::C512 => 65536
+		}
+	}
+
+	pub fn page_size(&self) -> u32 {
+		match self {
+			Self::C02 => 8,
+			Self::C04 | Self::C08 | Self::C16 => 16,
+			Self::C32 | Self::C64 => 32,
+			Self::C128 | Self::C256 => 64,
+			Self::C512 => 128
+		}
+	}
+
+	/// Up to 24C16 word address is one byte, higher bits go to device address
+	fn wide_addr(&self) -> bool {
+		self.size() > 2048
+	}
+}
+
+/// 24Cxx serial EEPROM
+pub struct Eeprom {
+	model: EepromModel,
+	addr: u8
+}
+
+impl Eeprom {
+	pub fn new(model: EepromModel, addr: u8) -> Self {
+		Self { model, addr }
+	}
+
+	/// Device address and word address bytes for `offset`
+	fn address(&self, offset: u32) -> (u8, Vec<u8>) {
+		if self.model.wide_addr() {
+			(self.addr, vec![(offset >> 8) as u8, offset as u8])
+		} else {
+			(self.addr | ((offset >> 8) & 0x07) as u8, vec![offset as u8])
+		}
+	}
+
+	fn check_range(&self, offset: u32, len: usize) -> Result<(), String> {
+		if offset as usize + len > self.model.size() as usize {
+			Err(format!("Range {}..{} exceeds EEPROM size {}", offset, offset as usize + len, self.model.size()))
+		} else {
+			Ok(())
+		}
+	}
+
+	pub fn read(&self, bus: &mut dyn I2cBus, offset: u32, buf: &mut [u8]) -> Result<(), String> {
+		self.check_range(offset, buf.len())?;
+		// sequential read wraps inside 256 byte block on small chips, split at block edge
+		let block = if self.model.wide_addr() { self.model.size() } else { 256 };
+		let mut pos = 0;
+		while pos < buf.len() {
+			let cur = offset + pos as u32;
+			let len = ((block - cur % block) as usize).min(buf.len() - pos);
+			let (dev, word) = self.address(cur);
+			if let Err(e) = bus.write_read(dev, &word, &mut buf[pos..pos + len]) {
+				return Err(format!("Fail read EEPROM at {}: {}", cur, e));
+			}
+			pos += len;
+		}
+		Ok(())
+	}
+
+	/// Write data by pages, every page is followed by acknowledge polling
+	pub fn write(&self, bus: &mut dyn I2cBus, offset: u32, data: &[u8]) -> Result<(), String> {
+		self.check_range(offset, data.len())?;
+		let page = self.model.page_size();
+		let mut pos = 0;
+		while pos < data.len() {
+			let cur = offset + pos as u32;
+			let len = ((page - cur % page) as usize).min(data.len() - pos);
+			let (dev, mut buf) = self.address(cur);
+			buf.extend_from_slice(&data[pos..pos + len]);
+			if let Err(e) = bus.write(dev, &buf) {
+				return Err(format!("Fail write EEPROM at {}: {}", cur, e));
+			}
+			self.wait_ready(bus, dev)?;
+			pos += len;
+		}
+		Ok(())
+	}
+
+	/// Chip does not acknowledge its address until internal write cycle ends
+	fn wait_ready(&self, bus: &mut dyn I2cBus, dev: u8) -> Result<(), String> {
+		let start = Instant::now();
+		let (_, word) = self.address(0);
+		loop {
+			if bus.write(dev, &word).is_ok() {
+				return Ok(());
+			}
+			if start.elapsed() >= WRITE_CYCLE_TIMEOUT {
+				return Err(String::from("EEPROM write cycle timeout"));
+			}
+			thread::sleep(ACK_POLL_DELAY);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::i2cmock::{MockBus, Eeprom24};
+
+	fn bus() -> (MockBus, Arc<Mutex<Eeprom24>>) {
+		let chip = Arc::new(Mutex::new(Eeprom24::new(256, 8, 1)));
+		let mut bus = MockBus::new();
+		bus.attach(0x50, &chip);
+		(bus, chip)
+	}
+
+	#[test]
+	fn write_is_split_at_page_edges() {
+		let (mut bus, chip) = bus();
+		let eeprom = Eeprom::new(EepromModel::C02, 0x50);
+		let data: Vec<u8> = (0..20).collect();
+		eeprom.write(&mut bus, 5, &data).unwrap();
+		// 5..8, 8..16, 16..24, 24..25
+		assert_eq!(chip.lock().unwrap().writes, 4);
+		let mut buf = [0u8;20];
+		eeprom.read(&mut bus, 5, &mut buf).unwrap();
+		assert_eq!(&buf[..], &data[..]);
+	}
+
+	#[test]
+	fn range_beyond_size_is_rejected() {
+		let (mut bus, _) = bus();
+		let eeprom = Eeprom::new(EepromModel::C02, 0x50);
+		assert!(eeprom.write(&mut bus, 250, &[0u8;8]).is_err());
+		assert!(eeprom.read(&mut bus, 256, &mut [0u8;1]).is_err());
+	}
+}
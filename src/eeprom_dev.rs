@@ -0,0 +1,113 @@
+use crate::i2cbus::I2cBus;
+use crate::idstore::{IdStore, MachineRecord, SlotState};
+use crate::iobus::IobusConfig;
+
+/// Changes applied by `write`, counters may be set or incremented
+#[derive(Default)]
+pub struct RecordUpdate {
+	pub serial: Option<String>,
+	pub hw_rev: Option<u16>,
+	pub money: Option<u64>,
+	pub cycles: Option<u32>,
+	pub add_money: Option<u64>,
+	pub add_cycles: Option<u32>
+}
+
+fn open(config: &IobusConfig) -> Result<(Box<dyn I2cBus>, IdStore), String> {
+	let store = match &config.eeprom {
+		Some(cfg) => IdStore::new(cfg)?,
+		None => return Err(String::from("EEPROM is not configured"))
+	};
+	Ok((config.open_bus()?, store))
+}
+
+fn print_record(slot: u32, seq: u32, rec: &MachineRecord) {
+	println!("\tSlot: {}, sequence: {}", slot, seq);
+	println!("\tSerial number: {}", rec.serial);
+	println!("\tHardware revision: {}", rec.hw_rev);
+	println!("\tLifetime money: {}", rec.money);
+	println!("\tLifetime cycles: {}", rec.cycles);
+}
+
+pub fn read(config: &IobusConfig) -> Result<(), String> {
+	println!("\n[EEPROM] Read record..");
+	let (mut bus, store) = open(config)?;
+	match store.latest(bus.as_mut())? {
+		Some((slot, seq, rec)) => {
+			print_record(slot, seq, &rec);
+			Ok(())
+		},
+		None => Err(String::from("No valid record found"))
+	}
+}
+
+pub fn write(config: &IobusConfig, upd: &RecordUpdate) -> Result<(), String> {
+	println!("\n[EEPROM] Write record..");
+	let (mut bus, store) = open(config)?;
+	let mut rec = match store.latest(bus.as_mut())? {
+		Some((_, _, rec)) => rec,
+		None => {
+			println!("\tNo valid record, new one is created");
+			MachineRecord::default()
+		}
+	};
+	if let Some(serial) = &upd.serial {
+		rec.serial = serial.clone();
+	}
+	if let Some(hw_rev) = upd.hw_rev {
+		rec.hw_rev = hw_rev;
+	}
+	if let Some(money) = upd.money {
+		rec.money = money;
+	}
+	if let Some(cycles) = upd.cycles {
+		rec.cycles = cycles;
+	}
+	rec.money = match rec.money.checked_add(upd.add_money.unwrap_or(0)) {
+		Some(money) => money,
+		None => return Err(format!("Lifetime money {} overflows when added", rec.money))
+	};
+	rec.cycles = match rec.cycles.checked_add(upd.add_cycles.unwrap_or(0)) {
+		Some(cycles) => cycles,
+		None => return Err(format!("Lifetime cycles {} overflow when added", rec.cycles))
+	};
+	let slot = store.write(bus.as_mut(), &rec)?;
+	match store.latest(bus.as_mut())? {
+		Some((s, seq, r)) if s == slot && r == rec => {
+			print_record(s, seq, &r);
+			Ok(())
+		},
+		_ => Err(String::from("Written record is not the latest one"))
+	}
+}
+
+pub fn verify(config: &IobusConfig) -> Result<(), String> {
+	println!("\n[EEPROM] Verify record store..");
+	let (mut bus, store) = open(config)?;
+	let mut corrupted = 0;
+	let mut valid = 0;
+	for slot in 0..store.slots() {
+		match store.read_slot(bus.as_mut(), slot)? {
+			SlotState::Empty => println!("\tSlot {}: empty", slot),
+			SlotState::Valid(seq, rec) => {
+				valid += 1;
+				println!("\tSlot {}: seq {}, serial '{}', money {}, cycles {}", slot, seq, rec.serial, rec.money, rec.cycles);
+			},
+			SlotState::Corrupted => {
+				corrupted += 1;
+				println!("\tSlot {}: CRC error", slot);
+			}
+		}
+	}
+	if valid == 0 {
+		return Err(String::from("No valid record found"));
+	}
+	if let Some((slot, seq, _)) = store.latest(bus.as_mut())? {
+		println!("\tLatest record: slot {}, sequence {}", slot, seq);
+	}
+	if corrupted > 0 {
+		Err(format!("{} of {} slots are corrupted", corrupted, store.slots()))
+	} else {
+		Ok(())
+	}
+}
@@ -0,0 +1,204 @@
+use std::{thread, time::Duration};
+
+use serde::Deserialize;
+
+use crate::i2cbus::{I2cBus, read, read_reg, write};
+
+const DELAY_AFTER_TRANSFER: Duration = Duration::from_millis(10);
+
+mod mcp23017 {
+	pub const IODIRA: u8 = 0x00;
+	pub const IPOLA: u8 = 0x02;
+	pub const GPINTENA: u8 = 0x04;
+	pub const INTCONA: u8 = 0x08;
+	pub const IOCON: u8 = 0x0A;
+	pub const GPPUA: u8 = 0x0C;
+	pub const INTCAPA: u8 = 0x10;
+	pub const GPIOA: u8 = 0x12;
+	pub const OLATA: u8 = 0x14;
+
+	/// INTA/INTB mirrored, open-drain INT output
+	pub const IOCON_MIRROR_ODR: u8 = 0x44;
+}
+
+mod pca9555 {
+	pub const INPUT: u8 = 0x00;
+	pub const OUTPUT: u8 = 0x02;
+	pub const POLARITY: u8 = 0x04;
+	pub const CONFIG: u8 = 0x06;
+}
+
+mod tca6408 {
+	pub const INPUT: u8 = 0x00;
+	pub const OUTPUT: u8 = 0x01;
+	pub const POLARITY: u8 = 0x02;
+	pub const CONFIG: u8 = 0x03;
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+	PCF8574,
+	PCF8575,
+	MCP23017,
+	PCA9555,
+	TCA9555,
+	TCA6408
+}
+
+impl Chip {
+	pub fn driver(&self) -> Box<dyn Expander> {
+		match self {
+			Self::PCF8574 => Box::new(Pcf857x { width: 8, input_mask: 0 }),
+			Self::PCF8575 => Box::new(Pcf857x { width: 16, input_mask: 0 }),
+			Self::MCP23017 => Box::new(Mcp23017),
+			Self::PCA9555 | Self::TCA9555 => Box::new(Pca9555 { width: 16 }),
+			Self::TCA6408 => Box::new(Pca9555 { width: 8 })
+		}
+	}
+}
+
+/// IO expander chip. Port value bit N is pin N, for 16-bit chips low byte is port 0 (A).
+pub trait Expander: Send {
+	/// Number of pins
+	fn width(&self) -> u8;
+	/// Configure direction, bits set in `input_mask` are inputs, other are outputs.
+	/// Output latch is set to `out_state` before pins become outputs, so they never glitch.
+	fn init(&mut self, bus: &mut dyn I2cBus, addr: u8, input_mask: u16, out_state: u16) -> Result<(), String>;
+	fn read_port(&mut self, bus: &mut dyn I2cBus, addr: u8) -> Result<u16, String>;
+	fn write_port(&mut self, bus: &mut dyn I2cBus, addr: u8, val: u16) -> Result<(), String>;
+	/// Enable INT output on change of `input_mask` pins, chips without INT config accept it as is
+	fn enable_interrupt(&mut self, _bus: &mut dyn I2cBus, _addr: u8, _input_mask: u16) -> Result<(), String> {
+		Ok(())
+	}
+	/// Port value latched at the moment of interrupt, `None` if chip does not latch it
+	fn read_captured(&mut self, _bus: &mut dyn I2cBus, _addr: u8) -> Result<Option<u16>, String> {
+		Ok(None)
+	}
+}
+
+/// Write register of `width` bits, 16-bit registers are written as pair low, high
+fn write_reg(bus: &mut dyn I2cBus, addr: u8, width: u8, reg: u8, val: u16) -> Result<(), String> {
+	if width > 8 {
+		write(bus, addr, &[reg, val as u8, (val >> 8) as u8])
+	} else {
+		write(bus, addr, &[reg, val as u8])
+	}
+}
+
+fn read_reg_val(bus: &mut dyn I2cBus, addr: u8, width: u8, reg: u8) -> Result<u16, String> {
+	let mut buf = [0u8;2];
+	let len = if width > 8 { 2 } else { 1 };
+	read_reg(bus, addr, reg, &mut buf[..len])?;
+	Ok(u16::from_le_bytes(buf))
+}
+
+/// PCF8574/PCF8575 quasi-bidirectional port, inputs are pins written high
+struct Pcf857x {
+	width: u8,
+	input_mask: u16
+}
+
+impl Expander for Pcf857x {
+	fn width(&self) -> u8 {
+		self.width
+	}
+
+	fn init(&mut self, bus: &mut dyn I2cBus, addr: u8, input_mask: u16, out_state: u16) -> Result<(), String> {
+		self.input_mask = input_mask;
+		self.write_port(bus, addr, out_state)?;
+		thread::sleep(DELAY_AFTER_TRANSFER);
+		Ok(())
+	}
+
+	fn read_port(&mut self, bus: &mut dyn I2cBus, addr: u8) -> Result<u16, String> {
+		let mut buf = [0u8;2];
+		let len = (self.width / 8) as usize;
+		read(bus, addr, &mut buf[..len])?;
+		Ok(u16::from_le_bytes(buf))
+	}
+
+	fn write_port(&mut self, bus: &mut dyn I2cBus, addr: u8, val: u16) -> Result<(), String> {
+		let val = val | self.input_mask;
+		if self.width > 8 {
+			write(bus, addr, &[val as u8, (val >> 8) as u8])
+		} else {
+			write(bus, addr, &[val as u8])
+		}
+	}
+}
+
+/// MCP23017 in default IOCON.BANK = 0 mode, A/B registers are interleaved
+struct Mcp23017;
+
+impl Expander for Mcp23017 {
+	fn width(&self) -> u8 {
+		16
+	}
+
+	fn init(&mut self, bus: &mut dyn I2cBus, addr: u8, input_mask: u16, out_state: u16) -> Result<(), String> {
+		write_reg(bus, addr, 16, mcp23017::OLATA, out_state)?;
+		write_reg(bus, addr, 16, mcp23017::IPOLA, 0)?;
+		// inputs get pull-ups, same as quasi-bidirectional PCF pins
+		write_reg(bus, addr, 16, mcp23017::GPPUA, input_mask)?;
+		write_reg(bus, addr, 16, mcp23017::IODIRA, input_mask)
+	}
+
+	fn read_port(&mut self, bus: &mut dyn I2cBus, addr: u8) -> Result<u16, String> {
+		read_reg_val(bus, addr, 16, mcp23017::GPIOA)
+	}
+
+	fn write_port(&mut self, bus: &mut dyn I2cBus, addr: u8, val: u16) -> Result<(), String> {
+		write_reg(bus, addr, 16, mcp23017::OLATA, val)
+	}
+
+	fn enable_interrupt(&mut self, bus: &mut dyn I2cBus, addr: u8, input_mask: u16) -> Result<(), String> {
+		write(bus, addr, &[mcp23017::IOCON, mcp23017::IOCON_MIRROR_ODR])?;
+		// interrupt on any change against previous value
+		write_reg(bus, addr, 16, mcp23017::INTCONA, 0)?;
+		write_reg(bus, addr, 16, mcp23017::GPINTENA, input_mask)?;
+		// clear interrupt pending since init
+		self.read_port(bus, addr).map(|_| ())
+	}
+
+	fn read_captured(&mut self, bus: &mut dyn I2cBus, addr: u8) -> Result<Option<u16>, String> {
+		read_reg_val(bus, addr, 16, mcp23017::INTCAPA).map(Some)
+	}
+}
+
+/// PCA9555/TCA9555 (16 pins) and register compatible TCA6408 (8 pins)
+struct Pca9555 {
+	width: u8
+}
+
+impl Pca9555 {
+	fn regs(&self) -> (u8, u8, u8, u8) {
+		if self.width > 8 {
+			(pca9555::INPUT, pca9555::OUTPUT, pca9555::POLARITY, pca9555::CONFIG)
+		} else {
+			(tca6408::INPUT, tca6408::OUTPUT, tca6408::POLARITY, tca6408::CONFIG)
+		}
+	}
+}
+
+impl Expander for Pca9555 {
+	fn width(&self) -> u8 {
+		self.width
+	}
+
+	fn init(&mut self, bus: &mut dyn I2cBus, addr: u8, input_mask: u16, out_state: u16) -> Result<(), String> {
+		let (_, output, polarity, config) = self.regs();
+		write_reg(bus, addr, self.width, output, out_state)?;
+		write_reg(bus, addr, self.width, polarity, 0)?;
+		write_reg(bus, addr, self.width, config, input_mask)
+	}
+
+	fn read_port(&mut self, bus: &mut dyn I2cBus, addr: u8) -> Result<u16, String> {
+		let (input, ..) = self.regs();
+		read_reg_val(bus, addr, self.width, input)
+	}
+
+	fn write_port(&mut self, bus: &mut dyn I2cBus, addr: u8, val: u16) -> Result<(), String> {
+		let (_, output, ..) = self.regs();
+		write_reg(bus, addr, self.width, output, val)
+	}
+}
@@ -1,12 +1,474 @@
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use gpio::GpioValue;
 use serde::Deserialize;
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::gpioline::{self, OutputPin, PinConfig};
+use crate::modbus::{self, Error, Request, Response};
+
+/// Slaves need time to execute broadcast request, nobody confirms it
+const BROADCAST_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum Parity {
+	None,
+	/// Modbus default
+	#[default]
+	Even,
+	Odd
+}
+
+impl Parity {
+	pub fn serial(&self) -> serialport::Parity {
+		match self {
+			Parity::None => serialport::Parity::None,
+			Parity::Even => serialport::Parity::Even,
+			Parity::Odd => serialport::Parity::Odd
+		}
+	}
+}
+
+/// Switching of RS-485 transceiver between transmit and receive
+#[derive(Deserialize, Debug, Clone, Default)]
+pub enum Direction {
+	/// Transceiver switches by itself
+	#[default]
+	Auto,
+	/// Driver is enabled by RTS of UART
+	Rts,
+	/// Driver is enabled by GPIO at high level
+	Gpio(PinConfig)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RegisterKind {
+	Coil,
+	Discrete,
+	Holding,
+	Input
+}
+
+/// How register words are read as value, 32-bit values take two words, high word first
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum ValueFormat {
+	#[default]
+	U16,
+	I16,
+	U32,
+	I32,
+	F32
+}
+
+impl ValueFormat {
+	fn words(&self) -> u16 {
+		match self {
+			ValueFormat::U16 | ValueFormat::I16 => 1,
+			_ => 2
+		}
+	}
+
+	fn value(&self, w: &[u16]) -> f64 {
+		let long = || (w[0] as u32) << 16 | w[1] as u32;
+		match self {
+			ValueFormat::U16 => w[0] as f64,
+			ValueFormat::I16 => w[0] as i16 as f64,
+			ValueFormat::U32 => long() as f64,
+			ValueFormat::I32 => long() as i32 as f64,
+			ValueFormat::F32 => f32::from_bits(long()) as f64
+		}
+	}
+}
+
+/// Entry of register map read by test
+#[derive(Deserialize, Debug, Clone)]
+pub struct RegisterConfig {
+	name: String,
+	/// Slave address, `addr` of bus when omitted
+	slave: Option<u8>,
+	kind: RegisterKind,
+	addr: u16,
+	/// Number of bits or values
+	#[serde(default = "default_count")]
+	count: u16,
+	#[serde(default)]
+	format: ValueFormat,
+	#[serde(default = "default_scale")]
+	scale: f64,
+	#[serde(default)]
+	unit: String,
+	/// Response timeout for slow devices, `timeout_ms` of bus when omitted
+	timeout_ms: Option<u64>
+}
+
+fn default_count() -> u16 { 1 }
+fn default_scale() -> f64 { 1.0 }
+fn default_baudrate() -> u32 { 9600 }
+fn default_stop_bits() -> u8 { 1 }
+fn default_timeout_ms() -> u64 { 500 }
 
 #[derive(Deserialize)]
 pub struct ExtbusConfig {
 	driver: String,
-	addr: u8
+	/// Default slave address
+	addr: u8,
+	#[serde(default = "default_baudrate")]
+	baudrate: u32,
+	#[serde(default)]
+	parity: Parity,
+	#[serde(default = "default_stop_bits")]
+	stop_bits: u8,
+	#[serde(default)]
+	direction: Direction,
+	#[serde(default = "default_timeout_ms")]
+	timeout_ms: u64,
+	#[serde(default)]
+	registers: Vec<RegisterConfig>
+}
+
+enum DriverEnable {
+	Auto,
+	Rts,
+	Gpio(Box<dyn OutputPin>)
+}
+
+fn io_error(e: impl std::fmt::Display) -> Error {
+	Error::Io(e.to_string())
+}
+
+/// Modbus RTU master on RS-485 line
+pub struct Master {
+	port: Box<dyn SerialPort>,
+	driver: DriverEnable,
+	char_time: Duration,
+	frame_gap: Duration,
+	timeout: Duration,
+	/// Next frame may start after this moment
+	bus_free: Instant
+}
+
+impl Master {
+	/// Master without direction control on opened port
+	pub fn new(port: Box<dyn SerialPort>, baudrate: u32, timeout: Duration) -> Self {
+		Self {
+			port,
+			driver: DriverEnable::Auto,
+			char_time: modbus::char_time(baudrate),
+			frame_gap: modbus::frame_gap(baudrate),
+			timeout,
+			bus_free: Instant::now()
+		}
+	}
+
+	pub fn open(config: &ExtbusConfig) -> Result<Self, String> {
+		let stop_bits = match config.stop_bits {
+			1 => serialport::StopBits::One,
+			2 => serialport::StopBits::Two,
+			n => return Err(format!("Stop bits {} is not 1 or 2", n))
+		};
+		let port = serialport::new(&config.driver, config.baudrate)
+			.parity(config.parity.serial())
+			.stop_bits(stop_bits)
+			.flow_control(serialport::FlowControl::None)
+			.open()
+			.map_err(|e| format!("Fail to open {}: {}", config.driver, e))?;
+		let mut master = Self::new(port, config.baudrate, Duration::from_millis(config.timeout_ms));
+		master.driver = match &config.direction {
+			Direction::Auto => DriverEnable::Auto,
+			Direction::Rts => DriverEnable::Rts,
+			Direction::Gpio(pin) => DriverEnable::Gpio(gpioline::open_output(pin, GpioValue::Low)
+				.map_err(|e| format!("Fail to open direction pin {}: {}", pin, e))?)
+		};
+		master.enable_driver(false).map_err(|e| format!("Fail to set receive direction: {}", e))?;
+		Ok(master)
+	}
+
+	/// Default response timeout
+	pub fn timeout(&self) -> Duration {
+		self.timeout
+	}
+
+	fn enable_driver(&mut self, on: bool) -> Result<(), Error> {
+		match &mut self.driver {
+			DriverEnable::Auto => Ok(()),
+			DriverEnable::Rts => self.port.write_request_to_send(on).map_err(io_error),
+			DriverEnable::Gpio(pin) => pin.write(if on { GpioValue::High } else { GpioValue::Low }).map_err(io_error)
+		}
+	}
+
+	fn send(&mut self, adu: &[u8]) -> Result<(), Error> {
+		let now = Instant::now();
+		if self.bus_free > now {
+			thread::sleep(self.bus_free - now);
+		}
+		// late answer to previous request must not be taken for response
+		self.port.clear(ClearBuffer::Input).map_err(io_error)?;
+		self.enable_driver(true)?;
+		// flush waits until output queue is drained, last character may still be in shift register
+		let sent = self.port.write_all(adu).and_then(|_| self.port.flush()).map_err(io_error);
+		if !matches!(self.driver, DriverEnable::Auto) {
+			thread::sleep(self.char_time);
+		}
+		self.enable_driver(false)?;
+		self.bus_free = Instant::now() + self.frame_gap;
+		sent
+	}
+
+	fn read_until(&mut self, buf: &mut [u8], deadline: Instant) -> Result<(), Error> {
+		let mut pos = 0;
+		while pos < buf.len() {
+			let left = deadline.saturating_duration_since(Instant::now());
+			if left.is_zero() {
+				return Err(Error::Timeout);
+			}
+			self.port.set_timeout(left).map_err(io_error)?;
+			match self.port.read(&mut buf[pos..]) {
+				Ok(0) => thread::sleep(self.char_time),
+				Ok(n) => pos += n,
+				Err(e) if e.kind() == ErrorKind::TimedOut => return Err(Error::Timeout),
+				Err(e) => return Err(io_error(e))
+			}
+		}
+		Ok(())
+	}
+
+	/// Frame end is found from its header. Silence of 3.5 characters is not
+	/// usable for this, USB adapters deliver data in chunks.
+	fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, Error> {
+		let deadline = Instant::now() + timeout;
+		let mut adu = vec![0; 3];
+		self.read_until(&mut adu, deadline)?;
+		let len = modbus::response_len(&adu)
+			.ok_or_else(|| Error::Frame(format!("Unknown function 0x{:02X}", adu[1])))?;
+		adu.resize(len, 0);
+		self.read_until(&mut adu[3..], deadline)?;
+		Ok(adu)
+	}
+
+	/// Send request and wait for response at most `timeout`
+	pub fn transact(&mut self, slave: u8, req: &Request, timeout: Duration) -> Result<Response, Error> {
+		req.validate()?;
+		if slave == modbus::BROADCAST && !req.is_write() {
+			return Err(Error::InvalidRequest(String::from("Only writes may be broadcast")));
+		}
+		self.send(&modbus::frame(slave, &req.encode()))?;
+		if slave == modbus::BROADCAST {
+			self.bus_free = Instant::now() + BROADCAST_DELAY;
+			return Ok(Response::Written);
+		}
+		let received = self.receive(timeout);
+		// rest of broken frame must pass before next request
+		self.bus_free = Instant::now() + self.frame_gap;
+		let adu = received?;
+		let (addr, pdu) = modbus::unframe(&adu)?;
+		if addr != slave {
+			return Err(Error::Frame(format!("Response from slave {} to request for {}", addr, slave)));
+		}
+		req.decode_response(pdu)
+	}
+
+	fn request(&mut self, slave: u8, req: Request) -> Result<Response, Error> {
+		let timeout = self.timeout;
+		self.transact(slave, &req, timeout)
+	}
+
+	pub fn read_coils(&mut self, slave: u8, addr: u16, count: u16) -> Result<Vec<bool>, Error> {
+		bits(self.request(slave, Request::ReadCoils { addr, count })?)
+	}
+
+	pub fn read_discrete_inputs(&mut self, slave: u8, addr: u16, count: u16) -> Result<Vec<bool>, Error> {
+		bits(self.request(slave, Request::ReadDiscreteInputs { addr, count })?)
+	}
+
+	pub fn read_holding_registers(&mut self, slave: u8, addr: u16, count: u16) -> Result<Vec<u16>, Error> {
+		registers(self.request(slave, Request::ReadHoldingRegisters { addr, count })?)
+	}
+
+	pub fn read_input_registers(&mut self, slave: u8, addr: u16, count: u16) -> Result<Vec<u16>, Error> {
+		registers(self.request(slave, Request::ReadInputRegisters { addr, count })?)
+	}
+
+	pub fn write_single_coil(&mut self, slave: u8, addr: u16, value: bool) -> Result<(), Error> {
+		self.request(slave, Request::WriteSingleCoil { addr, value }).map(|_| ())
+	}
+
+	pub fn write_single_register(&mut self, slave: u8, addr: u16, value: u16) -> Result<(), Error> {
+		self.request(slave, Request::WriteSingleRegister { addr, value }).map(|_| ())
+	}
+
+	pub fn write_multiple_coils(&mut self, slave: u8, addr: u16, values: &[bool]) -> Result<(), Error> {
+		self.request(slave, Request::WriteMultipleCoils { addr, values: values.to_vec() }).map(|_| ())
+	}
+
+	pub fn write_multiple_registers(&mut self, slave: u8, addr: u16, values: &[u16]) -> Result<(), Error> {
+		self.request(slave, Request::WriteMultipleRegisters { addr, values: values.to_vec() }).map(|_| ())
+	}
+
+	/// Write is done before read by slave
+	pub fn read_write_multiple_registers(&mut self, slave: u8, read_addr: u16, read_count: u16,
+		write_addr: u16, values: &[u16]) -> Result<Vec<u16>, Error> {
+		let req = Request::ReadWriteMultipleRegisters { read_addr, read_count, write_addr, values: values.to_vec() };
+		registers(self.request(slave, req)?)
+	}
+}
+
+fn bits(resp: Response) -> Result<Vec<bool>, Error> {
+	match resp {
+		Response::Bits(bits) => Ok(bits),
+		_ => Err(Error::Frame(String::from("Response has no bits")))
+	}
+}
+
+fn registers(resp: Response) -> Result<Vec<u16>, Error> {
+	match resp {
+		Response::Registers(regs) => Ok(regs),
+		_ => Err(Error::Frame(String::from("Response has no registers")))
+	}
+}
+
+fn format_bits(bits: &[bool]) -> String {
+	bits.iter().map(|b| if *b { "1" } else { "0" }).collect::<Vec<_>>().join(" ")
+}
+
+/// Scaled values of register words with unit
+fn format_values(words: &[u16], format: ValueFormat, scale: f64, unit: &str) -> String {
+	words.chunks(format.words() as usize)
+		.map(|w| format!("{}{}", format.value(w) * scale, unit))
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+fn read_register(master: &mut Master, slave: u8, reg: &RegisterConfig) -> Result<String, Error> {
+	let timeout = reg.timeout_ms.map(Duration::from_millis).unwrap_or(master.timeout());
+	let count = match reg.kind {
+		RegisterKind::Coil | RegisterKind::Discrete => reg.count,
+		RegisterKind::Holding | RegisterKind::Input => match reg.count.checked_mul(reg.format.words()) {
+			Some(count) => count,
+			None => return Err(Error::InvalidRequest(format!("{} values of {:?} exceed register address space", reg.count, reg.format)))
+		}
+	};
+	let req = match reg.kind {
+		RegisterKind::Coil => Request::ReadCoils { addr: reg.addr, count },
+		RegisterKind::Discrete => Request::ReadDiscreteInputs { addr: reg.addr, count },
+		RegisterKind::Holding => Request::ReadHoldingRegisters { addr: reg.addr, count },
+		RegisterKind::Input => Request::ReadInputRegisters { addr: reg.addr, count }
+	};
+	match master.transact(slave, &req, timeout)? {
+		Response::Bits(b) => Ok(format_bits(&b)),
+		Response::Registers(w) => Ok(format_values(&w, reg.format, reg.scale, &reg.unit)),
+		Response::Written => Err(Error::Frame(String::from("Write confirmation to read request")))
+	}
 }
 
+/// Read every entry of register map
 pub fn test(config: &ExtbusConfig) -> Result<(), String> {
-	
+	println!("\n[EXTBUS] Test begin..");
+	if config.registers.is_empty() {
+		return Err(String::from("Register map is empty"));
+	}
+	let mut master = Master::open(config)?;
+	let mut failed = 0;
+	for reg in &config.registers {
+		let slave = reg.slave.unwrap_or(config.addr);
+		print!("\t{} ({} {:?} {}): ", reg.name, slave, reg.kind, reg.addr);
+		match read_register(&mut master, slave, reg) {
+			Ok(value) => println!("{}", value),
+			Err(e) => {
+				println!("{}", e);
+				failed += 1;
+			}
+		}
+	}
+	if failed > 0 {
+		return Err(format!("{} of {} registers are not read", failed, config.registers.len()));
+	}
+	Ok(())
+}
+
+/// Read and print registers of slave, `addr` of config when `slave` is omitted
+pub fn read(config: &ExtbusConfig, slave: Option<u8>, kind: RegisterKind, addr: u16, count: u16) -> Result<(), String> {
+	let mut master = Master::open(config)?;
+	let slave = slave.unwrap_or(config.addr);
+	let text = match kind {
+		RegisterKind::Coil => master.read_coils(slave, addr, count).map(|b| format_bits(&b)),
+		RegisterKind::Discrete => master.read_discrete_inputs(slave, addr, count).map(|b| format_bits(&b)),
+		RegisterKind::Holding => master.read_holding_registers(slave, addr, count).map(|w| format!("{:?}", w)),
+		RegisterKind::Input => master.read_input_registers(slave, addr, count).map(|w| format!("{:?}", w))
+	}.map_err(|e| e.to_string())?;
+	println!("{}", text);
+	Ok(())
+}
+
+/// Write coils or holding registers, single value uses single write function unless `multiple`
+pub fn write(config: &ExtbusConfig, slave: Option<u8>, kind: RegisterKind, addr: u16, values: &[u16],
+	multiple: bool) -> Result<(), String> {
+	let mut master = Master::open(config)?;
+	let slave = slave.unwrap_or(config.addr);
+	let single = values.len() == 1 && !multiple;
+	let coils: Vec<bool> = values.iter().map(|v| *v != 0).collect();
+	match kind {
+		RegisterKind::Coil if single => master.write_single_coil(slave, addr, coils[0]),
+		RegisterKind::Coil => master.write_multiple_coils(slave, addr, &coils),
+		RegisterKind::Holding if single => master.write_single_register(slave, addr, values[0]),
+		RegisterKind::Holding => master.write_multiple_registers(slave, addr, values),
+		_ => return Err(format!("{:?} is read only", kind))
+	}.map_err(|e| e.to_string())?;
+	println!("Written");
 	Ok(())
-}
\ No newline at end of file
+}
+
+/// Write holding registers and read back in one request
+pub fn read_write(config: &ExtbusConfig, slave: Option<u8>, read_addr: u16, read_count: u16,
+	write_addr: u16, values: &[u16]) -> Result<(), String> {
+	let mut master = Master::open(config)?;
+	let slave = slave.unwrap_or(config.addr);
+	let regs = master.read_write_multiple_registers(slave, read_addr, read_count, write_addr, values)
+		.map_err(|e| e.to_string())?;
+	println!("{:?}", regs);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serialport::TTYPort;
+
+	/// Master against slave which answers with canned frames
+	#[test]
+	fn master_round_trip() {
+		let (master_port, mut slave_port) = TTYPort::pair().unwrap();
+		let mut master = Master::new(Box::new(master_port), 115200, Duration::from_millis(500));
+		let slave = thread::spawn(move || {
+			slave_port.set_timeout(Duration::from_secs(2)).unwrap();
+			let mut req = [0u8; 8];
+			slave_port.read_exact(&mut req).unwrap();
+			assert_eq!(req.to_vec(), modbus::frame(17, &[0x03, 0x00, 0x6B, 0x00, 0x02]));
+			slave_port.write_all(&modbus::frame(17, &[0x03, 0x04, 0x02, 0x2B, 0xFF, 0xFE])).unwrap();
+			slave_port.read_exact(&mut req).unwrap();
+			slave_port.write_all(&modbus::frame(17, &[0x85, 0x02])).unwrap();
+			slave_port.read_exact(&mut req).unwrap();
+			let mut bad = modbus::frame(17, &req[1..6]);
+			bad[7] ^= 0xFF;
+			slave_port.write_all(&bad).unwrap();
+			slave_port
+		});
+		assert_eq!(master.read_holding_registers(17, 0x6B, 2).unwrap(), vec![0x022B, 0xFFFE]);
+		assert_eq!(master.write_single_coil(17, 0xAC, true), Err(Error::Exception(modbus::exception::ILLEGAL_DATA_ADDRESS)));
+		assert!(matches!(master.write_single_register(17, 1, 3), Err(Error::Crc { .. })));
+		// slave side stays open but silent
+		let _slave_port = slave.join().unwrap();
+		assert_eq!(master.read_coils(17, 0, 1), Err(Error::Timeout));
+		assert_eq!(format_values(&[0x022B, 0xFFFE], ValueFormat::I16, 0.1, "V"), "55.5V -0.2V");
+	}
+
+	#[test]
+	fn oversized_register_entry_is_invalid() {
+		let (port, _slave_port) = TTYPort::pair().unwrap();
+		let mut master = Master::new(Box::new(port), 115200, Duration::from_millis(10));
+		let reg: RegisterConfig = toml::from_str("name = \"log\"\nkind = \"Holding\"\naddr = 0\ncount = 40000\nformat = \"U32\"").unwrap();
+		assert!(matches!(read_register(&mut master, 1, &reg), Err(Error::InvalidRequest(_))));
+	}
+}
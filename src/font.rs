@@ -0,0 +1,559 @@
+use std::collections::HashMap;
+use std::fs;
+
+use embedded_graphics::{
+	geometry::{OriginDimensions, Point},
+	image::GetPixel,
+	mono_font::{MonoFont, iso_8859_5},
+	pixelcolor::BinaryColor
+};
+
+/// Built-in fonts, X11 misc-fixed faces with ISO 8859-5 (Latin and Cyrillic) coverage
+const BUILTIN: &[(&str, MonoFont<'static>)] = &[
+	("4x6", iso_8859_5::FONT_4X6),
+	("5x7", iso_8859_5::FONT_5X7),
+	("5x8", iso_8859_5::FONT_5X8),
+	("6x10", iso_8859_5::FONT_6X10),
+	("6x13", iso_8859_5::FONT_6X13),
+	("6x13b", iso_8859_5::FONT_6X13_BOLD),
+	("7x13", iso_8859_5::FONT_7X13),
+	("7x13b", iso_8859_5::FONT_7X13_BOLD),
+	("8x13", iso_8859_5::FONT_8X13),
+	("9x15", iso_8859_5::FONT_9X15),
+	("9x18", iso_8859_5::FONT_9X18),
+	("10x20", iso_8859_5::FONT_10X20)
+];
+
+pub const DEFAULT_FONT: &str = "6x10";
+
+/// Shown for characters missing in font without default char
+const REPLACEMENT: char = '?';
+
+/// KOI8-R letters at 0xC0..=0xDF, same order in upper case at 0xE0..=0xFF
+const KOI8R_LETTERS: &str = "юабцдефгхийклмнопярстужвьызшэщчъ";
+
+const PCF_MAGIC: &[u8] = b"\x01fcp";
+const PCF_PROPERTIES: u32 = 1 << 0;
+const PCF_ACCELERATORS: u32 = 1 << 1;
+const PCF_METRICS: u32 = 1 << 2;
+const PCF_BITMAPS: u32 = 1 << 3;
+const PCF_BDF_ENCODINGS: u32 = 1 << 5;
+const PCF_BDF_ACCELERATORS: u32 = 1 << 8;
+const PCF_COMPRESSED_METRICS: u32 = 0x100;
+const PCF_BYTE_MSB: u32 = 1 << 2;
+const PCF_BIT_MSB: u32 = 1 << 3;
+const PCF_NO_GLYPH: u16 = 0xFFFF;
+
+/// Glyph bitmap placed relative to pen position on baseline, as in BDF:
+/// `x_off` to the left edge, `y_off` up to the bottom row
+#[derive(Clone)]
+pub struct Glyph {
+	pub width: u32,
+	pub height: u32,
+	pub x_off: i32,
+	pub y_off: i32,
+	/// Pen movement after glyph
+	pub advance: i32,
+	bits: Vec<bool>
+}
+
+impl Glyph {
+	/// Pixel at column `x`, row `y` counted from top of bitmap
+	pub fn pixel(&self, x: u32, y: u32) -> bool {
+		x < self.width && y < self.height && self.bits[(y * self.width + x) as usize]
+	}
+}
+
+/// Bitmap font, characters are mapped by Unicode code point
+pub struct Font {
+	glyphs: HashMap<char, Glyph>,
+	/// Pixels above and below baseline
+	pub ascent: i32,
+	pub descent: i32,
+	default_char: Option<char>
+}
+
+/// Character set of font file, glyphs are mapped to Unicode
+#[derive(Clone, Copy)]
+enum Charset {
+	Unicode,
+	Iso8859_5,
+	Koi8r
+}
+
+impl Charset {
+	fn from_registry(registry: &str, encoding: &str) -> Result<Self, String> {
+		match (registry.to_uppercase().as_str(), encoding) {
+			("ISO10646", _) | ("ISO8859", "1") | ("", _) => Ok(Self::Unicode),
+			("ISO8859", "5") => Ok(Self::Iso8859_5),
+			("KOI8", "R") | ("KOI8", "r") => Ok(Self::Koi8r),
+			(reg, enc) => Err(format!("Unsupported font charset {}-{}", reg, enc))
+		}
+	}
+
+	fn decode(&self, code: u32) -> Option<char> {
+		if code < 0x80 {
+			return char::from_u32(code);
+		}
+		match self {
+			Self::Unicode => char::from_u32(code),
+			Self::Iso8859_5 => iso8859_5(code),
+			Self::Koi8r => match code {
+				0xA3 => Some('ё'),
+				0xB3 => Some('Ё'),
+				0xC0..=0xDF => KOI8R_LETTERS.chars().nth((code - 0xC0) as usize),
+				0xE0..=0xFF => KOI8R_LETTERS.chars().nth((code - 0xE0) as usize)
+					.and_then(|c| c.to_uppercase().next()),
+				_ => None
+			}
+		}
+	}
+}
+
+fn iso8859_5(code: u32) -> Option<char> {
+	let ucs = match code {
+		0x00..=0xA0 | 0xAD => code,
+		0xF0 => 0x2116,
+		0xFD => 0xA7,
+		0xA1..=0xFF => code + 0x0360,
+		_ => return None
+	};
+	char::from_u32(ucs)
+}
+
+impl Font {
+	/// Names of built-in fonts
+	pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+		BUILTIN.iter().map(|(name, _)| *name)
+	}
+
+	pub fn builtin(name: &str) -> Result<Self, String> {
+		match BUILTIN.iter().find(|(n, _)| *n == name) {
+			Some((_, mono)) => Ok(Self::from_mono(mono)),
+			None => Err(format!("Unknown font '{}', built-in fonts: {}",
+				name, Self::builtin_names().collect::<Vec<_>>().join(", ")))
+		}
+	}
+
+	/// Built-in font by name or BDF/PCF file by path
+	pub fn open(name: &str) -> Result<Self, String> {
+		if BUILTIN.iter().any(|(n, _)| *n == name) {
+			Self::builtin(name)
+		} else {
+			Self::load(name)
+		}
+	}
+
+	/// Load BDF or uncompressed PCF font
+	pub fn load(path: &str) -> Result<Self, String> {
+		let data = match fs::read(path) {
+			Ok(data) => data,
+			Err(e) => return Err(format!("Fail to read font {}: {}", path, e))
+		};
+		let res = if data.starts_with(PCF_MAGIC) {
+			Self::parse_pcf(&data)
+		} else if data.starts_with(b"STARTFONT") {
+			Self::parse_bdf(&String::from_utf8_lossy(&data))
+		} else {
+			Err(String::from("Not a BDF or PCF font"))
+		};
+		res.map_err(|e| format!("Fail to load font {}: {}", path, e))
+	}
+
+	fn from_mono(mono: &MonoFont) -> Self {
+		let size = mono.character_size;
+		let per_row = mono.image.size().width / size.width;
+		let ascent = mono.baseline as i32 + 1;
+		let descent = size.height as i32 - ascent;
+		let mut glyphs = HashMap::new();
+		for c in (0x20..0x7F).chain(0xA0..=0xFF).filter_map(iso8859_5) {
+			let idx = mono.glyph_mapping.index(c) as u32;
+			let (gx, gy) = ((idx % per_row) * size.width, (idx / per_row) * size.height);
+			let mut bits = Vec::with_capacity((size.width * size.height) as usize);
+			for y in 0..size.height {
+				for x in 0..size.width {
+					let p = Point::new((gx + x) as i32, (gy + y) as i32);
+					bits.push(mono.image.pixel(p) == Some(BinaryColor::On));
+				}
+			}
+			glyphs.insert(c, Glyph {
+				width: size.width,
+				height: size.height,
+				x_off: 0,
+				y_off: -descent,
+				advance: (size.width + mono.character_spacing) as i32,
+				bits
+			});
+		}
+		Self { glyphs, ascent, descent, default_char: None }
+	}
+
+	pub fn line_height(&self) -> i32 {
+		self.ascent + self.descent
+	}
+
+	/// Glyph of `c`, missing characters are replaced by default char of font or '?'
+	pub fn glyph(&self, c: char) -> Option<&Glyph> {
+		self.glyphs.get(&c)
+			.or_else(|| self.default_char.and_then(|d| self.glyphs.get(&d)))
+			.or_else(|| self.glyphs.get(&REPLACEMENT))
+	}
+
+	/// Advance width of single line
+	pub fn text_width(&self, text: &str) -> i32 {
+		text.chars().filter_map(|c| self.glyph(c)).map(|g| g.advance).sum()
+	}
+
+	fn parse_bdf(text: &str) -> Result<Self, String> {
+		let mut glyphs = HashMap::new();
+		let (mut ascent, mut descent) = (None, None);
+		let (mut registry, mut encoding) = (String::new(), String::new());
+		let mut default_code = None;
+		let mut bbox_ascent = 0;
+		let mut lines = text.lines();
+		let mut raw = Vec::new();
+		while let Some(line) = lines.next() {
+			let mut words = line.split_whitespace();
+			let key = words.next().unwrap_or("");
+			let args: Vec<&str> = words.collect();
+			let num = |i: usize| -> Result<i32, String> {
+				args.get(i).and_then(|v| v.parse().ok()).ok_or_else(|| format!("Bad line '{}'", line))
+			};
+			match key {
+				"FONT_ASCENT" => ascent = Some(num(0)?),
+				"FONT_DESCENT" => descent = Some(num(0)?),
+				"FONTBOUNDINGBOX" => bbox_ascent = num(1)? + num(3)?,
+				"DEFAULT_CHAR" => default_code = Some(num(0)? as u32),
+				"CHARSET_REGISTRY" => registry = args.join(" ").trim_matches('"').to_string(),
+				"CHARSET_ENCODING" => encoding = args.join(" ").trim_matches('"').to_string(),
+				"STARTCHAR" => {
+					let (mut code, mut advance, mut bbx) = (-1, 0, (0, 0, 0, 0));
+					let mut bits = Vec::new();
+					while let Some(line) = lines.next() {
+						let mut words = line.split_whitespace();
+						let key = words.next().unwrap_or("");
+						let args: Vec<i32> = words.filter_map(|w| w.parse().ok()).collect();
+						match key {
+							"ENCODING" => code = *args.first().unwrap_or(&-1),
+							"DWIDTH" => advance = *args.first().unwrap_or(&0),
+							"BBX" if args.len() == 4 => bbx = (args[0], args[1], args[2], args[3]),
+							"BITMAP" => {
+								for _ in 0..bbx.1 {
+									let row = lines.next().ok_or("Truncated bitmap")?;
+									bits.extend(hex_row(row.trim(), bbx.0 as u32)?);
+								}
+							},
+							"ENDCHAR" => break,
+							_ => ()
+						}
+					}
+					if code >= 0 && bits.len() == (bbx.0 * bbx.1).max(0) as usize {
+						raw.push((code as u32, Glyph {
+							width: bbx.0 as u32,
+							height: bbx.1 as u32,
+							x_off: bbx.2,
+							y_off: bbx.3,
+							advance,
+							bits
+						}));
+					}
+				},
+				_ => ()
+			}
+		}
+		let charset = Charset::from_registry(&registry, &encoding)?;
+		for (code, glyph) in raw {
+			if let Some(c) = charset.decode(code) {
+				glyphs.insert(c, glyph);
+			}
+		}
+		if glyphs.is_empty() {
+			return Err(String::from("No glyphs"));
+		}
+		let ascent = ascent.unwrap_or(bbox_ascent);
+		let descent = descent.unwrap_or_else(|| glyphs.values().map(|g| -g.y_off).max().unwrap_or(0));
+		Ok(Self {
+			glyphs,
+			ascent,
+			descent,
+			default_char: default_code.and_then(|c| charset.decode(c))
+		})
+	}
+
+	fn parse_pcf(data: &[u8]) -> Result<Self, String> {
+		let mut tables = HashMap::new();
+		let count = Reader::new(data, 0, false).u32(4)? as usize;
+		for i in 0..count {
+			let toc = Reader::new(data, 0, false);
+			let base = 8 + i * 16;
+			let kind = toc.u32(base)?;
+			let offset = toc.u32(base + 12)? as usize;
+			tables.insert(kind, offset);
+		}
+		let table = |kind: u32| -> Result<Reader, String> {
+			let offset = *tables.get(&kind).ok_or_else(|| format!("No PCF table {}", kind))?;
+			let format = Reader::new(data, 0, false).u32(offset)?;
+			Ok(Reader::new(data, offset, format & PCF_BYTE_MSB != 0).with_format(format))
+		};
+
+		let mut props = HashMap::new();
+		if let Ok(t) = table(PCF_PROPERTIES) {
+			let n = t.u32(4)? as usize;
+			let strings = 8 + n * 9 + (4 - n % 4) % 4 + 4;
+			for i in 0..n {
+				let name = t.str(strings + t.u32(8 + i * 9)? as usize)?;
+				if t.u8(8 + i * 9 + 4)? != 0 {
+					props.insert(name, t.str(strings + t.u32(8 + i * 9 + 5)? as usize)?);
+				}
+			}
+		}
+		let charset = Charset::from_registry(
+			props.get("CHARSET_REGISTRY").map_or("", |s| s.as_str()),
+			props.get("CHARSET_ENCODING").map_or("", |s| s.as_str()))?;
+
+		let accel = table(PCF_BDF_ACCELERATORS).or_else(|_| table(PCF_ACCELERATORS))?;
+		let ascent = accel.u32(12)? as i32;
+		let descent = accel.u32(16)? as i32;
+
+		let m = table(PCF_METRICS)?;
+		let mut metrics = Vec::new();
+		if m.format & PCF_COMPRESSED_METRICS != 0 {
+			for i in 0..m.u16(4)? as usize {
+				let v = |k: usize| -> Result<i32, String> { Ok(m.u8(6 + i * 5 + k)? as i32 - 0x80) };
+				metrics.push((v(0)?, v(1)?, v(2)?, v(3)?, v(4)?));
+			}
+		} else {
+			for i in 0..m.u32(4)? as usize {
+				let v = |k: usize| -> Result<i32, String> { Ok(m.u16(8 + i * 12 + k * 2)? as i16 as i32) };
+				metrics.push((v(0)?, v(1)?, v(2)?, v(3)?, v(4)?));
+			}
+		}
+
+		let b = table(PCF_BITMAPS)?;
+		let nglyphs = b.u32(4)? as usize;
+		let pad = 1usize << (b.format & 3);
+		let unit = 1usize << ((b.format >> 4) & 3);
+		let bits_start = 8 + nglyphs * 4 + 16;
+		let mut glyph_list = Vec::new();
+		for (i, (left, right, advance, asc, desc)) in metrics.iter().copied().enumerate().take(nglyphs) {
+			let (width, height) = ((right - left).max(0) as u32, (asc + desc).max(0) as u32);
+			let stride = (width as usize).div_ceil(8).div_ceil(pad) * pad;
+			let start = bits_start + b.u32(8 + i * 4)? as usize;
+			let mut bits = Vec::with_capacity((width * height) as usize);
+			for y in 0..height as usize {
+				let row = b.bytes(start + y * stride, stride)?;
+				let row = pcf_row(row, b.format, unit);
+				for x in 0..width as usize {
+					bits.push(row[x / 8] & (0x80 >> (x % 8)) != 0);
+				}
+			}
+			glyph_list.push(Glyph { width, height, x_off: left, y_off: -desc, advance, bits });
+		}
+
+		let e = table(PCF_BDF_ENCODINGS)?;
+		let (min2, max2) = (e.u16(4)? as u32, e.u16(6)? as u32);
+		let (min1, max1) = (e.u16(8)? as u32, e.u16(10)? as u32);
+		let default_code = e.u16(12)? as u32;
+		let mut glyphs = HashMap::new();
+		let mut idx = 0;
+		for b1 in min1..=max1 {
+			for b2 in min2..=max2 {
+				let g = e.u16(14 + idx * 2)?;
+				idx += 1;
+				if g == PCF_NO_GLYPH {
+					continue;
+				}
+				let glyph = glyph_list.get(g as usize).cloned();
+				if let (Some(c), Some(glyph)) = (charset.decode(b1 << 8 | b2), glyph) {
+					glyphs.insert(c, glyph);
+				}
+			}
+		}
+		if glyphs.is_empty() {
+			return Err(String::from("No glyphs"));
+		}
+		Ok(Self { glyphs, ascent, descent, default_char: charset.decode(default_code) })
+	}
+}
+
+/// Row of BDF bitmap, hex digits with left pixel in most significant bit
+fn hex_row(row: &str, width: u32) -> Result<Vec<bool>, String> {
+	let bytes: Vec<u8> = (0..row.len() / 2)
+		.map(|i| u8::from_str_radix(&row[i * 2..i * 2 + 2], 16))
+		.collect::<Result<_, _>>()
+		.map_err(|_| format!("Bad bitmap row '{}'", row))?;
+	if bytes.len() * 8 < width as usize {
+		return Err(format!("Bitmap row '{}' is shorter than {} pixels", row, width));
+	}
+	Ok((0..width as usize).map(|x| bytes[x / 8] & (0x80 >> (x % 8)) != 0).collect())
+}
+
+/// PCF bitmap row converted to MSB first bit and byte order
+fn pcf_row(row: &[u8], format: u32, unit: usize) -> Vec<u8> {
+	let mut row = row.to_vec();
+	if (format & PCF_BYTE_MSB != 0) != (format & PCF_BIT_MSB != 0) && unit > 1 {
+		for chunk in row.chunks_mut(unit) {
+			chunk.reverse();
+		}
+	}
+	if format & PCF_BIT_MSB == 0 {
+		for b in row.iter_mut() {
+			*b = b.reverse_bits();
+		}
+	}
+	row
+}
+
+/// Bounds checked reader of PCF table
+struct Reader<'a> {
+	data: &'a [u8],
+	base: usize,
+	msb: bool,
+	format: u32
+}
+
+impl<'a> Reader<'a> {
+	fn new(data: &'a [u8], base: usize, msb: bool) -> Self {
+		Self { data, base, msb, format: 0 }
+	}
+
+	fn with_format(mut self, format: u32) -> Self {
+		self.format = format;
+		self
+	}
+
+	fn bytes(&self, pos: usize, len: usize) -> Result<&'a [u8], String> {
+		self.data.get(self.base + pos..self.base + pos + len).ok_or(String::from("Truncated PCF file"))
+	}
+
+	fn u8(&self, pos: usize) -> Result<u8, String> {
+		Ok(self.bytes(pos, 1)?[0])
+	}
+
+	fn u16(&self, pos: usize) -> Result<u16, String> {
+		let b: [u8;2] = self.bytes(pos, 2)?.try_into().unwrap();
+		Ok(if self.msb { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+	}
+
+	fn u32(&self, pos: usize) -> Result<u32, String> {
+		let b: [u8;4] = self.bytes(pos, 4)?.try_into().unwrap();
+		Ok(if self.msb { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+	}
+
+	/// Zero terminated string
+	fn str(&self, pos: usize) -> Result<String, String> {
+		let rest = self.data.get(self.base + pos..).ok_or(String::from("Truncated PCF file"))?;
+		let len = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+		Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BDF: &str = "STARTFONT 2.1
+FONTBOUNDINGBOX 3 4 0 -1
+STARTPROPERTIES 4
+FONT_ASCENT 3
+FONT_DESCENT 1
+CHARSET_REGISTRY \"KOI8\"
+CHARSET_ENCODING \"R\"
+ENDPROPERTIES
+CHARS 2
+STARTCHAR A
+ENCODING 65
+DWIDTH 4 0
+BBX 3 3 0 0
+BITMAP
+40
+A0
+E0
+ENDCHAR
+STARTCHAR afii10049
+ENCODING 241
+DWIDTH 4 0
+BBX 3 4 0 -1
+BITMAP
+E0
+A0
+E0
+20
+ENDCHAR
+ENDFONT
+";
+
+	fn rows(glyph: &Glyph) -> Vec<String> {
+		(0..glyph.height)
+			.map(|y| (0..glyph.width).map(|x| if glyph.pixel(x, y) { '#' } else { '.' }).collect())
+			.collect()
+	}
+
+	#[test]
+	fn bdf_glyphs_are_mapped_from_charset() {
+		let font = Font::parse_bdf(BDF).unwrap();
+		assert_eq!((font.ascent, font.descent), (3, 1));
+		assert_eq!(rows(font.glyph('A').unwrap()), vec![".#.", "#.#", "###"]);
+		// 0xF1 is capital ya in KOI8-R
+		let ya = font.glyph('Я').unwrap();
+		assert_eq!((ya.y_off, ya.advance), (-1, 4));
+		assert_eq!(rows(ya)[3], "..#");
+		// no default char and no '?' in font
+		assert!(font.glyph('z').is_none());
+		assert_eq!(font.text_width("AzЯ"), 8);
+	}
+
+	#[test]
+	fn bdf_without_glyphs_is_rejected() {
+		assert!(Font::parse_bdf("STARTFONT 2.1\nENDFONT\n").is_err());
+		let bad = BDF.replace("A0\nE0\nENDCHAR\nSTARTCHAR afii", "G0\nE0\nENDCHAR\nSTARTCHAR afii");
+		assert!(Font::parse_bdf(&bad).is_err());
+	}
+
+	/// PCF file of little endian tables
+	fn pcf(tables: &[(u32, Vec<u8>)]) -> Vec<u8> {
+		let mut data = PCF_MAGIC.to_vec();
+		data.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+		let mut offset = 8 + tables.len() * 16;
+		for (kind, table) in tables {
+			for v in [*kind, 0, table.len() as u32, offset as u32] {
+				data.extend_from_slice(&v.to_le_bytes());
+			}
+			offset += table.len();
+		}
+		for (_, table) in tables {
+			data.extend_from_slice(table);
+		}
+		data
+	}
+
+	#[test]
+	fn pcf_lsb_bitmap_is_decoded() {
+		let mut accel = vec![0u8;12];
+		accel.extend_from_slice(&2u32.to_le_bytes());
+		accel.extend_from_slice(&0u32.to_le_bytes());
+		// left 0, right 3, advance 4, ascent 2, descent 0
+		let mut metrics = PCF_COMPRESSED_METRICS.to_le_bytes().to_vec();
+		metrics.extend_from_slice(&1u16.to_le_bytes());
+		metrics.extend_from_slice(&[0x80, 0x83, 0x84, 0x82, 0x80]);
+		// one byte rows, least significant bit first
+		let mut bitmaps = vec![0u8;4];
+		bitmaps.extend_from_slice(&1u32.to_le_bytes());
+		bitmaps.extend_from_slice(&0u32.to_le_bytes());
+		bitmaps.extend_from_slice(&[0u8;16]);
+		bitmaps.extend_from_slice(&[0x05, 0x02]);
+		// 'A' and 'B', 'B' has no glyph, default char is 'A'
+		let mut enc = vec![0u8;4];
+		for v in [0x41u16, 0x42, 0, 0, 0x41, 0, PCF_NO_GLYPH] {
+			enc.extend_from_slice(&v.to_le_bytes());
+		}
+		let data = pcf(&[(PCF_ACCELERATORS, accel), (PCF_METRICS, metrics), (PCF_BITMAPS, bitmaps), (PCF_BDF_ENCODINGS, enc)]);
+		let font = Font::parse_pcf(&data).unwrap();
+		assert_eq!((font.ascent, font.descent), (2, 0));
+		let a = font.glyph('A').unwrap();
+		assert_eq!(rows(a), vec!["#.#", ".#."]);
+		assert_eq!(a.advance, 4);
+		assert_eq!(rows(font.glyph('B').unwrap()), rows(a));
+		assert!(Font::parse_pcf(&data[..data.len() - 2]).is_err());
+	}
+}
@@ -0,0 +1,168 @@
+use serde::Deserialize;
+
+/// Brightness levels of pixel are 0 (off) ..= LEVEL_MAX (full white)
+pub const LEVEL_MAX: u8 = 0x0F;
+
+/// Draw data begins with header, see `DrawHeader`
+pub const HEADER_SIZE: usize = 2;
+
+/// Check byte of draw data header
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Checksum {
+	/// Check byte is zero
+	#[default]
+	None,
+	/// Check byte is 8-bit sum of pixel bytes
+	Sum8
+}
+
+/// Header of draw data: mark byte, then check byte.
+///
+/// Protocol of display controller is not documented, baseline sent two zero header
+/// bytes, so default is the same. Mark and checksum are assumptions, config sets them
+/// to match controller firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrawHeader {
+	pub mark: u8,
+	pub checksum: Checksum
+}
+
+impl Default for DrawHeader {
+	fn default() -> Self {
+		Self { mark: 0, checksum: Checksum::None }
+	}
+}
+
+impl DrawHeader {
+	fn check_byte(&self, pixels: &[u8]) -> u8 {
+		match self.checksum {
+			Checksum::None => 0,
+			Checksum::Sum8 => pixels.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+		}
+	}
+
+	/// Fill header of draw data, pixel bytes must be in place
+	pub fn write(&self, data: &mut [u8]) {
+		data[0] = self.mark;
+		data[1] = self.check_byte(&data[HEADER_SIZE..]);
+	}
+
+	/// Check header of draw data
+	pub fn check(&self, data: &[u8]) -> Result<(), String> {
+		if data[0] != self.mark {
+			return Err(format!("Bad draw data header 0x{:02X}, expected 0x{:02X}", data[0], self.mark));
+		}
+		let check = self.check_byte(&data[HEADER_SIZE..]);
+		if data[1] != check {
+			return Err(format!("Draw data checksum 0x{:02X}, expected 0x{:02X}", data[1], check));
+		}
+		Ok(())
+	}
+}
+
+/// Frame of monochrome matrix with 4-bit brightness, one byte per pixel.
+/// Drawing outside of frame is clipped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+	width: u32,
+	height: u32,
+	pixels: Vec<u8>
+}
+
+impl Framebuffer {
+	pub fn new(width: u32, height: u32) -> Self {
+		Self {
+			width,
+			height,
+			pixels: vec![0; (width * height) as usize]
+		}
+	}
+
+	pub fn width(&self) -> u32 {
+		self.width
+	}
+
+	pub fn height(&self) -> u32 {
+		self.height
+	}
+
+	fn index(&self, x: i32, y: i32) -> Option<usize> {
+		if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+			None
+		} else {
+			Some((y as u32 * self.width + x as u32) as usize)
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.fill(0);
+	}
+
+	pub fn fill(&mut self, level: u8) {
+		self.pixels.fill(level.min(LEVEL_MAX));
+	}
+
+	pub fn set_pixel(&mut self, x: i32, y: i32, level: u8) {
+		if let Some(i) = self.index(x, y) {
+			self.pixels[i] = level.min(LEVEL_MAX);
+		}
+	}
+
+	/// Level of pixel, 0 outside of frame
+	pub fn pixel(&self, x: i32, y: i32) -> u8 {
+		self.index(x, y).map_or(0, |i| self.pixels[i])
+	}
+
+	/// Bresenham line, both ends included
+	pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, level: u8) {
+		let dx = (x1 - x0).abs();
+		let dy = -(y1 - y0).abs();
+		let sx = if x0 < x1 { 1 } else { -1 };
+		let sy = if y0 < y1 { 1 } else { -1 };
+		let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+		loop {
+			self.set_pixel(x, y, level);
+			if x == x1 && y == y1 {
+				break;
+			}
+			let e2 = 2 * err;
+			if e2 >= dy {
+				err += dy;
+				x += sx;
+			}
+			if e2 <= dx {
+				err += dx;
+				y += sy;
+			}
+		}
+	}
+
+	/// Outline of rectangle with top left corner at `x`, `y`
+	pub fn rect(&mut self, x: i32, y: i32, w: u32, h: u32, level: u8) {
+		if w == 0 || h == 0 {
+			return;
+		}
+		let (x1, y1) = (x + w as i32 - 1, y + h as i32 - 1);
+		self.line(x, y, x1, y, level);
+		self.line(x, y1, x1, y1, level);
+		self.line(x, y, x, y1, level);
+		self.line(x1, y, x1, y1, level);
+	}
+
+	pub fn fill_rect(&mut self, x: i32, y: i32, w: u32, h: u32, level: u8) {
+		for py in y..y + h as i32 {
+			for px in x..x + w as i32 {
+				self.set_pixel(px, py, level);
+			}
+		}
+	}
+
+	/// Copy whole `src` with its top left corner at `x`, `y`
+	pub fn blit(&mut self, src: &Framebuffer, x: i32, y: i32) {
+		for sy in 0..src.height as i32 {
+			for sx in 0..src.width as i32 {
+				self.set_pixel(x + sx, y + sy, src.pixel(sx, sy));
+			}
+		}
+	}
+}
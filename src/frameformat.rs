@@ -0,0 +1,273 @@
+use serde::Deserialize;
+
+use crate::framebuffer::{DrawHeader, Framebuffer, HEADER_SIZE, LEVEL_MAX};
+
+/// Order of chained panels on the display
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChainLayout {
+	/// Every row of panels is chained left to right
+	#[default]
+	SideBySide,
+	/// Chain goes left to right, then back right to left on next row, panels of
+	/// returning rows are mounted upside down
+	Serpentine
+}
+
+/// Channels of one pixel in draw data
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+	#[default]
+	Mono,
+	/// Red, green and blue values, frame is shown in gray
+	Rgb
+}
+
+/// How frame of the display is laid out in draw data.
+///
+/// Header is followed by panels sent as one long chain `panel_width * chain` pixels wide, row by row,
+/// every pixel as `bpp` bits per channel, most significant bit first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameFormat {
+	pub panel_width: u32,
+	pub panel_height: u32,
+	/// Panels in chain
+	pub chain: u32,
+	/// Rows of panels, `chain` must be divisible by it
+	pub rows: u32,
+	pub layout: ChainLayout,
+	/// Clockwise rotation of frame on panels in degrees: 0, 90, 180 or 270
+	pub rotation: u32,
+	/// Bits per channel, 1..=8
+	pub bpp: u8,
+	pub color: ColorMode,
+	pub header: DrawHeader
+}
+
+impl Default for FrameFormat {
+	/// Single 64x32 mono panel with 4-bit pixels
+	fn default() -> Self {
+		Self {
+			panel_width: 64,
+			panel_height: 32,
+			chain: 1,
+			rows: 1,
+			layout: ChainLayout::SideBySide,
+			rotation: 0,
+			bpp: 4,
+			color: ColorMode::Mono,
+			header: DrawHeader::default()
+		}
+	}
+}
+
+impl FrameFormat {
+	pub fn validate(&self) -> Result<(), String> {
+		if self.panel_width == 0 || self.panel_height == 0 {
+			return Err(String::from("Panel size must not be zero"));
+		}
+		if self.chain == 0 || self.rows == 0 || !self.chain.is_multiple_of(self.rows) {
+			return Err(format!("{} panels can not be laid out in {} rows", self.chain, self.rows));
+		}
+		if ![0, 90, 180, 270].contains(&self.rotation) {
+			return Err(format!("Rotation {} is not multiple of 90 degrees", self.rotation));
+		}
+		if !(1..=8).contains(&self.bpp) {
+			return Err(format!("Unsupported {} bits per pixel", self.bpp));
+		}
+		Ok(())
+	}
+
+	/// Size of panel grid before rotation
+	fn grid_size(&self) -> (u32, u32) {
+		(self.panel_width * self.chain / self.rows, self.panel_height * self.rows)
+	}
+
+	pub fn width(&self) -> u32 {
+		let (w, h) = self.grid_size();
+		if self.rotation.is_multiple_of(180) { w } else { h }
+	}
+
+	pub fn height(&self) -> u32 {
+		let (w, h) = self.grid_size();
+		if self.rotation.is_multiple_of(180) { h } else { w }
+	}
+
+	/// Blank frame of display size
+	pub fn frame(&self) -> Framebuffer {
+		Framebuffer::new(self.width(), self.height())
+	}
+
+	fn channels(&self) -> usize {
+		match self.color {
+			ColorMode::Mono => 1,
+			ColorMode::Rgb => 3
+		}
+	}
+
+	/// Pixels of chain, all panels in one row
+	fn chain_pixels(&self) -> usize {
+		(self.panel_width * self.chain * self.panel_height) as usize
+	}
+
+	/// Size of draw data with header
+	pub fn encoded_size(&self) -> usize {
+		HEADER_SIZE + (self.chain_pixels() * self.channels() * self.bpp as usize).div_ceil(8)
+	}
+
+	/// Index of pixel in chain for pixel of frame
+	fn chain_index(&self, x: u32, y: u32) -> usize {
+		let (gw, gh) = self.grid_size();
+		let (gx, gy) = match self.rotation {
+			90 => (gw - 1 - y, x),
+			180 => (gw - 1 - x, gh - 1 - y),
+			270 => (y, gh - 1 - x),
+			_ => (x, y)
+		};
+		let per_row = self.chain / self.rows;
+		let (px, py) = (gx / self.panel_width, gy / self.panel_height);
+		let (lx, ly) = (gx % self.panel_width, gy % self.panel_height);
+		let (panel, lx, ly) = if self.layout == ChainLayout::Serpentine && py % 2 == 1 {
+			(py * per_row + per_row - 1 - px, self.panel_width - 1 - lx, self.panel_height - 1 - ly)
+		} else {
+			(py * per_row + px, lx, ly)
+		};
+		(ly * self.panel_width * self.chain + panel * self.panel_width + lx) as usize
+	}
+
+	/// Levels of frame in chain order
+	fn chain_levels(&self, fb: &Framebuffer) -> Vec<u8> {
+		let mut levels = vec![0; self.chain_pixels()];
+		for y in 0..self.height() {
+			for x in 0..self.width() {
+				levels[self.chain_index(x, y)] = fb.pixel(x as i32, y as i32);
+			}
+		}
+		levels
+	}
+
+	fn value_max(&self) -> u32 {
+		(1 << self.bpp) - 1
+	}
+
+	/// Encode frame of display size as draw data: header, then packed pixels of chain.
+	/// With 4 bpp on single mono panel two pixels go to a byte, left pixel in high nibble.
+	pub fn encode(&self, fb: &Framebuffer) -> Vec<u8> {
+		let (max, level_max) = (self.value_max(), LEVEL_MAX as u32);
+		let mut buf = vec![0; self.encoded_size()];
+		let mut bit = HEADER_SIZE * 8;
+		for level in self.chain_levels(fb) {
+			let value = (level as u32 * max + level_max / 2) / level_max;
+			for _ in 0..self.channels() {
+				for b in (0..self.bpp).rev() {
+					if value >> b & 1 != 0 {
+						buf[bit / 8] |= 0x80 >> (bit % 8);
+					}
+					bit += 1;
+				}
+			}
+		}
+		self.header.write(&mut buf);
+		buf
+	}
+
+	/// Decode draw data made by `encode`, brightest channel of RGB pixel gives the level
+	pub fn decode(&self, data: &[u8]) -> Result<Framebuffer, String> {
+		if data.len() != self.encoded_size() {
+			return Err(format!("Draw data is {} bytes, {}x{} frame needs {}",
+				data.len(), self.width(), self.height(), self.encoded_size()));
+		}
+		self.header.check(data)?;
+		let (max, level_max) = (self.value_max(), LEVEL_MAX as u32);
+		let mut bit = HEADER_SIZE * 8;
+		let mut levels = Vec::with_capacity(self.chain_pixels());
+		for _ in 0..self.chain_pixels() {
+			let mut value = 0;
+			for _ in 0..self.channels() {
+				let mut channel = 0;
+				for _ in 0..self.bpp {
+					channel = channel << 1 | (data[bit / 8] >> (7 - bit % 8) & 1) as u32;
+					bit += 1;
+				}
+				value = value.max(channel);
+			}
+			levels.push(((value * level_max + max / 2) / max) as u8);
+		}
+		let mut fb = self.frame();
+		for y in 0..self.height() {
+			for x in 0..self.width() {
+				fb.set_pixel(x as i32, y as i32, levels[self.chain_index(x, y)]);
+			}
+		}
+		Ok(fb)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::framebuffer::Checksum;
+
+	fn pattern(format: &FrameFormat) -> Framebuffer {
+		let mut fb = format.frame();
+		for y in 0..fb.height() as i32 {
+			for x in 0..fb.width() as i32 {
+				fb.set_pixel(x, y, ((x * 3 + y * 5) % 16) as u8);
+			}
+		}
+		fb
+	}
+
+	#[test]
+	fn default_format_packs_two_pixels_per_byte() {
+		let format = FrameFormat::default();
+		let fb = pattern(&format);
+		let data = format.encode(&fb);
+		assert_eq!(format.encoded_size(), 1026);
+		assert_eq!(&data[..4], &[0x00, 0x00, 0x03, 0x69]);
+		assert!(format.decode(&data).unwrap() == fb);
+	}
+
+	#[test]
+	fn chained_rotated_frames_round_trip() {
+		for layout in [ChainLayout::SideBySide, ChainLayout::Serpentine] {
+			for rotation in [0, 90, 180, 270] {
+				let format = FrameFormat { chain: 4, rows: 2, layout, rotation, ..Default::default() };
+				let fb = pattern(&format);
+				assert!(format.decode(&format.encode(&fb)).unwrap() == fb);
+			}
+		}
+		let rgb = FrameFormat { bpp: 8, color: ColorMode::Rgb, ..Default::default() };
+		let fb = pattern(&rgb);
+		assert_eq!(rgb.encoded_size(), 2 + 64 * 32 * 3);
+		assert!(rgb.decode(&rgb.encode(&fb)).unwrap() == fb);
+	}
+
+	#[test]
+	fn header_follows_config() {
+		let plain = FrameFormat { header: DrawHeader { mark: 0xA5, checksum: Checksum::None }, ..Default::default() };
+		let fb = pattern(&plain);
+		let data = plain.encode(&fb);
+		assert_eq!(&data[..HEADER_SIZE], &[0xA5, 0x00]);
+		assert!(plain.decode(&data).unwrap() == fb);
+		assert!(FrameFormat::default().decode(&data).is_err());
+		let summed = FrameFormat { header: DrawHeader { mark: 0xA5, checksum: Checksum::Sum8 }, ..Default::default() };
+		let mut data = summed.encode(&fb);
+		assert!(summed.decode(&data).unwrap() == fb);
+		data[HEADER_SIZE] ^= 0x01;
+		assert!(summed.decode(&data).is_err());
+	}
+
+	#[test]
+	fn serpentine_returning_row_is_upside_down() {
+		let format = FrameFormat { panel_width: 2, panel_height: 2, chain: 4, rows: 2,
+			layout: ChainLayout::Serpentine, bpp: 8, ..Default::default() };
+		let mut fb = format.frame();
+		// top left pixel of bottom left panel, last panel of chain
+		fb.set_pixel(0, 2, LEVEL_MAX);
+		let data = format.encode(&fb);
+		// chain is 8 pixels wide, pixel lands at bottom right corner of panel 3
+		let pixels = &data[HEADER_SIZE..];
+		assert_eq!(pixels.iter().position(|p| *p != 0), Some(8 + 7));
+		assert_eq!((format.width(), format.height()), (4, 4));
+	}
+}
@@ -0,0 +1,250 @@
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use gpio::{GpioIn, GpioOut, GpioValue};
+use gpio::sysfs::{SysFsGpioInput, SysFsGpioOutput};
+use gpio_cdev::{Chip, EventRequestFlags, Line, LineEventHandle, LineHandle, LineRequestFlags};
+use serde::Deserialize;
+
+const CONSUMER_DEF: &str = "wshmch_test";
+const DEV_DIR: &str = "/dev";
+
+/// Kernel GPIOHANDLE_REQUEST_BIAS_* flags (since linux 5.5), not exported by gpio_cdev
+const REQUEST_BIAS_PULL_UP: u32 = 1 << 5;
+const REQUEST_BIAS_PULL_DOWN: u32 = 1 << 6;
+const REQUEST_BIAS_DISABLE: u32 = 1 << 7;
+
+/// Line of GPIO chip, addressed by offset or by name given in device tree
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum LineId {
+	Offset(u32),
+	Name(String)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum Bias {
+	#[default]
+	AsIs,
+	Disable,
+	PullUp,
+	PullDown
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum Drive {
+	#[default]
+	PushPull,
+	OpenDrain,
+	OpenSource
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CdevLineConfig {
+	chip: String,
+	line: LineId,
+	name: Option<String>,
+	#[serde(default)]
+	bias: Bias,
+	#[serde(default)]
+	drive: Drive
+}
+
+/// GPIO pin description from config.
+///
+/// Plain number selects legacy sysfs pin (`pin = 23`), table selects
+/// line of character device (`pin = { chip = "gpiochip0", line = 23 }`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PinConfig {
+	Sysfs(u16),
+	Cdev(CdevLineConfig)
+}
+
+impl std::fmt::Display for PinConfig {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Sysfs(pin) => write!(f, "sysfs:{}", pin),
+			Self::Cdev(cfg) => match &cfg.line {
+				LineId::Offset(offset) => write!(f, "{}:{}", cfg.chip, offset),
+				LineId::Name(name) => write!(f, "{}:'{}'", cfg.chip, name)
+			}
+		}
+	}
+}
+
+pub trait InputPin: Send {
+	fn read(&mut self) -> Result<GpioValue, Error>;
+}
+
+pub trait OutputPin: Send {
+	fn write(&mut self, val: GpioValue) -> Result<(), Error>;
+}
+
+/// Input with falling edges queued by the kernel, pulse between two checks is not lost
+pub trait EdgeInput: InputPin {
+	/// Take edges queued since last call, true if any came
+	fn take_falling(&mut self) -> Result<bool, Error>;
+	/// Descriptor readable while edge is queued, negative if there is none
+	fn fd(&self) -> RawFd;
+}
+
+impl InputPin for SysFsGpioInput {
+	fn read(&mut self) -> Result<GpioValue, Error> {
+		self.read_value()
+	}
+}
+
+impl OutputPin for SysFsGpioOutput {
+	fn write(&mut self, val: GpioValue) -> Result<(), Error> {
+		self.set_value(val)
+	}
+}
+
+pub struct CdevPin {
+	handle: LineHandle
+}
+
+impl InputPin for CdevPin {
+	fn read(&mut self) -> Result<GpioValue, Error> {
+		self.handle.get_value().map(GpioValue::from).map_err(cdev_err)
+	}
+}
+
+impl OutputPin for CdevPin {
+	fn write(&mut self, val: GpioValue) -> Result<(), Error> {
+		self.handle.set_value(val.into()).map_err(cdev_err)
+	}
+}
+
+pub struct CdevEdges {
+	handle: LineEventHandle
+}
+
+impl InputPin for CdevEdges {
+	fn read(&mut self) -> Result<GpioValue, Error> {
+		self.handle.get_value().map(GpioValue::from).map_err(cdev_err)
+	}
+}
+
+impl EdgeInput for CdevEdges {
+	fn take_falling(&mut self) -> Result<bool, Error> {
+		let mut fired = false;
+		while wait_readable(&[self.fd()], Duration::ZERO)? {
+			self.handle.get_event().map_err(cdev_err)?;
+			fired = true;
+		}
+		Ok(fired)
+	}
+
+	fn fd(&self) -> RawFd {
+		self.handle.as_raw_fd()
+	}
+}
+
+/// poll(2) `fds` for input, true if any is readable before `timeout`
+fn wait_readable(fds: &[RawFd], timeout: Duration) -> Result<bool, Error> {
+	let mut polled: Vec<libc::pollfd> = fds.iter().map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 }).collect();
+	let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+	match unsafe { libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, ms) } {
+		n if n >= 0 => Ok(n > 0),
+		_ => match Error::last_os_error() {
+			e if e.kind() == ErrorKind::Interrupted => Ok(false),
+			e => Err(e)
+		}
+	}
+}
+
+/// Wait up to `timeout` for edge on any of `pins`, edges stay queued for `take_falling`
+pub fn wait_falling(pins: &[&dyn EdgeInput], timeout: Duration) -> Result<(), Error> {
+	let fds: Vec<RawFd> = pins.iter().map(|p| p.fd()).collect();
+	wait_readable(&fds, timeout).map(|_| ())
+}
+
+fn cdev_err(e: gpio_cdev::Error) -> Error {
+	Error::other(e.to_string())
+}
+
+fn chip_path(chip: &str) -> String {
+	if chip.starts_with('/') {
+		chip.to_string()
+	} else {
+		format!("{}/{}", DEV_DIR, chip)
+	}
+}
+
+fn find_line(chip: &mut Chip, id: &LineId) -> Result<Line, Error> {
+	match id {
+		LineId::Offset(offset) => chip.get_line(*offset).map_err(cdev_err),
+		LineId::Name(name) => {
+			for line in chip.lines() {
+				let info = line.info().map_err(cdev_err)?;
+				if info.name() == Some(name.as_str()) {
+					return Ok(line);
+				}
+			}
+			Err(Error::new(ErrorKind::NotFound, format!("line '{}' not found at {}", name, chip.path().display())))
+		}
+	}
+}
+
+/// Drive mode is valid only for outputs, the kernel rejects it on inputs
+fn request_flags(cfg: &CdevLineConfig, direction: LineRequestFlags) -> Result<LineRequestFlags, Error> {
+	if direction.contains(LineRequestFlags::INPUT) && !matches!(cfg.drive, Drive::PushPull) {
+		return Err(Error::new(ErrorKind::InvalidInput, format!("drive {:?} is not allowed for input line", cfg.drive)));
+	}
+	let bias = match cfg.bias {
+		Bias::AsIs => 0,
+		Bias::Disable => REQUEST_BIAS_DISABLE,
+		Bias::PullUp => REQUEST_BIAS_PULL_UP,
+		Bias::PullDown => REQUEST_BIAS_PULL_DOWN
+	};
+	let drive = match cfg.drive {
+		Drive::PushPull => LineRequestFlags::empty(),
+		Drive::OpenDrain => LineRequestFlags::OPEN_DRAIN,
+		Drive::OpenSource => LineRequestFlags::OPEN_SOURCE
+	};
+	// bias bits are passed to the kernel as is, gpio_cdev only forwards `bits()`
+	Ok(direction | drive | unsafe { LineRequestFlags::from_bits_unchecked(bias) })
+}
+
+fn open_line(cfg: &CdevLineConfig) -> Result<Line, Error> {
+	let mut chip = Chip::new(chip_path(&cfg.chip)).map_err(cdev_err)?;
+	find_line(&mut chip, &cfg.line)
+}
+
+fn request_cdev(cfg: &CdevLineConfig, direction: LineRequestFlags, default: GpioValue) -> Result<CdevPin, Error> {
+	let consumer = cfg.name.as_deref().unwrap_or(CONSUMER_DEF);
+	let handle = open_line(cfg)?.request(request_flags(cfg, direction)?, default.into(), consumer).map_err(cdev_err)?;
+	Ok(CdevPin { handle })
+}
+
+/// Open pin as input with backend selected by config
+pub fn open_input(cfg: &PinConfig) -> Result<Box<dyn InputPin>, Error> {
+	match cfg {
+		PinConfig::Sysfs(pin) => Ok(Box::new(SysFsGpioInput::open(*pin)?)),
+		PinConfig::Cdev(cfg) => Ok(Box::new(request_cdev(cfg, LineRequestFlags::INPUT, GpioValue::Low)?))
+	}
+}
+
+/// Open pin as output with backend selected by config, `default` is applied at request
+pub fn open_output(cfg: &PinConfig, default: GpioValue) -> Result<Box<dyn OutputPin>, Error> {
+	match cfg {
+		PinConfig::Sysfs(pin) => {
+			let mut pin = SysFsGpioOutput::open(*pin)?;
+			pin.set_value(default)?;
+			Ok(Box::new(pin))
+		},
+		PinConfig::Cdev(cfg) => Ok(Box::new(request_cdev(cfg, LineRequestFlags::OUTPUT, default)?))
+	}
+}
+
+/// Open character device line as input with falling edge events,
+/// sysfs pins have no events here and are read by level
+pub fn open_falling_edges(cfg: &CdevLineConfig) -> Result<Box<dyn EdgeInput>, Error> {
+	let consumer = cfg.name.as_deref().unwrap_or(CONSUMER_DEF);
+	let flags = request_flags(cfg, LineRequestFlags::INPUT)?;
+	let handle = open_line(cfg)?.events(flags, EventRequestFlags::FALLING_EDGE, consumer).map_err(cdev_err)?;
+	Ok(Box::new(CdevEdges { handle }))
+}
@@ -0,0 +1,55 @@
+use std::io::Error;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use gpio::GpioValue;
+
+use crate::gpioline::{EdgeInput, InputPin, OutputPin};
+
+/// Wire between mock pins, shared with test to drive and inspect level
+pub type Net = Arc<Mutex<GpioValue>>;
+
+pub fn net(level: GpioValue) -> Net {
+	Arc::new(Mutex::new(level))
+}
+
+/// Input reading level of its net
+pub struct MockInput(pub Net);
+
+impl InputPin for MockInput {
+	fn read(&mut self) -> Result<GpioValue, Error> {
+		Ok(*self.0.lock().unwrap())
+	}
+}
+
+/// Output driving every net wired to it, no net leaves it unconnected
+pub struct MockOutput(pub Vec<Net>);
+
+impl OutputPin for MockOutput {
+	fn write(&mut self, val: GpioValue) -> Result<(), Error> {
+		self.0.iter().for_each(|n| *n.lock().unwrap() = val);
+		Ok(())
+	}
+}
+
+/// Input of `MockInput` kind with falling edges queued by test
+pub struct MockEdges {
+	pub net: Net,
+	pub falling: Arc<Mutex<usize>>
+}
+
+impl InputPin for MockEdges {
+	fn read(&mut self) -> Result<GpioValue, Error> {
+		Ok(*self.net.lock().unwrap())
+	}
+}
+
+impl EdgeInput for MockEdges {
+	fn take_falling(&mut self) -> Result<bool, Error> {
+		Ok(std::mem::take(&mut *self.falling.lock().unwrap()) > 0)
+	}
+
+	fn fd(&self) -> RawFd {
+		-1
+	}
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use i2cdev::{
+	core::{I2CDevice, I2CMessage, I2CTransfer},
+	linux::{LinuxI2CBus, LinuxI2CDevice, LinuxI2CMessage}
+};
+use serde::Deserialize;
+
+/// Longest block of SMBus i2c block transfers
+const SMBUS_BLOCK_MAX: usize = 32;
+
+/// How the bus adapter is accessed
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum BusMode {
+	/// Plain I2C messages (I2C_RDWR), needs adapter with I2C_FUNC_I2C
+	#[default]
+	I2c,
+	/// SMBus commands only, e.g. for kernel `i2c-stub`
+	Smbus
+}
+
+/// I2C bus master. `write` with empty data is SMBus quick write.
+pub trait I2cBus: Send {
+	fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), Error>;
+	fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), Error>;
+	/// Write then read with repeated start, used for register reads
+	fn write_read(&mut self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), Error>;
+}
+
+impl I2cBus for LinuxI2CBus {
+	fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), Error> {
+		let mut msgs = [LinuxI2CMessage::write(data).with_address(addr as u16)];
+		self.transfer(&mut msgs).map(|_| ()).map_err(Error::from)
+	}
+
+	fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), Error> {
+		let mut msgs = [LinuxI2CMessage::read(buf).with_address(addr as u16)];
+		self.transfer(&mut msgs).map(|_| ()).map_err(Error::from)
+	}
+
+	fn write_read(&mut self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), Error> {
+		let mut msgs = [
+			LinuxI2CMessage::write(data).with_address(addr as u16),
+			LinuxI2CMessage::read(buf).with_address(addr as u16)
+		];
+		self.transfer(&mut msgs).map(|_| ()).map_err(Error::from)
+	}
+}
+
+/// Bus on SMBus-only adapter, transfers are mapped to SMBus commands
+pub struct SmbusBus {
+	driver: String,
+	devices: HashMap<u8, LinuxI2CDevice>
+}
+
+fn unsupported(what: &str) -> Error {
+	Error::new(ErrorKind::Unsupported, format!("{} has no SMBus equivalent", what))
+}
+
+impl SmbusBus {
+	pub fn new(driver: &str) -> Self {
+		Self {
+			driver: driver.to_string(),
+			devices: HashMap::new()
+		}
+	}
+
+	fn device(&mut self, addr: u8) -> Result<&mut LinuxI2CDevice, Error> {
+		if !self.devices.contains_key(&addr) {
+			let dev = LinuxI2CDevice::new(&self.driver, addr as u16).map_err(Error::from)?;
+			self.devices.insert(addr, dev);
+		}
+		Ok(self.devices.get_mut(&addr).unwrap())
+	}
+}
+
+impl I2cBus for SmbusBus {
+	fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), Error> {
+		let dev = self.device(addr)?;
+		let res = match data {
+			[] => dev.smbus_write_quick(false),
+			[val] => dev.smbus_write_byte(*val),
+			[reg, val] => dev.smbus_write_byte_data(*reg, *val),
+			[reg, rest @ ..] if rest.len() <= SMBUS_BLOCK_MAX => dev.smbus_write_i2c_block_data(*reg, rest),
+			_ => return Err(unsupported("Long write"))
+		};
+		res.map_err(Error::from)
+	}
+
+	fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), Error> {
+		if buf.len() != 1 {
+			return Err(unsupported("Multi byte read without register"));
+		}
+		buf[0] = self.device(addr)?.smbus_read_byte().map_err(Error::from)?;
+		Ok(())
+	}
+
+	fn write_read(&mut self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), Error> {
+		let reg = match data {
+			[reg] => *reg,
+			_ => return Err(unsupported("Register address longer than one byte"))
+		};
+		let dev = self.device(addr)?;
+		match buf.len() {
+			1 => buf[0] = dev.smbus_read_byte_data(reg).map_err(Error::from)?,
+			len if len <= SMBUS_BLOCK_MAX => {
+				let data = dev.smbus_read_i2c_block_data(reg, len as u8).map_err(Error::from)?;
+				if data.len() != len {
+					return Err(Error::new(ErrorKind::UnexpectedEof, "Short SMBus block read"));
+				}
+				buf.copy_from_slice(&data);
+			},
+			_ => return Err(unsupported("Long read"))
+		}
+		Ok(())
+	}
+}
+
+pub fn open(driver: &str, mode: BusMode) -> Result<Box<dyn I2cBus>, String> {
+	match mode {
+		BusMode::I2c => match LinuxI2CBus::new(driver) {
+			Ok(bus) => Ok(Box::new(bus)),
+			Err(e) => Err(format!("Fail to open bus {}: {}", driver, e))
+		},
+		BusMode::Smbus => Ok(Box::new(SmbusBus::new(driver)))
+	}
+}
+
+pub fn write(bus: &mut dyn I2cBus, addr: u8, buf: &[u8]) -> Result<(), String> {
+	bus.write(addr, buf).map_err(|e| format!("Fail write to dev: {}", e))
+}
+
+pub fn read(bus: &mut dyn I2cBus, addr: u8, buf: &mut [u8]) -> Result<(), String> {
+	bus.read(addr, buf).map_err(|e| format!("Fail read from dev: {}", e))
+}
+
+pub fn read_reg(bus: &mut dyn I2cBus, addr: u8, reg: u8, buf: &mut [u8]) -> Result<(), String> {
+	bus.write_read(addr, &[reg], buf).map_err(|e| format!("Fail read reg 0x{:02X} from dev: {}", reg, e))
+}
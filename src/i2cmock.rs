@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+
+use crate::i2cbus::I2cBus;
+
+/// No acknowledge of address, same errno as Linux adapters return
+const ENXIO: i32 = 6;
+
+pub mod mcp23017 {
+	pub const IODIRA: usize = 0x00;
+	pub const IPOLA: usize = 0x02;
+	pub const GPINTENA: usize = 0x04;
+	pub const INTFA: usize = 0x0E;
+	pub const INTCAPA: usize = 0x10;
+	pub const GPIOA: usize = 0x12;
+	pub const OLATA: usize = 0x14;
+	pub const REG_COUNT: usize = 0x16;
+}
+
+/// Chip attached to `MockBus`, every transfer is a separate START..STOP
+pub trait MockDevice: Send {
+	fn write(&mut self, data: &[u8]);
+	fn read(&mut self, buf: &mut [u8]);
+}
+
+/// In-memory bus, devices are shared with test to drive and inspect pins
+#[derive(Default)]
+pub struct MockBus {
+	devices: HashMap<u8, Arc<Mutex<dyn MockDevice>>>
+}
+
+impl MockBus {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn attach<D: MockDevice + 'static>(&mut self, addr: u8, dev: &Arc<Mutex<D>>) {
+		self.devices.insert(addr, dev.clone());
+	}
+
+	fn device(&self, addr: u8) -> Result<&Arc<Mutex<dyn MockDevice>>, Error> {
+		self.devices.get(&addr).ok_or_else(|| Error::from_raw_os_error(ENXIO))
+	}
+}
+
+impl I2cBus for MockBus {
+	fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), Error> {
+		self.device(addr)?.lock().unwrap().write(data);
+		Ok(())
+	}
+
+	fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), Error> {
+		self.device(addr)?.lock().unwrap().read(buf);
+		Ok(())
+	}
+
+	fn write_read(&mut self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), Error> {
+		let mut dev = self.device(addr)?.lock().unwrap();
+		dev.write(data);
+		dev.read(buf);
+		Ok(())
+	}
+}
+
+/// PCF8574/PCF8575 quasi-bidirectional port. Pin written high is a weak pull-up,
+/// it reads low when pulled low externally; pin written low always reads low.
+pub struct Pcf857x {
+	width: u8,
+	pub latch: u16,
+	/// Pins pulled low from outside, e.g. pushed buttons
+	pub pulled_low: u16,
+	pub writes: u32
+}
+
+impl Pcf857x {
+	pub fn new(width: u8) -> Self {
+		// power-on latch is all high
+		Self { width, latch: 0xFFFF, pulled_low: 0, writes: 0 }
+	}
+
+	pub fn pull_low(&mut self, pin: u8, low: bool) {
+		if low {
+			self.pulled_low |= 1 << pin;
+		} else {
+			self.pulled_low &= !(1 << pin);
+		}
+	}
+
+	pub fn pins(&self) -> u16 {
+		self.latch & !self.pulled_low
+	}
+}
+
+impl MockDevice for Pcf857x {
+	fn write(&mut self, data: &[u8]) {
+		let len = (self.width / 8) as usize;
+		// chip latches every full port transfer, last one wins
+		for chunk in data.chunks_exact(len) {
+			self.latch = if len > 1 { u16::from_le_bytes([chunk[0], chunk[1]]) } else { chunk[0] as u16 | 0xFF00 };
+			self.writes += 1;
+		}
+	}
+
+	fn read(&mut self, buf: &mut [u8]) {
+		let pins = self.pins().to_le_bytes();
+		let len = (self.width / 8) as usize;
+		for (i, b) in buf.iter_mut().enumerate() {
+			*b = pins[i % len];
+		}
+	}
+}
+
+/// MCP23017 register file in IOCON.BANK = 0 mode with sequential addressing
+pub struct Mcp23017 {
+	pub regs: [u8;mcp23017::REG_COUNT],
+	ptr: usize,
+	/// Levels driven on pins from outside, undriven pins are pulled up
+	pub external: u16,
+	/// Register writes in order as (register, data)
+	pub log: Vec<(usize, Vec<u8>)>
+}
+
+impl Default for Mcp23017 {
+	fn default() -> Self {
+		let mut regs = [0u8;mcp23017::REG_COUNT];
+		// all pins are inputs after reset
+		regs[mcp23017::IODIRA] = 0xFF;
+		regs[mcp23017::IODIRA + 1] = 0xFF;
+		Self { regs, ptr: 0, external: 0xFFFF, log: Vec::new() }
+	}
+}
+
+impl Mcp23017 {
+	pub fn reg16(&self, reg: usize) -> u16 {
+		u16::from_le_bytes([self.regs[reg], self.regs[reg + 1]])
+	}
+
+	fn set_reg16(&mut self, reg: usize, val: u16) {
+		self.regs[reg..reg + 2].copy_from_slice(&val.to_le_bytes());
+	}
+
+	pub fn olat(&self) -> u16 {
+		self.reg16(mcp23017::OLATA)
+	}
+
+	pub fn iodir(&self) -> u16 {
+		self.reg16(mcp23017::IODIRA)
+	}
+
+	/// Pin values as read from GPIO register
+	pub fn gpio(&self) -> u16 {
+		let iodir = self.iodir();
+		let pins = (self.olat() & !iodir) | (self.external & iodir);
+		pins ^ (self.reg16(mcp23017::IPOLA) & iodir)
+	}
+
+	/// Drive pin from outside, captures port on enabled interrupt
+	pub fn set_input(&mut self, pin: u8, high: bool) {
+		let before = self.gpio();
+		if high {
+			self.external |= 1 << pin;
+		} else {
+			self.external &= !(1 << pin);
+		}
+		let changed = (before ^ self.gpio()) & self.reg16(mcp23017::GPINTENA);
+		if changed != 0 && self.reg16(mcp23017::INTFA) == 0 {
+			self.set_reg16(mcp23017::INTFA, changed);
+			self.set_reg16(mcp23017::INTCAPA, before ^ changed);
+		}
+	}
+
+	pub fn int_active(&self) -> bool {
+		self.reg16(mcp23017::INTFA) != 0
+	}
+}
+
+impl MockDevice for Mcp23017 {
+	fn write(&mut self, data: &[u8]) {
+		let Some((reg, vals)) = data.split_first() else {
+			return;
+		};
+		self.ptr = *reg as usize % mcp23017::REG_COUNT;
+		if !vals.is_empty() {
+			self.log.push((self.ptr, vals.to_vec()));
+		}
+		for val in vals {
+			match self.ptr {
+				// GPIO write goes to output latch
+				r if r == mcp23017::GPIOA || r == mcp23017::GPIOA + 1 => self.regs[r + 2] = *val,
+				r if (mcp23017::INTFA..mcp23017::GPIOA).contains(&r) => (),
+				r => self.regs[r] = *val
+			}
+			self.ptr = (self.ptr + 1) % mcp23017::REG_COUNT;
+		}
+	}
+
+	fn read(&mut self, buf: &mut [u8]) {
+		let gpio = self.gpio().to_le_bytes();
+		for b in buf.iter_mut() {
+			*b = match self.ptr {
+				r if r == mcp23017::GPIOA || r == mcp23017::GPIOA + 1 => gpio[r - mcp23017::GPIOA],
+				r => self.regs[r]
+			};
+			// reading GPIO or INTCAP clears interrupt of the port
+			if (mcp23017::INTCAPA..mcp23017::OLATA).contains(&self.ptr) {
+				self.regs[mcp23017::INTFA + self.ptr % 2] = 0;
+			}
+			self.ptr = (self.ptr + 1) % mcp23017::REG_COUNT;
+		}
+	}
+}
+
+/// 24Cxx EEPROM answering at one device address: word address of `addr_bytes`,
+/// page write and sequential read wrap inside page and memory
+pub struct Eeprom24 {
+	pub mem: Vec<u8>,
+	page: usize,
+	addr_bytes: usize,
+	ptr: usize,
+	/// Write cycles, one per transfer with data
+	pub writes: u32
+}
+
+impl Eeprom24 {
+	pub fn new(size: usize, page: usize, addr_bytes: usize) -> Self {
+		// erased cells read as ones
+		Self { mem: vec![0xFF;size], page, addr_bytes, ptr: 0, writes: 0 }
+	}
+}
+
+impl MockDevice for Eeprom24 {
+	fn write(&mut self, data: &[u8]) {
+		if data.len() < self.addr_bytes {
+			return;
+		}
+		let (word, vals) = data.split_at(self.addr_bytes);
+		self.ptr = word.iter().fold(0, |acc, b| acc << 8 | *b as usize) % self.mem.len();
+		if vals.is_empty() {
+			return;
+		}
+		let base = self.ptr - self.ptr % self.page;
+		for (i, val) in vals.iter().enumerate() {
+			self.mem[base + (self.ptr - base + i) % self.page] = *val;
+		}
+		self.writes += 1;
+	}
+
+	fn read(&mut self, buf: &mut [u8]) {
+		for b in buf.iter_mut() {
+			*b = self.mem[self.ptr];
+			self.ptr = (self.ptr + 1) % self.mem.len();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pcf_input_reads_low_only_when_pulled() {
+		let mut pcf = Pcf857x::new(8);
+		pcf.write(&[0x0F]);
+		pcf.pull_low(0, true);
+		pcf.pull_low(7, true);
+		let mut buf = [0u8];
+		pcf.read(&mut buf);
+		assert_eq!(buf[0], 0x0E);
+	}
+
+	#[test]
+	fn mcp_gpio_write_goes_to_latch() {
+		let mut mcp = Mcp23017::default();
+		mcp.write(&[mcp23017::IODIRA as u8, 0x0F, 0x00]);
+		mcp.write(&[mcp23017::GPIOA as u8, 0xA5, 0x5A]);
+		assert_eq!(mcp.olat(), 0x5AA5);
+		assert_eq!(mcp.gpio(), 0x5AAF);
+	}
+
+	#[test]
+	fn mcp_interrupt_capture_cleared_by_read() {
+		let mut mcp = Mcp23017::default();
+		mcp.write(&[mcp23017::GPINTENA as u8, 0x01, 0x00]);
+		mcp.set_input(0, false);
+		mcp.set_input(0, true);
+		assert!(mcp.int_active());
+		let mut cap = [0u8;2];
+		mcp.write(&[mcp23017::INTCAPA as u8]);
+		mcp.read(&mut cap);
+		assert_eq!(u16::from_le_bytes(cap), 0xFFFE);
+		assert!(!mcp.int_active());
+	}
+
+	#[test]
+	fn eeprom_page_write_wraps_inside_page() {
+		let mut eeprom = Eeprom24::new(256, 8, 1);
+		eeprom.write(&[0x06, 1, 2, 3]);
+		assert_eq!(&eeprom.mem[0..8], &[3, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 1, 2]);
+		let mut buf = [0u8;2];
+		eeprom.write(&[0x07]);
+		eeprom.read(&mut buf);
+		assert_eq!(buf, [2, 0xFF]);
+		assert_eq!(eeprom.writes, 1);
+	}
+}
@@ -0,0 +1,229 @@
+use std::io;
+
+use crate::expander::Chip;
+use crate::i2cbus::{self, I2cBus};
+use crate::sensor::SensorChip;
+
+pub const ADDR_FIRST: u8 = 0x03;
+pub const ADDR_LAST: u8 = 0x77;
+
+const EOPNOTSUPP: i32 = 95;
+
+/// Devices known by address range and, for register based chips, by signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Known {
+	PCF8574,
+	PCF8574A,
+	PCF8575,
+	MCP23017,
+	PCA9555,
+	TCA6408,
+	ADS1115,
+	TMP102,
+	LM75,
+	EEPROM24,
+	DS3231,
+	BME280
+}
+
+impl Known {
+	/// Quasi-bidirectional chips have no registers, any written byte goes to the port
+	fn has_registers(&self) -> bool {
+		!matches!(self, Self::PCF8574 | Self::PCF8574A | Self::PCF8575 | Self::EEPROM24)
+	}
+
+	pub fn from_chip(chip: Chip, addr: u8) -> Self {
+		match chip {
+			Chip::PCF8574 if (0x38..=0x3F).contains(&addr) => Self::PCF8574A,
+			Chip::PCF8574 => Self::PCF8574,
+			Chip::PCF8575 => Self::PCF8575,
+			Chip::MCP23017 => Self::MCP23017,
+			Chip::PCA9555 | Chip::TCA9555 => Self::PCA9555,
+			Chip::TCA6408 => Self::TCA6408
+		}
+	}
+
+	pub fn from_sensor(chip: SensorChip) -> Self {
+		match chip {
+			// same config register layout and defaults
+			SensorChip::ADS1115 | SensorChip::ADS1015 => Self::ADS1115,
+			SensorChip::LM75 => Self::LM75,
+			SensorChip::TMP102 => Self::TMP102,
+			SensorChip::BME280 => Self::BME280
+		}
+	}
+}
+
+/// Devices which may respond at `addr`
+pub fn candidates(addr: u8) -> Vec<Known> {
+	let mut list = Vec::new();
+	if (0x20..=0x27).contains(&addr) {
+		list.extend([Known::PCF8574, Known::PCF8575, Known::MCP23017, Known::PCA9555]);
+	}
+	if (0x20..=0x21).contains(&addr) {
+		list.push(Known::TCA6408);
+	}
+	if (0x38..=0x3F).contains(&addr) {
+		list.push(Known::PCF8574A);
+	}
+	if (0x48..=0x4B).contains(&addr) {
+		list.extend([Known::ADS1115, Known::TMP102]);
+	}
+	if (0x48..=0x4F).contains(&addr) {
+		list.push(Known::LM75);
+	}
+	if (0x50..=0x57).contains(&addr) {
+		list.push(Known::EEPROM24);
+	}
+	if addr == 0x68 {
+		list.push(Known::DS3231);
+	}
+	if (0x76..=0x77).contains(&addr) {
+		list.push(Known::BME280);
+	}
+	list
+}
+
+/// Quick read: one byte read, safe for EEPROMs and write-only sensitive chips
+fn quick_read(bus: &mut dyn I2cBus, addr: u8) -> Result<(), io::Error> {
+	let mut buf = [0u8;1];
+	bus.read(addr, &mut buf)
+}
+
+/// Quick write: address with zero length write, does not touch device state
+fn quick_write(bus: &mut dyn I2cBus, addr: u8) -> Result<(), io::Error> {
+	bus.write(addr, &[])
+}
+
+/// Check device acknowledges `addr`, same method choice as i2cdetect
+pub fn probe(bus: &mut dyn I2cBus, addr: u8) -> bool {
+	if (0x30..=0x37).contains(&addr) || (0x50..=0x5F).contains(&addr) {
+		return quick_read(bus, addr).is_ok();
+	}
+	match quick_write(bus, addr) {
+		Ok(()) => true,
+		Err(e) if e.raw_os_error() == Some(EOPNOTSUPP) => quick_read(bus, addr).is_ok(),
+		Err(_) => false
+	}
+}
+
+fn read_u8(bus: &mut dyn I2cBus, addr: u8, reg: u8) -> Option<u8> {
+	let mut buf = [0u8;1];
+	i2cbus::read_reg(bus, addr, reg, &mut buf).ok().map(|_| buf[0])
+}
+
+fn read_be16(bus: &mut dyn I2cBus, addr: u8, reg: u8) -> Option<u16> {
+	let mut buf = [0u8;2];
+	i2cbus::read_reg(bus, addr, reg, &mut buf).ok().map(|_| u16::from_be_bytes(buf))
+}
+
+/// Check register signature of device, `None` if device has no signature
+fn signature(bus: &mut dyn I2cBus, addr: u8, dev: Known) -> Option<bool> {
+	let matched = match dev {
+		// IOCON is mapped to both 0x0A and 0x0B, bit 0 is unimplemented
+		Known::MCP23017 => match (read_u8(bus, addr, 0x0A), read_u8(bus, addr, 0x0B)) {
+			(Some(a), Some(b)) => a == b && a & 0x01 == 0,
+			_ => false
+		},
+		// only 3 bits of command are decoded, registers repeat every 8
+		Known::PCA9555 | Known::TCA6408 => match (read_u8(bus, addr, 0x06), read_u8(bus, addr, 0x0E)) {
+			(Some(a), Some(b)) => a == b,
+			_ => false
+		},
+		// config register power-on default
+		Known::ADS1115 => matches!(read_be16(bus, addr, 0x01), Some(cfg) if cfg & 0x7FFF == 0x0583),
+		// resolution bits R1, R0 are read-only ones
+		Known::TMP102 => matches!(read_be16(bus, addr, 0x01), Some(cfg) if cfg & 0x6000 == 0x6000),
+		// Thyst and Tos are 9-bit values, low 7 bits are zero
+		Known::LM75 => match (read_be16(bus, addr, 0x02), read_be16(bus, addr, 0x03)) {
+			(Some(hyst), Some(os)) => hyst & 0x7F == 0 && os & 0x7F == 0,
+			_ => false
+		},
+		Known::BME280 => read_u8(bus, addr, 0xD0) == Some(0x60),
+		_ => return None
+	};
+	Some(matched)
+}
+
+pub struct Found {
+	pub candidates: Vec<Known>,
+	/// Device confirmed by register signature
+	pub identified: Option<Known>
+}
+
+/// Identify device at `addr`. Register probes write a pointer byte which
+/// quasi-bidirectional chips latch on outputs, so where they may sit probes run
+/// only with `probe_registers` and a register chip `expected`.
+pub fn identify(bus: &mut dyn I2cBus, addr: u8, expected: Option<Known>, probe_registers: bool) -> Found {
+	let candidates = candidates(addr);
+	let safe = candidates.iter().all(|c| c.has_registers())
+		|| (probe_registers && expected.map(|e| e.has_registers()).unwrap_or(false));
+	let mut identified = None;
+	if safe {
+		let mut order = candidates.clone();
+		if let Some(e) = expected {
+			order.retain(|c| *c != e);
+			order.insert(0, e);
+		}
+		for dev in order {
+			if signature(bus, addr, dev) == Some(true) {
+				identified = Some(dev);
+				break;
+			}
+		}
+	}
+	Found { candidates, identified }
+}
+
+/// Print present addresses as i2cdetect-like table
+pub fn print_map(present: &[u8]) {
+	println!("\t     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+	for row in (0..0x80u8).step_by(16) {
+		let mut line = format!("\t{:02x}: ", row);
+		for addr in row..row + 16 {
+			if !(ADDR_FIRST..=ADDR_LAST).contains(&addr) {
+				line.push_str("   ");
+			} else if present.contains(&addr) {
+				line.push_str(&format!("{:02x} ", addr));
+			} else {
+				line.push_str("-- ");
+			}
+		}
+		println!("{}", line.trim_end());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::i2cmock::{MockBus, Mcp23017, Pcf857x};
+
+	#[test]
+	fn pcf_latch_survives_scan_for_register_chip() {
+		let pcf = Arc::new(Mutex::new(Pcf857x::new(8)));
+		let mut bus = MockBus::new();
+		bus.attach(0x20, &pcf);
+		bus.write(0x20, &[0xA5]).unwrap();
+		let writes = pcf.lock().unwrap().writes;
+
+		assert!(probe(&mut bus, 0x20));
+		let found = identify(&mut bus, 0x20, Some(Known::MCP23017), false);
+		assert_eq!(found.identified, None);
+		assert!(found.candidates.contains(&Known::PCF8574));
+		let pcf = pcf.lock().unwrap();
+		assert_eq!(pcf.latch & 0xFF, 0xA5);
+		assert_eq!(pcf.writes, writes);
+	}
+
+	#[test]
+	fn register_probe_is_explicit() {
+		let mcp = Arc::new(Mutex::new(Mcp23017::default()));
+		let mut bus = MockBus::new();
+		bus.attach(0x21, &mcp);
+		assert_eq!(identify(&mut bus, 0x21, Some(Known::MCP23017), true).identified, Some(Known::MCP23017));
+		// sensor range has no quasi-bidirectional chips
+		assert_eq!(identify(&mut bus, 0x48, None, false).identified, None);
+	}
+}
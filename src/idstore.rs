@@ -0,0 +1,216 @@
+use serde::Deserialize;
+
+use crate::eeprom::{Eeprom, EepromModel};
+use crate::i2cbus::I2cBus;
+
+const MAGIC: u16 = 0x5743;
+const VERSION: u8 = 1;
+pub const SERIAL_LEN: usize = 16;
+/// magic(2) version(1) reserved(1) seq(4) serial(16) hw_rev(2) money(8) cycles(4) crc(4)
+const RECORD_SIZE: usize = 42;
+/// Record slots are aligned to this size
+const SLOT_ALIGN: u32 = 8;
+const CRC32_POLY: u32 = 0xEDB88320;
+
+#[derive(Deserialize)]
+pub struct IdStoreConfig {
+	model: EepromModel,
+	addr: u8,
+	/// First byte of store area
+	#[serde(default)]
+	offset: u32,
+	/// Number of record slots used for wear levelling
+	slots: u32
+}
+
+/// Machine identity and lifetime counters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MachineRecord {
+	pub serial: String,
+	pub hw_rev: u16,
+	/// Lifetime money in minimal currency units
+	pub money: u64,
+	pub cycles: u32
+}
+
+pub enum SlotState {
+	Empty,
+	Valid(u32, MachineRecord),
+	Corrupted
+}
+
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc = 0xFFFFFFFFu32;
+	for byte in data {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32_POLY } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+impl MachineRecord {
+	fn encode(&self, seq: u32) -> Result<[u8;RECORD_SIZE], String> {
+		if self.serial.len() > SERIAL_LEN {
+			return Err(format!("Serial number is longer than {} bytes", SERIAL_LEN));
+		}
+		let mut buf = [0u8;RECORD_SIZE];
+		buf[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+		buf[2] = VERSION;
+		buf[4..8].copy_from_slice(&seq.to_le_bytes());
+		buf[8..8 + self.serial.len()].copy_from_slice(self.serial.as_bytes());
+		buf[24..26].copy_from_slice(&self.hw_rev.to_le_bytes());
+		buf[26..34].copy_from_slice(&self.money.to_le_bytes());
+		buf[34..38].copy_from_slice(&self.cycles.to_le_bytes());
+		let crc = crc32(&buf[..38]);
+		buf[38..42].copy_from_slice(&crc.to_le_bytes());
+		Ok(buf)
+	}
+
+	fn decode(buf: &[u8;RECORD_SIZE]) -> SlotState {
+		if buf.iter().all(|b| *b == 0xFF) {
+			return SlotState::Empty;
+		}
+		let crc = u32::from_le_bytes([buf[38], buf[39], buf[40], buf[41]]);
+		if u16::from_le_bytes([buf[0], buf[1]]) != MAGIC || buf[2] != VERSION || crc32(&buf[..38]) != crc {
+			return SlotState::Corrupted;
+		}
+		let serial = &buf[8..24];
+		let len = serial.iter().position(|b| *b == 0).unwrap_or(SERIAL_LEN);
+		let seq = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+		SlotState::Valid(seq, Self {
+			serial: String::from_utf8_lossy(&serial[..len]).into_owned(),
+			hw_rev: u16::from_le_bytes([buf[24], buf[25]]),
+			money: u64::from_le_bytes(buf[26..34].try_into().unwrap()),
+			cycles: u32::from_le_bytes(buf[34..38].try_into().unwrap())
+		})
+	}
+}
+
+/// Record store in EEPROM. Every write goes to the slot after the newest one,
+/// the newest valid record is found by sequence number.
+pub struct IdStore {
+	eeprom: Eeprom,
+	offset: u32,
+	slots: u32
+}
+
+impl IdStore {
+	pub fn new(config: &IdStoreConfig) -> Result<Self, String> {
+		let store = Self {
+			eeprom: Eeprom::new(config.model, config.addr),
+			offset: config.offset,
+			slots: config.slots
+		};
+		if store.slots == 0 {
+			return Err(String::from("Record store needs at least one slot"));
+		}
+		let end = store.slot_offset(store.slots - 1) + RECORD_SIZE as u32;
+		if end > config.model.size() {
+			return Err(format!("Record store needs {} bytes, EEPROM {:?} has {}", end, config.model, config.model.size()));
+		}
+		Ok(store)
+	}
+
+	fn slot_offset(&self, slot: u32) -> u32 {
+		let size = (RECORD_SIZE as u32).div_ceil(SLOT_ALIGN) * SLOT_ALIGN;
+		self.offset + slot * size
+	}
+
+	pub fn slots(&self) -> u32 {
+		self.slots
+	}
+
+	pub fn read_slot(&self, bus: &mut dyn I2cBus, slot: u32) -> Result<SlotState, String> {
+		let mut buf = [0u8;RECORD_SIZE];
+		self.eeprom.read(bus, self.slot_offset(slot), &mut buf)?;
+		Ok(MachineRecord::decode(&buf))
+	}
+
+	/// Newest valid record as (slot, seq, record)
+	pub fn latest(&self, bus: &mut dyn I2cBus) -> Result<Option<(u32, u32, MachineRecord)>, String> {
+		let mut latest: Option<(u32, u32, MachineRecord)> = None;
+		for slot in 0..self.slots {
+			if let SlotState::Valid(seq, rec) = self.read_slot(bus, slot)? {
+				let newer = match &latest {
+					// sequence may wrap, compare by distance
+					Some((_, best, _)) => (seq.wrapping_sub(*best) as i32) > 0,
+					None => true
+				};
+				if newer {
+					latest = Some((slot, seq, rec));
+				}
+			}
+		}
+		Ok(latest)
+	}
+
+	/// Write record to next slot and read it back, returns used slot
+	pub fn write(&self, bus: &mut dyn I2cBus, rec: &MachineRecord) -> Result<u32, String> {
+		let (slot, seq) = match self.latest(bus)? {
+			Some((slot, seq, _)) => ((slot + 1) % self.slots, seq.wrapping_add(1)),
+			None => (0, 0)
+		};
+		let buf = rec.encode(seq)?;
+		self.eeprom.write(bus, self.slot_offset(slot), &buf)?;
+		match self.read_slot(bus, slot)? {
+			SlotState::Valid(s, ref r) if s == seq && r == rec => Ok(slot),
+			_ => Err(format!("Read back of slot {} does not match written record", slot))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::i2cmock::{MockBus, Eeprom24};
+
+	fn store(slots: u32) -> (IdStore, MockBus) {
+		let chip = Arc::new(Mutex::new(Eeprom24::new(256, 8, 1)));
+		let mut bus = MockBus::new();
+		bus.attach(0x50, &chip);
+		let config: IdStoreConfig = toml::from_str(&format!("model = \"C02\"\naddr = 80\nslots = {}", slots)).unwrap();
+		(IdStore::new(&config).unwrap(), bus)
+	}
+
+	fn record(money: u64) -> MachineRecord {
+		MachineRecord { serial: String::from("WM-0042"), hw_rev: 3, money, cycles: 17 }
+	}
+
+	#[test]
+	fn crc32_check_value() {
+		assert_eq!(crc32(b"123456789"), 0xCBF43926);
+	}
+
+	#[test]
+	fn record_round_trip_and_corruption() {
+		let mut buf = record(1000).encode(7).unwrap();
+		assert!(matches!(MachineRecord::decode(&buf), SlotState::Valid(7, ref r) if *r == record(1000)));
+		buf[30] ^= 0x01;
+		assert!(matches!(MachineRecord::decode(&buf), SlotState::Corrupted));
+		assert!(matches!(MachineRecord::decode(&[0xFF;RECORD_SIZE]), SlotState::Empty));
+		let long = MachineRecord { serial: "X".repeat(SERIAL_LEN + 1), ..record(0) };
+		assert!(long.encode(0).is_err());
+	}
+
+	#[test]
+	fn writes_rotate_over_slots() {
+		let (store, mut bus) = store(3);
+		assert!(store.latest(&mut bus).unwrap().is_none());
+		let slots: Vec<u32> = (0..4).map(|i| store.write(&mut bus, &record(i)).unwrap()).collect();
+		assert_eq!(slots, vec![0, 1, 2, 0]);
+		assert_eq!(store.latest(&mut bus).unwrap(), Some((0, 3, record(3))));
+	}
+
+	#[test]
+	fn sequence_wraps_around() {
+		let (store, mut bus) = store(3);
+		let buf = record(1).encode(u32::MAX).unwrap();
+		store.eeprom.write(&mut bus, store.slot_offset(0), &buf).unwrap();
+		assert_eq!(store.write(&mut bus, &record(2)).unwrap(), 1);
+		assert_eq!(store.latest(&mut bus).unwrap(), Some((1, 0, record(2))));
+	}
+}
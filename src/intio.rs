@@ -1,15 +1,39 @@
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use clap::ValueEnum;
 use gpio::GpioValue;
 use serde::Deserialize;
-use gpio::sysfs::SysFsGpioInput;
-use gpio::sysfs::SysFsGpioOutput;
-use gpio::{GpioIn, GpioOut};
 
 use crate::utils;
+use crate::gpioline::{self, InputPin, OutputPin, PinConfig};
+use crate::pulse::{PulseCounter, PulseTrain};
+use crate::outsched::OutputScheduler;
 
-#[derive(Deserialize)]
+const PULSE_POLL_DELAY: Duration = Duration::from_micros(200);
+const FLOW_PRINT_PERIOD: Duration = Duration::from_secs(1);
+const TEST_PULSE_DUR: Duration = Duration::from_millis(500);
+const TEST_BLINK_DUR: Duration = Duration::from_millis(200);
+const TEST_BLINK_COUNT: u32 = 5;
+const TEST_PWM_PERIOD: Duration = Duration::from_millis(1000);
+const TEST_PWM_DUTIES: [f64;4] = [0.25, 0.5, 0.75, 0.0];
+const LOOPBACK_POLL_DELAY: Duration = Duration::from_millis(1);
+const LOOPBACK_SETTLE_DELAY: Duration = Duration::from_millis(10);
+
+/// Part of internal IO test
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IntioMode {
+	/// Inputs switch outputs
+	Io,
+	/// Flow meter or coin mech on pulse input
+	Pulse,
+	/// Pulse, blink and PWM on outputs
+	Outputs,
+	/// Outputs wired back to inputs by test harness
+	Loopback
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
 pub enum PinLevel {
 	High,
 	Low
@@ -33,39 +57,391 @@ impl PinLevel {
 
 #[derive(Deserialize)]
 pub struct IntioConfig {
-	pin_i1: u16,
-	pin_o1: u16,
-	pin_i2: u16,
-	pin_o2: u16,
+	pin_i1: PinConfig,
+	pin_o1: PinConfig,
+	pin_i2: PinConfig,
+	pin_o2: PinConfig,
 	active_input: PinLevel,
-	active_output: PinLevel
+	active_output: PinLevel,
+	pulse: Option<PulseConfig>,
+	loopback: Option<LoopbackConfig>
+}
+
+/// Output wired back to input by test harness
+#[derive(Deserialize)]
+pub struct LoopbackPair {
+	output: PinConfig,
+	input: PinConfig
+}
+
+#[derive(Deserialize)]
+pub struct LoopbackConfig {
+	timeout_ms: u64,
+	pairs: Vec<LoopbackPair>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoopbackFault {
+	/// Input does not follow output and stays at level
+	StuckAt(GpioValue),
+	/// Input of other pair follows output
+	Crossed(String)
+}
+
+impl std::fmt::Display for LoopbackFault {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::StuckAt(level) => write!(f, "stuck at {:?}", level),
+			Self::Crossed(input) => write!(f, "crossed with {}", input)
+		}
+	}
+}
+
+pub struct LoopbackResult {
+	pub output: String,
+	pub input: String,
+	pub faults: Vec<LoopbackFault>
+}
+
+impl LoopbackResult {
+	pub fn passed(&self) -> bool {
+		self.faults.is_empty()
+	}
+}
+
+#[derive(Deserialize)]
+pub enum PulseMode {
+	/// Flow meter, prints flow in litres per minute
+	Flow,
+	/// Pulse coin mech, decodes pulse trains to coin values
+	Coin
+}
+
+#[derive(Deserialize)]
+pub struct CoinPulses {
+	pulses: u32,
+	value: u32
+}
+
+#[derive(Deserialize)]
+pub struct PulseConfig {
+	pin: PinConfig,
+	active_level: PinLevel,
+	mode: PulseMode,
+	min_width_us: u64,
+	window_ms: u64,
+	pulses_per_litre: Option<f64>,
+	train_gap_ms: Option<u64>,
+	#[serde(default)]
+	coins: Vec<CoinPulses>
 }
 
-fn apply_io(input: &mut SysFsGpioInput, output: &mut SysFsGpioOutput, active_input: &PinLevel, active_output: &PinLevel) -> bool {
-	if input.read_value().unwrap() == active_input.as_gpioval() {
-		output.set_value(active_output.as_gpioval()).unwrap();
+fn apply_io(input: &mut dyn InputPin, output: &mut dyn OutputPin, active_input: &PinLevel, active_output: &PinLevel) -> bool {
+	if input.read().unwrap() == active_input.as_gpioval() {
+		output.write(active_output.as_gpioval()).unwrap();
 		true
 	} else {
-		output.set_value(active_output.inverse().as_gpioval()).unwrap();
+		output.write(active_output.inverse().as_gpioval()).unwrap();
 		false
 	}
 }
 
-pub fn test(config: &IntioConfig) -> Result<(), String>{
+fn open_input(pin: &PinConfig) -> Result<Box<dyn InputPin>, String> {
+	gpioline::open_input(pin).map_err(|e| format!("Fail to open input {}: {}", pin, e))
+}
+
+fn open_output(pin: &PinConfig, level: GpioValue) -> Result<Box<dyn OutputPin>, String> {
+	gpioline::open_output(pin, level).map_err(|e| format!("Fail to open output {}: {}", pin, e))
+}
+
+/// IO test followed by every configured part
+pub fn test(config: &IntioConfig) -> Result<(), String> {
+	test_io(config)?;
+	if config.pulse.is_some() {
+		test_pulse(config)?;
+	}
+	test_outputs(config)?;
+	if config.loopback.is_some() {
+		test_loopback(config)?;
+	}
+	Ok(())
+}
+
+pub fn test_mode(config: &IntioConfig, mode: IntioMode) -> Result<(), String> {
+	match mode {
+		IntioMode::Io => test_io(config),
+		IntioMode::Pulse => test_pulse(config),
+		IntioMode::Outputs => test_outputs(config),
+		IntioMode::Loopback => test_loopback(config)
+	}
+}
+
+fn test_io(config: &IntioConfig) -> Result<(), String> {
 	println!("\n[INTIO] Test begin..");
-	let mut pin_i1 = SysFsGpioInput::open(config.pin_i1).unwrap();
-	let mut pin_o1 = SysFsGpioOutput::open(config.pin_o1).unwrap();
-	let mut pin_i2 = SysFsGpioInput::open(config.pin_i2).unwrap();
-	let mut pin_o2 = SysFsGpioOutput::open(config.pin_o2).unwrap();
+	let inactive_output = config.active_output.inverse().as_gpioval();
+	let mut pin_i1 = open_input(&config.pin_i1)?;
+	let mut pin_o1 = open_output(&config.pin_o1, inactive_output)?;
+	let mut pin_i2 = open_input(&config.pin_i2)?;
+	let mut pin_o2 = open_output(&config.pin_o2, inactive_output)?;
 	println!("\tPush I1 and O2 should be shorted, I2-O2 too..");
 	let exiter = utils::Exiter::new();
 	loop {
-		apply_io(&mut pin_i1, &mut pin_o1, &config.active_input, &config.active_output);
-		apply_io(&mut pin_i2, &mut pin_o2, &config.active_input, &config.active_output);
+		apply_io(pin_i1.as_mut(), pin_o1.as_mut(), &config.active_input, &config.active_output);
+		apply_io(pin_i2.as_mut(), pin_o2.as_mut(), &config.active_input, &config.active_output);
 		if exiter.check() {
 			break;
 		}
 		thread::sleep(Duration::from_millis(10));
 	}
 	Ok(())
-}
\ No newline at end of file
+}
+
+fn test_flow(counter: &mut PulseCounter, pulses_per_litre: f64) -> Result<(), String> {
+	println!("\tLet water through flow meter, flow should be printed..");
+	let exiter = utils::Exiter::new();
+	let mut tl_print = Instant::now();
+	loop {
+		if let Err(e) = counter.poll() {
+			return Err(format!("Fail to read pulse input: {}", e));
+		}
+		if tl_print.elapsed() >= FLOW_PRINT_PERIOD {
+			tl_print = Instant::now();
+			let flow = counter.rate() * 60.0 / pulses_per_litre;
+			let total = counter.count() as f64 / pulses_per_litre;
+			println!("\tFlow: {:.2} l/min, total: {:.3} l", flow, total);
+		}
+		if exiter.check() {
+			break;
+		}
+		thread::sleep(PULSE_POLL_DELAY);
+	}
+	Ok(())
+}
+
+fn test_coin(counter: &mut PulseCounter, gap: Duration, coins: &[CoinPulses]) -> Result<(), String> {
+	println!("\tPut coins to coin mech, coin values should be printed..");
+	let exiter = utils::Exiter::new();
+	let mut train = PulseTrain::new(gap);
+	let mut total = 0;
+	loop {
+		match counter.poll() {
+			Ok(n) => train.push(n, Instant::now()),
+			Err(e) => return Err(format!("Fail to read pulse input: {}", e))
+		}
+		if let Some(pulses) = train.poll(Instant::now()) {
+			match coins.iter().find(|c| c.pulses == pulses) {
+				Some(coin) => {
+					total += coin.value;
+					println!("\tCOIN: {} pulses, value {}, total {}", pulses, coin.value, total);
+				},
+				None => println!("\tUnknown coin: {} pulses", pulses)
+			}
+		}
+		if exiter.check() {
+			break;
+		}
+		thread::sleep(PULSE_POLL_DELAY);
+	}
+	Ok(())
+}
+
+fn test_pulse(config: &IntioConfig) -> Result<(), String> {
+	println!("\n[INTIO] Pulse input test begin..");
+	let pulse = match &config.pulse {
+		Some(pulse) => pulse,
+		None => return Err(String::from("Pulse input is not configured"))
+	};
+	let mut counter = PulseCounter::new(open_input(&pulse.pin)?);
+	counter.set_active_level(pulse.active_level.as_gpioval());
+	counter.set_min_width(Duration::from_micros(pulse.min_width_us));
+	counter.set_poll_period(PULSE_POLL_DELAY);
+	counter.set_window(Duration::from_millis(pulse.window_ms));
+	match pulse.mode {
+		PulseMode::Flow => match pulse.pulses_per_litre {
+			Some(k) if k > 0.0 => test_flow(&mut counter, k),
+			_ => Err(String::from("'pulses_per_litre' must be set and positive for flow mode"))
+		},
+		PulseMode::Coin => match pulse.train_gap_ms {
+			Some(gap) => test_coin(&mut counter, Duration::from_millis(gap), &pulse.coins),
+			None => Err(String::from("'train_gap_ms' must be set for coin mode"))
+		}
+	}
+}
+
+fn test_outputs(config: &IntioConfig) -> Result<(), String> {
+	println!("\n[INTIO] Timed outputs test begin..");
+	let mut sched = OutputScheduler::new();
+	let active = config.active_output.as_gpioval();
+	let inactive = config.active_output.inverse().as_gpioval();
+	let o1 = sched.add(open_output(&config.pin_o1, inactive)?, active)?;
+	let o2 = sched.add(open_output(&config.pin_o2, inactive)?, active)?;
+	let ctl = utils::InController::new(&[
+		"p - pulse O1 for 500 ms",
+		"b - blink O2 5 times",
+		"w - PWM on O1, next duty cycle on each press",
+		"o - turn all outputs off"
+	]);
+	let mut duty = 0;
+	loop {
+		match ctl.get() {
+			'p' => sched.pulse(o1, TEST_PULSE_DUR)?,
+			'b' => sched.blink(o2, TEST_BLINK_DUR, TEST_BLINK_DUR, Some(TEST_BLINK_COUNT))?,
+			'w' => {
+				println!("\tPWM duty {}%", TEST_PWM_DUTIES[duty] * 100.0);
+				sched.pwm(o1, TEST_PWM_PERIOD, TEST_PWM_DUTIES[duty])?;
+				duty = (duty + 1) % TEST_PWM_DUTIES.len();
+			},
+			'o' => {
+				sched.set(o1, false)?;
+				sched.set(o2, false)?;
+			},
+			'q' => break,
+			_ => println!("Unknown command")
+		}
+	}
+	Ok(())
+}
+
+/// Wait until input reaches `level`, returns last read value
+fn wait_input(input: &mut dyn InputPin, level: GpioValue, timeout: Duration) -> Result<GpioValue, String> {
+	let start = Instant::now();
+	loop {
+		let val = input.read().map_err(|e| format!("Fail to read input: {}", e))?;
+		if val == level || start.elapsed() >= timeout {
+			return Ok(val);
+		}
+		thread::sleep(LOOPBACK_POLL_DELAY);
+	}
+}
+
+fn read_inputs(inputs: &mut [Box<dyn InputPin>]) -> Result<Vec<GpioValue>, String> {
+	inputs.iter_mut()
+		.map(|i| i.read().map_err(|e| format!("Fail to read input: {}", e)))
+		.collect()
+}
+
+/// Levels of loopback outputs and inputs
+struct LoopbackLevels {
+	out_on: GpioValue,
+	out_off: GpioValue,
+	in_on: GpioValue,
+	in_off: GpioValue
+}
+
+/// Drive every loopback output through both levels and check paired inputs
+pub fn loopback(config: &IntioConfig, lb: &LoopbackConfig) -> Result<Vec<LoopbackResult>, String> {
+	let levels = LoopbackLevels {
+		out_on: config.active_output.as_gpioval(),
+		out_off: config.active_output.inverse().as_gpioval(),
+		in_on: config.active_input.as_gpioval(),
+		in_off: config.active_input.inverse().as_gpioval()
+	};
+	let mut outputs = Vec::new();
+	let mut inputs = Vec::new();
+	for pair in &lb.pairs {
+		outputs.push(open_output(&pair.output, levels.out_off)?);
+		inputs.push(open_input(&pair.input)?);
+	}
+	let names: Vec<(String, String)> = lb.pairs.iter()
+		.map(|p| (p.output.to_string(), p.input.to_string()))
+		.collect();
+	check_pairs(&mut outputs, &mut inputs, &names, &levels, Duration::from_millis(lb.timeout_ms))
+}
+
+/// Pair faults: input which does not follow its output is stuck,
+/// input of other pair which follows the output is crossed
+fn check_pairs(outputs: &mut [Box<dyn OutputPin>], inputs: &mut [Box<dyn InputPin>], names: &[(String, String)],
+	levels: &LoopbackLevels, timeout: Duration) -> Result<Vec<LoopbackResult>, String> {
+	thread::sleep(LOOPBACK_SETTLE_DELAY);
+	let mut results = Vec::new();
+	for (i, (output, input)) in names.iter().enumerate() {
+		let mut faults = Vec::new();
+		let idle = read_inputs(inputs)?;
+
+		outputs[i].write(levels.out_on).map_err(|e| format!("Fail to set output {}: {}", output, e))?;
+		let val = wait_input(inputs[i].as_mut(), levels.in_on, timeout)?;
+		if val != levels.in_on {
+			faults.push(LoopbackFault::StuckAt(val));
+		}
+		thread::sleep(LOOPBACK_SETTLE_DELAY);
+		let active = read_inputs(inputs)?;
+		for (j, (_, other)) in names.iter().enumerate() {
+			if j != i && idle[j] == levels.in_off && active[j] == levels.in_on {
+				faults.push(LoopbackFault::Crossed(other.clone()));
+			}
+		}
+
+		outputs[i].write(levels.out_off).map_err(|e| format!("Fail to set output {}: {}", output, e))?;
+		let val = wait_input(inputs[i].as_mut(), levels.in_off, timeout)?;
+		if val != levels.in_off {
+			faults.push(LoopbackFault::StuckAt(val));
+		}
+		thread::sleep(LOOPBACK_SETTLE_DELAY);
+
+		results.push(LoopbackResult {
+			output: output.clone(),
+			input: input.clone(),
+			faults
+		});
+	}
+	Ok(results)
+}
+
+fn test_loopback(config: &IntioConfig) -> Result<(), String> {
+	println!("\n[INTIO] Loopback test begin..");
+	let lb = match &config.loopback {
+		Some(lb) => lb,
+		None => return Err(String::from("Loopback pairs are not configured"))
+	};
+	let results = loopback(config, lb)?;
+	let mut failed = 0;
+	for res in &results {
+		if res.passed() {
+			println!("\t{} -> {}: OK", res.output, res.input);
+		} else {
+			failed += 1;
+			let faults: Vec<String> = res.faults.iter().map(|f| f.to_string()).collect();
+			println!("\t{} -> {}: FAIL ({})", res.output, res.input, faults.join(", "));
+		}
+	}
+	if failed > 0 {
+		Err(format!("{} of {} loopback pairs failed", failed, results.len()))
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gpiomock::{self, MockInput, MockOutput};
+
+	#[test]
+	fn loopback_faults_are_classified() {
+		let low = || gpiomock::net(GpioValue::Low);
+		let (n0, n1, stuck, n2) = (low(), low(), low(), low());
+		let mut outputs: Vec<Box<dyn OutputPin>> = vec![
+			Box::new(MockOutput(vec![n0.clone()])),
+			// broken wire, input 1 is left at idle level
+			Box::new(MockOutput(vec![n1])),
+			// short to pair 0
+			Box::new(MockOutput(vec![n2.clone(), n0.clone()]))
+		];
+		let mut inputs: Vec<Box<dyn InputPin>> = vec![
+			Box::new(MockInput(n0)),
+			Box::new(MockInput(stuck)),
+			Box::new(MockInput(n2))
+		];
+		let names: Vec<(String, String)> = (0..3).map(|i| (format!("o{}", i), format!("i{}", i))).collect();
+		let levels = LoopbackLevels {
+			out_on: GpioValue::High,
+			out_off: GpioValue::Low,
+			in_on: GpioValue::High,
+			in_off: GpioValue::Low
+		};
+		let res = check_pairs(&mut outputs, &mut inputs, &names, &levels, Duration::from_millis(5)).unwrap();
+		assert!(res[0].passed());
+		assert_eq!(res[1].faults, vec![LoopbackFault::StuckAt(GpioValue::Low)]);
+		assert_eq!(res[2].faults, vec![LoopbackFault::Crossed(String::from("i0"))]);
+	}
+}
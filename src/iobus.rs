@@ -1,21 +1,35 @@
-use std::{thread, time::Duration};
+use std::{thread, time::{Duration, Instant}};
 
-use i2cdev::{
-	linux::{LinuxI2CBus, LinuxI2CMessage},
-	core::I2CMessage,
-	core::I2CTransfer
-};
+use gpio::GpioValue;
 use serde::Deserialize;
 
 use crate::utils;
 use crate::intio::PinLevel;
+use crate::expander::{Chip, Expander};
+use crate::i2cbus::{self, BusMode, I2cBus};
+use crate::i2cscan::{self, Known};
+use crate::gpioline::{self, EdgeInput, InputPin, PinConfig};
+use crate::sensor::{Reading, Sensor, SensorConfig};
+use crate::idstore::IdStoreConfig;
 
 const CYCLE_DELAY: Duration = Duration::from_millis(2);
-const DELAY_AFTER_TRANSFER: Duration = Duration::from_millis(10);
+const SENSOR_PRINT_PERIOD: Duration = Duration::from_secs(1);
+/// INT outputs of expanders are open-drain, active low
+const INT_ACTIVE: GpioValue = GpioValue::Low;
 
-#[derive(Deserialize, Debug)]
-enum Chip {
-	PCF8574
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+	Input,
+	Output
+}
+
+/// Named pin of expander, `active` overrides level of device
+#[derive(Deserialize)]
+struct ChannelConfig {
+	name: String,
+	pin: u8,
+	dir: Direction,
+	active: Option<PinLevel>
 }
 
 #[derive(Deserialize)]
@@ -24,118 +38,710 @@ struct IoBusDevice {
 	addr: u8,
 	active_input: PinLevel,
 	active_output: PinLevel,
-	input_mask: u8,
-	output_mask: u8
+	#[serde(default)]
+	input_mask: u16,
+	#[serde(default)]
+	output_mask: u16,
+	#[serde(default)]
+	channels: Vec<ChannelConfig>,
+	/// Host GPIO wired to INT output of the device
+	int_pin: Option<PinConfig>
 }
 
 #[derive(Deserialize)]
 pub struct IobusConfig {
 	driver: String,
-	devices: Vec<IoBusDevice>
+	/// `Smbus` for adapters without plain I2C transfers, e.g. `i2c-stub`
+	#[serde(default)]
+	mode: BusMode,
+	/// Host GPIO shared by INT outputs of devices without own `int_pin`
+	int_pin: Option<PinConfig>,
+	devices: Vec<IoBusDevice>,
+	#[serde(default)]
+	sensors: Vec<SensorConfig>,
+	pub eeprom: Option<IdStoreConfig>
 }
 
-fn init_pcf8574(bus: &mut LinuxI2CBus, addr: u8, input_mask: u8) -> Result<(), String> {
-	let mut msgs = [
-		LinuxI2CMessage::write(&[input_mask]).with_address(addr as u16)
-	];
-	match bus.transfer(&mut msgs) {
-		Ok(_) => {
-			thread::sleep(DELAY_AFTER_TRANSFER);
-			Ok(())
-		},
-		Err(e) => Err(format!("Fail write to dev: {}", e.to_string()))
+impl IobusConfig {
+	pub fn open_bus(&self) -> Result<Box<dyn I2cBus>, String> {
+		i2cbus::open(&self.driver, self.mode)
 	}
 }
 
-fn init(bus: &mut LinuxI2CBus, dev: &IoBusDevice) -> Result<(), String> {
-	match dev.chip {
-		Chip::PCF8574 => init_pcf8574(bus, dev.addr, dev.input_mask)
-	}
+pub struct Channel {
+	pub name: String,
+	pub dev: usize,
+	pub pin: u8,
+	pub dir: Direction,
+	pub active: PinLevel
+}
+
+struct Device {
+	chip: Chip,
+	addr: u8,
+	drv: Box<dyn Expander>,
+	input_mask: u16,
+	/// Last written port value
+	out_state: u16,
+	/// Last read input pins
+	in_state: u16
+}
+
+/// Host GPIO wired to INT outputs
+enum IntPin {
+	/// Character device line, falling edges are queued by the kernel
+	Edges(Box<dyn EdgeInput>),
+	/// Sysfs pin, read by level
+	Level(Box<dyn InputPin>)
 }
 
-fn write_pcf8574(bus: &mut LinuxI2CBus, addr: u8, active_out: &PinLevel, output_mask: u8, input_mask: u8, val: bool) -> Result<(), String> {
-	let mut buf = if val {
-		match active_out {
-			PinLevel::High => output_mask,
-			PinLevel::Low => !output_mask
+impl IntPin {
+	/// INT went active since last call or is still active
+	fn fired(&mut self) -> Result<bool, std::io::Error> {
+		match self {
+			Self::Edges(pin) => Ok(pin.take_falling()? || pin.read()? == INT_ACTIVE),
+			Self::Level(pin) => Ok(pin.read()? == INT_ACTIVE)
 		}
-	} else {
-		match active_out {
-			PinLevel::High => !output_mask,
-			PinLevel::Low => output_mask
-		}
-	};
-	buf = buf | input_mask;
-	let mut msgs = [
-		LinuxI2CMessage::write(&[buf]).with_address(addr as u16)
-	];
-	match bus.transfer(&mut msgs) {
-		Ok(_) => Ok(()),
-		Err(e) => Err(format!("Fail read from dev: {}", e.to_string()))
-	}
-}
-
-fn read_pcf8574(bus: &mut LinuxI2CBus, addr: u8, active_in: &PinLevel, input_mask: u8) -> Result<bool, String> {
-	let mut buf: [u8;1] = [0;1];
-	let mut msgs = [
-		LinuxI2CMessage::read(&mut buf).with_address(addr as u16)
-	];
-	match bus.transfer(&mut msgs) {
-		Ok(_) => {
-			thread::sleep(DELAY_AFTER_TRANSFER);
-			let but_is_high = (buf[0] & input_mask) > 0;
-			match active_in {
-				PinLevel::High => Ok(but_is_high),
-				PinLevel::Low => Ok(!but_is_high)
-			}
-		},
-		Err(e) => Err(format!("Fail read from dev: {}", e.to_string()))
 	}
 }
 
-fn write_dev(bus: &mut LinuxI2CBus, dev: &IoBusDevice, val: bool) -> Result<(), String> {
-	match dev.chip {
-		Chip::PCF8574 => write_pcf8574(bus, dev.addr, &dev.active_output, dev.output_mask, dev.input_mask, val)
+/// Host GPIO with INT outputs of `devices`
+struct IntLine {
+	pin: IntPin,
+	devices: Vec<usize>
+}
+
+/// Change of input pins of device
+pub struct ChangeEvent {
+	pub time: Instant,
+	pub addr: u8,
+	/// Mask of changed input pins
+	pub changed: u16,
+	pub port: u16
+}
+
+/// Expanders on I2C bus with pins mapped to named channels
+pub struct IoBus {
+	bus: Box<dyn I2cBus>,
+	devices: Vec<Device>,
+	channels: Vec<Channel>,
+	int_lines: Vec<IntLine>,
+	/// Devices without INT line, polled on every `poll_changes`
+	polled: Vec<usize>,
+	sensors: Vec<Box<dyn Sensor>>
+}
+
+fn level_bit(active: PinLevel, on: bool) -> bool {
+	match active {
+		PinLevel::High => on,
+		PinLevel::Low => !on
 	}
 }
 
-fn read_dev(bus: &mut LinuxI2CBus, dev: &IoBusDevice) -> Result<bool, String> {
-	match dev.chip {
-		Chip::PCF8574 => read_pcf8574(bus, dev.addr, &dev.active_input, dev.input_mask)
+/// Channels of device, masks of legacy config are mapped to channels `<chip>@<addr>:<pin>`
+fn device_channels(dev: &IoBusDevice, idx: usize) -> Result<Vec<Channel>, String> {
+	let mut channels = Vec::new();
+	for pin in 0..16u8 {
+		let bit = 1u16 << pin;
+		let dir = if dev.input_mask & bit != 0 {
+			Direction::Input
+		} else if dev.output_mask & bit != 0 {
+			Direction::Output
+		} else {
+			continue;
+		};
+		channels.push(Channel {
+			name: format!("{:?}@{}:{}", dev.chip, dev.addr, pin),
+			dev: idx,
+			pin,
+			dir,
+			active: if dir == Direction::Input { dev.active_input } else { dev.active_output }
+		});
 	}
+	for ch in &dev.channels {
+		if ch.pin >= 16 {
+			return Err(format!("Channel '{}': pin {} out of range", ch.name, ch.pin));
+		}
+		channels.push(Channel {
+			name: ch.name.clone(),
+			dev: idx,
+			pin: ch.pin,
+			dir: ch.dir,
+			active: ch.active.unwrap_or(if ch.dir == Direction::Input { dev.active_input } else { dev.active_output })
+		});
+	}
+	Ok(channels)
 }
 
-pub fn test(config: &IobusConfig) -> Result<(), String> {
-	println!("\n[IOBUS] Test begin..");
-	let mut bus = LinuxI2CBus::new(&config.driver).unwrap();
-	for dev in &config.devices {
-		match init(&mut bus, dev) {
-			Err(e) => {
-				println!("Fail init device {:?} at addr {}: {}", dev.chip, dev.addr, e);
-				return Err(format!("Device {:?} at addr {} not found", dev.chip, dev.addr));
+impl IoBus {
+	/// Open bus and init all devices, outputs are set inactive
+	pub fn open(config: &IobusConfig) -> Result<Self, String> {
+		// SMBus receive byte is a transfer on its own, PCF8575 sends low port byte in each
+		if let (BusMode::Smbus, Some(dev)) = (config.mode, config.devices.iter().find(|d| d.chip == Chip::PCF8575)) {
+			return Err(format!("PCF8575 at addr {} needs two byte read, use mode \"I2c\"", dev.addr));
+		}
+		Self::with_bus(config, config.open_bus()?)
+	}
+
+	/// Init all devices on already opened `bus`
+	pub fn with_bus(config: &IobusConfig, bus: Box<dyn I2cBus>) -> Result<Self, String> {
+		let mut iobus = Self {
+			bus,
+			devices: Vec::new(),
+			channels: Vec::new(),
+			int_lines: Vec::new(),
+			polled: Vec::new(),
+			sensors: Vec::new()
+		};
+		for (idx, dev) in config.devices.iter().enumerate() {
+			for ch in device_channels(dev, idx)? {
+				if iobus.channels.iter().any(|c| c.name == ch.name) {
+					return Err(format!("Channel '{}' is defined twice", ch.name));
+				}
+				iobus.channels.push(ch);
+			}
+			iobus.devices.push(Device {
+				chip: dev.chip,
+				addr: dev.addr,
+				drv: dev.chip.driver(),
+				input_mask: 0,
+				out_state: 0,
+				in_state: 0
+			});
+		}
+		for idx in 0..iobus.devices.len() {
+			iobus.init_device(idx)?;
+		}
+		let mut shared = Vec::new();
+		for (idx, dev) in config.devices.iter().enumerate() {
+			match &dev.int_pin {
+				Some(pin) => iobus.add_int_line(pin, vec![idx])?,
+				None if config.int_pin.is_some() => shared.push(idx),
+				None => iobus.polled.push(idx)
+			}
+		}
+		if let Some(pin) = &config.int_pin {
+			iobus.add_int_line(pin, shared)?;
+		}
+		for cfg in &config.sensors {
+			let mut sensor = cfg.driver()?;
+			if let Err(e) = sensor.init(iobus.bus.as_mut()) {
+				return Err(format!("Sensor '{}' {:?} at addr {} not found: {}", cfg.name, cfg.chip, cfg.addr, e));
+			}
+			iobus.sensors.push(sensor);
+		}
+		Ok(iobus)
+	}
+
+	/// Read all sensors
+	pub fn read_sensors(&mut self) -> Result<Vec<Reading>, String> {
+		let mut readings = Vec::new();
+		for sensor in self.sensors.iter_mut() {
+			readings.append(&mut sensor.read(self.bus.as_mut())?);
+		}
+		Ok(readings)
+	}
+
+	fn add_int_line(&mut self, cfg: &PinConfig, devices: Vec<usize>) -> Result<(), String> {
+		let pin = match cfg {
+			PinConfig::Cdev(line) => gpioline::open_falling_edges(line).map(IntPin::Edges),
+			PinConfig::Sysfs(_) => gpioline::open_input(cfg).map(IntPin::Level)
+		};
+		match pin {
+			Ok(pin) => self.attach_int_line(pin, devices),
+			Err(e) => Err(format!("Fail to open INT pin {}: {}", cfg, e))
+		}
+	}
+
+	/// Interrupts are enabled after pin is opened, so first edge is not missed
+	fn attach_int_line(&mut self, pin: IntPin, devices: Vec<usize>) -> Result<(), String> {
+		for idx in &devices {
+			let dev = &mut self.devices[*idx];
+			dev.drv.enable_interrupt(self.bus.as_mut(), dev.addr, dev.input_mask)?;
+		}
+		self.int_lines.push(IntLine { pin, devices });
+		Ok(())
+	}
+
+	fn init_device(&mut self, idx: usize) -> Result<(), String> {
+		let mut input_mask = 0;
+		let mut out_state = 0;
+		let mut used = 0;
+		for ch in self.channels.iter().filter(|c| c.dev == idx) {
+			let bit = 1u16 << ch.pin;
+			used |= bit;
+			match ch.dir {
+				Direction::Input => input_mask |= bit,
+				Direction::Output => if level_bit(ch.active, false) {
+					out_state |= bit;
+				}
+			}
+		}
+		let dev = &mut self.devices[idx];
+		if mask_exceeds(used, dev.drv.width()) {
+			return Err(format!("Pins exceed {} pins of {:?} at addr {}", dev.drv.width(), dev.chip, dev.addr));
+		}
+		dev.input_mask = input_mask;
+		dev.out_state = out_state | input_mask;
+		if let Err(e) = dev.drv.init(self.bus.as_mut(), dev.addr, input_mask, dev.out_state) {
+			return Err(format!("Device {:?} at addr {} not found: {}", dev.chip, dev.addr, e));
+		}
+		dev.in_state = dev.drv.read_port(self.bus.as_mut(), dev.addr)? & input_mask;
+		Ok(())
+	}
+
+	/// Read ports of device and push event if inputs changed
+	fn update_device(&mut self, idx: usize, events: &mut Vec<ChangeEvent>) -> Result<(), String> {
+		let time = Instant::now();
+		let dev = &mut self.devices[idx];
+		let mut ports = Vec::new();
+		if let Some(captured) = dev.drv.read_captured(self.bus.as_mut(), dev.addr)? {
+			ports.push(captured);
+		}
+		ports.push(dev.drv.read_port(self.bus.as_mut(), dev.addr)?);
+		for port in ports {
+			let changed = (port & dev.input_mask) ^ dev.in_state;
+			if changed != 0 {
+				dev.in_state = port & dev.input_mask;
+				events.push(ChangeEvent { time, addr: dev.addr, changed, port });
+			}
+		}
+		Ok(())
+	}
+
+	/// Input changes since last call. Devices with INT line are read only when it fired.
+	/// Character device INT line queues falling edges, so INT released before this call
+	/// still gets device read. Sysfs INT is read by level and such short pulse is lost.
+	pub fn poll_changes(&mut self) -> Result<Vec<ChangeEvent>, String> {
+		let mut events = Vec::new();
+		let mut fired = Vec::new();
+		for line in self.int_lines.iter_mut() {
+			match line.pin.fired() {
+				Ok(true) => fired.extend(line.devices.iter().copied()),
+				Ok(false) => (),
+				Err(e) => return Err(format!("Fail to read INT pin: {}", e))
+			}
+		}
+		fired.extend(self.polled.iter().copied());
+		for idx in fired {
+			self.update_device(idx, &mut events)?;
+		}
+		Ok(events)
+	}
+
+	/// Sleep up to `timeout`, wakes on INT edge of character device lines
+	pub fn wait_interrupt(&mut self, timeout: Duration) -> Result<(), String> {
+		let pins: Vec<&dyn EdgeInput> = self.int_lines.iter().filter_map(|l| match &l.pin {
+			IntPin::Edges(pin) => Some(pin.as_ref()),
+			IntPin::Level(_) => None
+		}).collect();
+		if pins.is_empty() {
+			thread::sleep(timeout);
+			return Ok(());
+		}
+		gpioline::wait_falling(&pins, timeout).map_err(|e| format!("Fail to wait INT pin: {}", e))
+	}
+
+	/// State of input channel from last `poll_changes`
+	pub fn input_state(&self, ch: &Channel) -> bool {
+		level_bit(ch.active, self.devices[ch.dev].in_state & (1 << ch.pin) != 0)
+	}
+
+	pub fn channels(&self) -> &[Channel] {
+		&self.channels
+	}
+
+	fn channel(&self, name: &str) -> Result<usize, String> {
+		match self.channels.iter().position(|c| c.name == name) {
+			Some(idx) => Ok(idx),
+			None => Err(format!("Unknown channel '{}'", name))
+		}
+	}
+
+	fn device(&self, addr: u8) -> Result<usize, String> {
+		match self.devices.iter().position(|d| d.addr == addr) {
+			Some(idx) => Ok(idx),
+			None => Err(format!("No device at addr {}", addr))
+		}
+	}
+
+	/// Raw port value of device at `addr`
+	pub fn read_port(&mut self, addr: u8) -> Result<u16, String> {
+		let idx = self.device(addr)?;
+		let dev = &mut self.devices[idx];
+		dev.drv.read_port(self.bus.as_mut(), dev.addr)
+	}
+
+	/// State of channel, for outputs it is the last written state
+	pub fn read(&mut self, name: &str) -> Result<bool, String> {
+		let ch = &self.channels[self.channel(name)?];
+		let (dev, pin, dir, active) = (ch.dev, ch.pin, ch.dir, ch.active);
+		let port = match dir {
+			Direction::Input => {
+				let dev = &mut self.devices[dev];
+				dev.drv.read_port(self.bus.as_mut(), dev.addr)?
 			},
-			_ => println!("Init device {:?} at addr {}: OK", dev.chip, dev.addr)
+			Direction::Output => self.devices[dev].out_state
+		};
+		Ok(level_bit(active, port & (1 << pin) != 0))
+	}
+
+	/// Set output channel, other pins of port keep cached state
+	pub fn write(&mut self, name: &str, on: bool) -> Result<(), String> {
+		let ch = &self.channels[self.channel(name)?];
+		if ch.dir != Direction::Output {
+			return Err(format!("Channel '{}' is not an output", name));
+		}
+		let bit = 1u16 << ch.pin;
+		let high = level_bit(ch.active, on);
+		let dev = &mut self.devices[ch.dev];
+		let val = if high { dev.out_state | bit } else { dev.out_state & !bit };
+		if val != dev.out_state {
+			dev.out_state = val;
+			dev.drv.write_port(self.bus.as_mut(), dev.addr, val)?;
 		}
+		Ok(())
+	}
+}
+
+fn mask_exceeds(mask: u16, width: u8) -> bool {
+	(mask as u32) >> width != 0
+}
+
+pub fn test(config: &IobusConfig) -> Result<(), String> {
+	println!("\n[IOBUS] Test begin..");
+	let mut iobus = IoBus::open(config)?;
+	let devices: Vec<(Chip, u8)> = iobus.devices.iter().map(|d| (d.chip, d.addr)).collect();
+	for (chip, addr) in devices {
+		println!("Init device {:?} at addr {}: OK, port 0x{:04X}", chip, addr, iobus.read_port(addr)?);
+	}
+	let names: Vec<String> = iobus.channels().iter().map(|c| c.name.clone()).collect();
+	for name in names {
+		println!("\tChannel '{}': {}", name, if iobus.read(&name)? { "on" } else { "off" });
+	}
+	println!("\tPush button at IO module, all outputs of module should be shorted when pushed..");
+	if !config.sensors.is_empty() {
+		println!("\tSensor readings are printed every second..");
 	}
-	println!("\tPush button at IO module, all outputs should be shorted when pushed..");
 	let exiter = utils::Exiter::new();
+	let start = Instant::now();
+	let mut tl_sensors = Instant::now();
 	loop {
-		for dev in &config.devices {
-			match read_dev(&mut bus, dev) {
-				Ok(state) => {
-					match write_dev(&mut bus, dev, state) {
-						Err(e) => return Err(format!("Fail performing dev {:?} at {}: {}", dev.chip, dev.addr, e)),
-						_ => ()
-					}
-				},
-				Err(e) => return Err(format!("Fail performing dev {:?} at {}: {}", dev.chip, dev.addr, e))
+		if !config.sensors.is_empty() && tl_sensors.elapsed() >= SENSOR_PRINT_PERIOD {
+			tl_sensors = Instant::now();
+			let readings: Vec<String> = iobus.read_sensors()?.iter()
+				.map(|r| format!("{} = {:.2} {}", r.name, r.value, r.unit))
+				.collect();
+			println!("\t{}", readings.join(", "));
+		}
+		for ev in iobus.poll_changes()? {
+			let dev = match iobus.devices.iter().position(|d| d.addr == ev.addr) {
+				Some(dev) => dev,
+				None => continue
+			};
+			println!("\t[{:>8} ms] addr {}: changed 0x{:04X}, port 0x{:04X}",
+				ev.time.duration_since(start).as_millis(), ev.addr, ev.changed, ev.port);
+			let pushed = iobus.channels().iter()
+				.filter(|c| c.dev == dev && c.dir == Direction::Input)
+				.any(|c| iobus.input_state(c));
+			let outputs: Vec<String> = iobus.channels().iter()
+				.filter(|c| c.dev == dev && c.dir == Direction::Output)
+				.map(|c| c.name.clone())
+				.collect();
+			for name in outputs {
+				iobus.write(&name, pushed)?;
 			}
-		}		
+		}
 		if exiter.check() {
 			break;
 		}
-		thread::sleep(CYCLE_DELAY);
+		iobus.wait_interrupt(CYCLE_DELAY)?;
 	}
 
 	Ok(())
-}
\ No newline at end of file
+}
+
+/// Probe all addresses of bus and compare found devices with config
+pub fn scan(config: &IobusConfig, probe_registers: bool) -> Result<(), String> {
+	println!("\n[IOBUS] Scan begin..");
+	let mut bus = config.open_bus()?;
+	let present: Vec<u8> = (i2cscan::ADDR_FIRST..=i2cscan::ADDR_LAST)
+		.filter(|addr| i2cscan::probe(bus.as_mut(), *addr))
+		.collect();
+	i2cscan::print_map(&present);
+
+	let mut diff = 0;
+	for dev in &config.devices {
+		let expected = Known::from_chip(dev.chip, dev.addr);
+		if !(i2cscan::ADDR_FIRST..=i2cscan::ADDR_LAST).contains(&dev.addr) {
+			diff += 1;
+			println!("\tINVALID  {:?} at addr {}: not a 7-bit device address", dev.chip, dev.addr);
+		} else if !present.contains(&dev.addr) {
+			diff += 1;
+			println!("\tMISSING  {:?} at 0x{:02x} ({})", dev.chip, dev.addr, dev.addr);
+		} else {
+			let found = i2cscan::identify(bus.as_mut(), dev.addr, Some(expected), probe_registers);
+			match found.identified {
+				Some(known) if known != expected => {
+					diff += 1;
+					println!("\tMISMATCH {:?} at 0x{:02x} ({}): found {:?}", dev.chip, dev.addr, dev.addr, known);
+				},
+				Some(_) => println!("\tOK       {:?} at 0x{:02x} ({}): signature matched", dev.chip, dev.addr, dev.addr),
+				None if found.candidates.contains(&expected) => println!("\tOK       {:?} at 0x{:02x} ({})", dev.chip, dev.addr, dev.addr),
+				None => {
+					diff += 1;
+					println!("\tMISMATCH {:?} at 0x{:02x} ({}): chip is not expected at this addr", dev.chip, dev.addr, dev.addr);
+				}
+			}
+		}
+	}
+	for sensor in &config.sensors {
+		let expected = Known::from_sensor(sensor.chip);
+		if !present.contains(&sensor.addr) {
+			diff += 1;
+			println!("\tMISSING  {:?} '{}' at 0x{:02x} ({})", sensor.chip, sensor.name, sensor.addr, sensor.addr);
+			continue;
+		}
+		match i2cscan::identify(bus.as_mut(), sensor.addr, Some(expected), probe_registers).identified {
+			Some(known) if known != expected => {
+				diff += 1;
+				println!("\tMISMATCH {:?} '{}' at 0x{:02x} ({}): found {:?}", sensor.chip, sensor.name, sensor.addr, sensor.addr, known);
+			},
+			Some(_) => println!("\tOK       {:?} '{}' at 0x{:02x} ({}): signature matched", sensor.chip, sensor.name, sensor.addr, sensor.addr),
+			None => println!("\tOK       {:?} '{}' at 0x{:02x} ({})", sensor.chip, sensor.name, sensor.addr, sensor.addr)
+		}
+	}
+	let configured = |addr: u8| config.devices.iter().any(|d| d.addr == addr) || config.sensors.iter().any(|s| s.addr == addr);
+	for addr in present.iter().filter(|a| !configured(**a)) {
+		let found = i2cscan::identify(bus.as_mut(), *addr, None, probe_registers);
+		match found.identified {
+			Some(known) => println!("\tEXTRA    0x{:02x} ({}): {:?}", addr, addr, known),
+			None if found.candidates.is_empty() => println!("\tEXTRA    0x{:02x} ({}): unknown device", addr, addr),
+			None => println!("\tEXTRA    0x{:02x} ({}): one of {:?}", addr, addr, found.candidates)
+		}
+	}
+	if diff > 0 {
+		Err(format!("{} configured devices are not found as expected", diff))
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::i2cmock::{MockBus, Mcp23017, Pcf857x};
+
+	fn config(text: &str) -> IobusConfig {
+		toml::from_str(text).unwrap()
+	}
+
+	fn pcf_bus(addr: u8) -> (Box<MockBus>, Arc<Mutex<Pcf857x>>) {
+		let pcf = Arc::new(Mutex::new(Pcf857x::new(8)));
+		let mut bus = MockBus::new();
+		bus.attach(addr, &pcf);
+		(Box::new(bus), pcf)
+	}
+
+	fn mcp_bus(addr: u8) -> (Box<MockBus>, Arc<Mutex<Mcp23017>>) {
+		let mcp = Arc::new(Mutex::new(Mcp23017::default()));
+		let mut bus = MockBus::new();
+		bus.attach(addr, &mcp);
+		(Box::new(bus), mcp)
+	}
+
+	const PCF_MASKS: &str = r#"
+		driver = "mock"
+		[[devices]]
+		chip = "PCF8574"
+		addr = 32
+		active_input = "Low"
+		active_output = "High"
+		input_mask = 0x0F
+		output_mask = 0xF0
+	"#;
+
+	#[test]
+	fn pcf_masks_set_inputs_high_and_outputs_inactive() {
+		let (bus, pcf) = pcf_bus(32);
+		let iobus = IoBus::with_bus(&config(PCF_MASKS), bus).unwrap();
+		assert_eq!(pcf.lock().unwrap().latch & 0xFF, 0x0F);
+		assert_eq!(iobus.channels().len(), 8);
+		assert_eq!(iobus.channels()[0].name, "PCF8574@32:0");
+	}
+
+	#[test]
+	fn pcf_output_keeps_inputs_high() {
+		let (bus, pcf) = pcf_bus(32);
+		let mut iobus = IoBus::with_bus(&config(PCF_MASKS), bus).unwrap();
+		iobus.write("PCF8574@32:5", true).unwrap();
+		assert_eq!(pcf.lock().unwrap().latch & 0xFF, 0x2F);
+		// input pins can not be driven low
+		assert!(iobus.write("PCF8574@32:0", false).is_err());
+		iobus.write("PCF8574@32:5", false).unwrap();
+		assert_eq!(pcf.lock().unwrap().latch & 0xFF, 0x0F);
+		assert!(!iobus.read("PCF8574@32:5").unwrap());
+	}
+
+	#[test]
+	fn pcf_active_low_input_follows_pulled_pin() {
+		let (bus, pcf) = pcf_bus(32);
+		let mut iobus = IoBus::with_bus(&config(PCF_MASKS), bus).unwrap();
+		assert!(!iobus.read("PCF8574@32:2").unwrap());
+		pcf.lock().unwrap().pull_low(2, true);
+		assert!(iobus.read("PCF8574@32:2").unwrap());
+		let events = iobus.poll_changes().unwrap();
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].changed, 0x04);
+		assert!(iobus.poll_changes().unwrap().is_empty());
+	}
+
+	#[test]
+	fn unchanged_output_is_not_rewritten() {
+		let (bus, pcf) = pcf_bus(32);
+		let mut iobus = IoBus::with_bus(&config(PCF_MASKS), bus).unwrap();
+		let writes = pcf.lock().unwrap().writes;
+		iobus.write("PCF8574@32:4", false).unwrap();
+		assert_eq!(pcf.lock().unwrap().writes, writes);
+	}
+
+	#[test]
+	fn pcf8575_is_rejected_on_smbus() {
+		let text = PCF_MASKS.replace("PCF8574", "PCF8575").replace("driver = \"mock\"", "driver = \"mock\"\nmode = \"Smbus\"");
+		let err = IoBus::open(&config(&text)).err().unwrap();
+		assert!(err.contains("PCF8575"));
+	}
+
+	const MCP_CHANNELS: &str = r#"
+		driver = "mock"
+		[[devices]]
+		chip = "MCP23017"
+		addr = 33
+		active_input = "Low"
+		active_output = "High"
+		channels = [
+			{ name = "door", pin = 0, dir = "Input" },
+			{ name = "level", pin = 9, dir = "Input", active = "High" },
+			{ name = "pump", pin = 8, dir = "Output" },
+			{ name = "lamp", pin = 15, dir = "Output", active = "Low" },
+		]
+	"#;
+
+	#[test]
+	fn mcp_init_sets_direction_and_inactive_outputs() {
+		let (bus, mcp) = mcp_bus(33);
+		IoBus::with_bus(&config(MCP_CHANNELS), bus).unwrap();
+		let mcp = mcp.lock().unwrap();
+		assert_eq!(mcp.iodir(), 0x0201);
+		// active low lamp is off when high
+		assert_eq!(mcp.olat() & 0xFDFE, 0x8000);
+	}
+
+	#[test]
+	fn mcp_latch_is_set_before_direction() {
+		use crate::i2cmock::mcp23017::{IODIRA, OLATA};
+
+		let (bus, mcp) = mcp_bus(33);
+		IoBus::with_bus(&config(MCP_CHANNELS), bus).unwrap();
+		let log = &mcp.lock().unwrap().log;
+		let latch = log.iter().position(|(reg, _)| *reg == OLATA).unwrap();
+		let dir = log.iter().position(|(reg, _)| *reg == IODIRA).unwrap();
+		assert!(latch < dir);
+		// input bits are set, active low lamp starts high
+		assert_eq!(log[latch].1, vec![0x01, 0x82]);
+	}
+
+	#[test]
+	fn queued_int_edge_reads_device_after_int_is_released() {
+		use crate::gpiomock::{self, MockEdges};
+
+		let (bus, mcp) = mcp_bus(33);
+		let mut iobus = IoBus::with_bus(&config(MCP_CHANNELS), bus).unwrap();
+		iobus.polled.clear();
+		let net = gpiomock::net(GpioValue::High);
+		let falling = Arc::new(Mutex::new(0));
+		let pin = MockEdges { net: net.clone(), falling: falling.clone() };
+		iobus.attach_int_line(IntPin::Edges(Box::new(pin)), vec![0]).unwrap();
+		// short press: INT pulsed and released before poll, port is captured
+		mcp.lock().unwrap().set_input(0, false);
+		mcp.lock().unwrap().set_input(0, true);
+		assert!(iobus.poll_changes().unwrap().is_empty());
+		*falling.lock().unwrap() += 1;
+		let events = iobus.poll_changes().unwrap();
+		assert_eq!(events.len(), 2);
+		assert_eq!(events[0].changed, 0x0001);
+		assert!(iobus.poll_changes().unwrap().is_empty());
+		iobus.wait_interrupt(Duration::from_millis(1)).unwrap();
+	}
+
+	#[test]
+	fn mcp_channels_apply_active_level() {
+		let (bus, mcp) = mcp_bus(33);
+		let mut iobus = IoBus::with_bus(&config(MCP_CHANNELS), bus).unwrap();
+		iobus.write("pump", true).unwrap();
+		iobus.write("lamp", true).unwrap();
+		assert_eq!(mcp.lock().unwrap().olat() & 0xFDFE, 0x0100);
+		assert!(iobus.read("lamp").unwrap());
+
+		assert!(!iobus.read("door").unwrap());
+		assert!(iobus.read("level").unwrap());
+		mcp.lock().unwrap().set_input(0, false);
+		mcp.lock().unwrap().set_input(9, false);
+		assert!(iobus.read("door").unwrap());
+		assert!(!iobus.read("level").unwrap());
+		iobus.poll_changes().unwrap();
+		let ch = iobus.channels().iter().find(|c| c.name == "door").unwrap();
+		assert!(iobus.input_state(ch));
+	}
+
+	#[test]
+	fn write_to_input_or_unknown_channel_fails() {
+		let (bus, _mcp) = mcp_bus(33);
+		let mut iobus = IoBus::with_bus(&config(MCP_CHANNELS), bus).unwrap();
+		assert!(iobus.write("door", true).is_err());
+		assert!(iobus.write("fan", true).is_err());
+	}
+
+	#[test]
+	fn pin_beyond_chip_width_is_rejected() {
+		let (bus, _pcf) = pcf_bus(32);
+		let cfg = config(r#"
+			driver = "mock"
+			[[devices]]
+			chip = "PCF8574"
+			addr = 32
+			active_input = "Low"
+			active_output = "High"
+			channels = [{ name = "relay", pin = 9, dir = "Output" }]
+		"#);
+		assert!(IoBus::with_bus(&cfg, bus).is_err());
+	}
+
+	#[test]
+	fn missing_device_is_reported() {
+		let (bus, _pcf) = pcf_bus(32);
+		let cfg = config(&PCF_MASKS.replace("addr = 32", "addr = 39"));
+		assert!(IoBus::with_bus(&cfg, bus).is_err());
+	}
+
+	/// Same register checks on kernel `i2c-stub`, which is plain register memory:
+	/// `modprobe i2c-stub chip_addr=0x21`, then run ignored tests with
+	/// `IOBUS_STUB_BUS=/dev/i2c-N`
+	#[test]
+	#[ignore]
+	fn mcp_on_i2c_stub() {
+		let driver = std::env::var("IOBUS_STUB_BUS").expect("IOBUS_STUB_BUS is not set");
+		let text = MCP_CHANNELS.replace("driver = \"mock\"", &format!("driver = \"{}\"\nmode = \"Smbus\"", driver));
+		let mut iobus = IoBus::open(&config(&text)).unwrap();
+		iobus.write("pump", true).unwrap();
+		let mut olat = [0u8;2];
+		i2cbus::read_reg(iobus.bus.as_mut(), 33, 0x14, &mut olat).unwrap();
+		assert_eq!(u16::from_le_bytes(olat) & 0x8100, 0x8100);
+		// stub keeps written value, so GPIO register acts as driven pins
+		i2cbus::write(iobus.bus.as_mut(), 33, &[0x12, 0x00, 0x00]).unwrap();
+		assert!(iobus.read("door").unwrap());
+		assert!(!iobus.read("level").unwrap());
+	}
+}
@@ -1,55 +1,320 @@
-use std::{time::Duration, thread};
+use std::{time::{Duration, Instant}, thread};
+use std::sync::Arc;
+use std::io::Write;
 
 use serde::Deserialize;
 use serialport::SerialPort;
 use crate::utils;
+use crate::ledmatrix_diag;
+use crate::framebuffer::{Checksum, DrawHeader, Framebuffer, LEVEL_MAX};
+use crate::font::Font;
+use crate::text::{self, Align};
+use crate::scene::{FrameScheduler, Scene, SceneHandle};
+use crate::picture::{self, Dither, Fit};
+use crate::sink::{FrameSink, PngSink, TermSink};
+use crate::frameformat::{ChainLayout, ColorMode, FrameFormat};
 
-const DISPLAY_WIDTH: u32 = 64;
-const DISPLAY_HEIGHT: u32 = 32;
-const DRAW_DATA_SIZE: usize = (DISPLAY_HEIGHT*DISPLAY_WIDTH/2 + 2) as usize; 
+/// Baud rates in bits per second, break is a zero byte sent at low speed
+const DEFAULT_DATA_SPEED: u32 = 500000;
+const DEFAULT_BREAK_SPEED: u32 = 1200;
+const DEFAULT_BREAK_DELAY_MS: u64 = 10;
 
-const TTY_DATA_SPEED: u32 = termios::os::linux::B500000;
-const TTY_BREAK_SPEED: u32 = termios::os::linux::B1200;
-const BREAK_DELAY: Duration = Duration::from_millis(10);
+const TERM_DRIVER: &str = "term";
+const PNG_DRIVER_PREFIX: &str = "png:";
+/// Frame of one 64x32 panel takes about 30 ms: break, then 1026 bytes at 500000 baud
+const DEFAULT_FRAME_RATE: u32 = 25;
+const DEMO_SCENE_TIME: Duration = Duration::from_secs(8);
+const STATS_PERIOD: Duration = Duration::from_secs(1);
 
 #[derive(Deserialize)]
 pub struct LedmatrixConfig {
 	driver: String,
-	fill_delay: u32 
+	fill_delay: u32,
+	/// Frames per second of render loop
+	#[serde(default = "default_frame_rate")]
+	pub frame_rate: u32,
+	/// Size of one panel
+	#[serde(default = "default_width")]
+	width: u32,
+	#[serde(default = "default_height")]
+	height: u32,
+	/// Chained panels and rows they are laid out in
+	#[serde(default = "default_one")]
+	chain: u32,
+	#[serde(default = "default_one")]
+	chain_rows: u32,
+	#[serde(default)]
+	layout: ChainLayout,
+	#[serde(default)]
+	rotation: u32,
+	#[serde(default = "default_bpp")]
+	bpp: u8,
+	#[serde(default)]
+	color: ColorMode,
+	#[serde(default = "default_data_speed")]
+	data_speed: u32,
+	#[serde(default = "default_break_speed")]
+	break_speed: u32,
+	#[serde(default = "default_break_delay_ms")]
+	break_delay_ms: u64,
+	/// Draw data header, assumed protocol: see `DrawHeader`
+	#[serde(default = "default_header_mark")]
+	header_mark: u8,
+	#[serde(default)]
+	checksum: Checksum
 }
 
-/// Send encoded draw data to display
-fn send_draw_data(tty: &mut Box<dyn SerialPort>, buf: &[u8]) {
-    let break_byte: [u8;1] = [0;1];
-	tty.set_baud_rate(TTY_BREAK_SPEED).unwrap();
-    tty.write(&break_byte).unwrap();
-    thread::sleep(BREAK_DELAY);
-    tty.set_baud_rate(TTY_DATA_SPEED).unwrap();
-	tty.write(&buf).unwrap();
+fn default_frame_rate() -> u32 {
+	DEFAULT_FRAME_RATE
 }
 
+fn default_width() -> u32 {
+	FrameFormat::default().panel_width
+}
+
+fn default_height() -> u32 {
+	FrameFormat::default().panel_height
+}
+
+fn default_one() -> u32 {
+	1
+}
+
+fn default_bpp() -> u8 {
+	FrameFormat::default().bpp
+}
+
+fn default_data_speed() -> u32 {
+	DEFAULT_DATA_SPEED
+}
+
+fn default_break_speed() -> u32 {
+	DEFAULT_BREAK_SPEED
+}
+
+fn default_break_delay_ms() -> u64 {
+	DEFAULT_BREAK_DELAY_MS
+}
+
+fn default_header_mark() -> u8 {
+	DrawHeader::default().mark
+}
+
+impl LedmatrixConfig {
+	/// Layout of draw data, checked for consistency
+	pub fn format(&self) -> Result<FrameFormat, String> {
+		let format = FrameFormat {
+			panel_width: self.width,
+			panel_height: self.height,
+			chain: self.chain,
+			rows: self.chain_rows,
+			layout: self.layout,
+			rotation: self.rotation,
+			bpp: self.bpp,
+			color: self.color,
+			header: DrawHeader { mark: self.header_mark, checksum: self.checksum }
+		};
+		format.validate().map_err(|e| format!("Bad ledmatrix geometry: {}", e))?;
+		Ok(format)
+	}
+
+	pub fn timing(&self) -> LineTiming {
+		LineTiming {
+			data_speed: self.data_speed,
+			break_speed: self.break_speed,
+			break_delay: Duration::from_millis(self.break_delay_ms)
+		}
+	}
+}
+
+/// Serial line settings of frame transfer
+#[derive(Debug, Clone, Copy)]
+pub struct LineTiming {
+	pub data_speed: u32,
+	pub break_speed: u32,
+	/// Pause after break byte before draw data
+	pub break_delay: Duration
+}
+
+/// Send encoded draw data to display, returns after all bytes are transmitted
+pub fn send_draw_data(tty: &mut Box<dyn SerialPort>, buf: &[u8], timing: &LineTiming) -> Result<(), String> {
+	let break_byte: [u8;1] = [0;1];
+	tty.set_baud_rate(timing.break_speed).map_err(|e| format!("Fail to set break speed: {}", e))?;
+	tty.write_all(&break_byte).map_err(|e| format!("Fail to send break: {}", e))?;
+	thread::sleep(timing.break_delay);
+	tty.set_baud_rate(timing.data_speed).map_err(|e| format!("Fail to set data speed: {}", e))?;
+	tty.write_all(buf).map_err(|e| format!("Fail to send draw data: {}", e))?;
+	// speed of next break must not be changed while frame is in output queue
+	tty.flush().map_err(|e| format!("Fail to send draw data: {}", e))
+}
+
+/// Real display on serial port
+struct SerialSink {
+	tty: Box<dyn SerialPort>,
+	timing: LineTiming
+}
+
+impl FrameSink for SerialSink {
+	fn send(&mut self, data: &[u8]) -> Result<(), String> {
+		send_draw_data(&mut self.tty, data, &self.timing)
+	}
+}
+
+/// LED matrix, `driver` of config selects where frames go:
+/// `term` for terminal preview, `png:<path>` for PNG snapshots, otherwise serial port
+pub struct Display {
+	sink: Box<dyn FrameSink>,
+	format: FrameFormat
+}
+
+impl Display {
+	pub fn open(config: &LedmatrixConfig) -> Result<Self, String> {
+		let format = config.format()?;
+		let sink: Box<dyn FrameSink> = if config.driver == TERM_DRIVER {
+			Box::new(TermSink::new(format.clone()))
+		} else if let Some(path) = config.driver.strip_prefix(PNG_DRIVER_PREFIX) {
+			Box::new(PngSink::new(path, format.clone()))
+		} else {
+			let timing = config.timing();
+			match serialport::new(&config.driver, timing.data_speed).open() {
+				Ok(tty) => Box::new(SerialSink { tty, timing }),
+				Err(e) => return Err(format!("Fail to open serial port: {}", e))
+			}
+		};
+		Ok(Self { sink, format })
+	}
+
+	/// Blank frame of display size
+	pub fn frame(&self) -> Framebuffer {
+		self.format.frame()
+	}
+
+	pub fn show(&mut self, fb: &Framebuffer) -> Result<(), String> {
+		if fb.width() != self.format.width() || fb.height() != self.format.height() {
+			return Err(format!("Frame {}x{} does not match display {}x{}",
+				fb.width(), fb.height(), self.format.width(), self.format.height()));
+		}
+		let buf = self.format.encode(fb);
+		self.sink.send(&buf)
+	}
+}
+
+/// Border, diagonals and centre square with gradient
+fn draw_pattern(fb: &mut Framebuffer) {
+	let (w, h) = (fb.width() as i32, fb.height() as i32);
+	fb.clear();
+	fb.rect(0, 0, w as u32, h as u32, LEVEL_MAX);
+	fb.line(0, 0, w - 1, h - 1, LEVEL_MAX / 2);
+	fb.line(0, h - 1, w - 1, 0, LEVEL_MAX / 2);
+	let mut square = Framebuffer::new(LEVEL_MAX as u32 + 1, 8);
+	for level in 0..=LEVEL_MAX {
+		square.fill_rect(level as i32, 0, 1, 8, level);
+	}
+	fb.blit(&square, (w - square.width() as i32) / 2, (h - square.height() as i32) / 2);
+}
+
+/// Incoming inspection: operator confirms every diagnostic pattern
 pub fn test(config: &LedmatrixConfig) -> Result<(), String> {
 	println!("\n[LEDMATRIX] Test begin..");
-	println!("Display should be filling of white color..");
-	let mut dd_buf: [u8;DRAW_DATA_SIZE] = [0;DRAW_DATA_SIZE];
-	let mut serial = match serialport::new(&config.driver, TTY_DATA_SPEED).open() {
-		Ok(port) => port,
-		Err(e) => return Err(format!("Fail to open serial port: {}", e.to_string()))
-	};
+	println!("Answer for every pattern whether display shows it right..");
+	let mut display = Display::open(config)?;
+	let ctl = utils::InController::new(&ledmatrix_diag::CONTROL_MAP);
+	let report = ledmatrix_diag::run(&mut display, &ctl, Duration::from_millis(config.fill_delay as u64))?;
+	report.print();
+	report.result()
+}
 
-	let exiter = utils::Exiter::new();
-	let mut i = 0;
+/// Render text with font and show it once
+pub fn show_text(config: &LedmatrixConfig, msg: &str, font: &str, align: Align) -> Result<(), String> {
+	let font = Font::open(font)?;
+	let mut display = Display::open(config)?;
+	let mut fb = display.frame();
+	text::draw_text(&mut fb, &font, msg, align, LEVEL_MAX);
+	display.show(&fb)
+}
+
+/// Scenes of demo, switched by `demo_updater` which also sets countdown end
+fn demo_scenes(font: &Arc<Font>, frame: &Framebuffer) -> Vec<Scene> {
+	let mut pattern = frame.clone();
+	draw_pattern(&mut pattern);
+	let mut inverse = frame.clone();
+	inverse.fill(LEVEL_MAX);
+	inverse.fill_rect(4, 4, inverse.width() - 8, inverse.height() - 8, 0);
+	vec![
+		Scene::Scroll { text: String::from("Добро пожаловать! Welcome!"), font: font.clone(), speed: 24, level: LEVEL_MAX },
+		Scene::Countdown { until: Instant::now(), prefix: String::new(), font: font.clone(), level: LEVEL_MAX },
+		Scene::Blink { frame: pattern.clone(), on: Duration::from_millis(500), off: Duration::from_millis(250) },
+		Scene::Sequence { frames: vec![(pattern, Duration::from_millis(300)), (inverse, Duration::from_millis(300))], repeat: true }
+	]
+}
+
+/// Switch demo scenes from own thread until scheduler stops
+fn demo_updater(handle: SceneHandle, font: Arc<Font>, frame: Framebuffer) {
 	loop {
-		dd_buf[i] = 0xFF;
-		i += 1;
-		if i >= DRAW_DATA_SIZE {
-			i = 0;
+		for mut scene in demo_scenes(&font, &frame) {
+			// countdown starts when it is switched in
+			if let Scene::Countdown { until, .. } = &mut scene {
+				*until = Instant::now() + DEMO_SCENE_TIME / 2;
+			}
+			if handle.set(scene).is_err() {
+				return;
+			}
+			thread::sleep(DEMO_SCENE_TIME);
 		}
-		send_draw_data(&mut serial, &dd_buf);
-		if exiter.check() {
-			break;
+	}
+}
+
+/// Run render loop with scroll, countdown, blink and sequence scenes, print frame statistics
+pub fn demo(config: &LedmatrixConfig, font: &str) -> Result<(), String> {
+	println!("\n[LEDMATRIX] Demo of scenes at {} fps..", config.frame_rate);
+	let font = Arc::new(Font::open(font)?);
+	let display = Display::open(config)?;
+	let frame = display.frame();
+	let mut title = frame.clone();
+	text::draw_text(&mut title, &font, "Demo", Align::Center, LEVEL_MAX);
+	let scheduler = FrameScheduler::new(display, config.frame_rate);
+	scheduler.set(Scene::Static(title))?;
+	thread::sleep(STATS_PERIOD);
+	let handle = scheduler.handle();
+	let updater = thread::spawn(move || demo_updater(handle, font, frame));
+
+	let exiter = utils::Exiter::new();
+	let mut dropped = 0;
+	while !exiter.check() {
+		thread::sleep(STATS_PERIOD);
+		let st = scheduler.stats();
+		println!("\tFrames: {}, dropped: {} (+{}), max frame time: {} ms, errors: {}",
+			st.frames, st.dropped, st.dropped - dropped, st.max_frame_time.as_millis(), st.errors);
+		dropped = st.dropped;
+		if let Some(e) = st.last_error {
+			println!("\tLast error: {}", e);
 		}
-		thread::sleep(Duration::from_millis(config.fill_delay as u64));
 	}
+	drop(scheduler);
+	let _ = updater.join();
 	Ok(())
-}
\ No newline at end of file
+}
+
+/// Show image or play animation until exit, with `save` frames are only written to file
+pub fn show_image(config: &LedmatrixConfig, path: &str, fit: Fit, dither: Dither, save: Option<&str>) -> Result<(), String> {
+	let frames = picture::load(path, &config.format()?.frame(), fit, dither)?;
+	if let Some(out) = save {
+		picture::save_frames(out, &frames)?;
+		println!("{} frames saved to {}", frames.len(), out);
+		return Ok(());
+	}
+	let mut display = Display::open(config)?;
+	if frames.len() == 1 {
+		return display.show(&frames[0].0);
+	}
+	println!("Playing {} frames..", frames.len());
+	let scheduler = FrameScheduler::new(display, config.frame_rate);
+	scheduler.set(Scene::Sequence { frames, repeat: true })?;
+	let exiter = utils::Exiter::new();
+	while !exiter.check() {
+		thread::sleep(STATS_PERIOD);
+	}
+	let st = scheduler.stats();
+	println!("\tFrames: {}, dropped: {}", st.frames, st.dropped);
+	Ok(())
+}
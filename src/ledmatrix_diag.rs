@@ -0,0 +1,269 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::framebuffer::{Framebuffer, LEVEL_MAX};
+use crate::ledmatrix::Display;
+use crate::utils::InController;
+
+/// Step of row and column walk, slow enough to follow a single line
+const WALK_DELAY: Duration = Duration::from_millis(150);
+/// Checkerboard is inverted with this period, so every LED is checked on and off
+const CHECKER_DELAY: Duration = Duration::from_secs(1);
+const POLL_DELAY: Duration = Duration::from_millis(20);
+
+pub const CONTROL_MAP: [&str;2] = ["y - pattern is shown right", "n - pattern is wrong"];
+
+/// Standard pattern of incoming inspection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+	/// Whole display at one brightness level
+	Level(u8),
+	RowWalk,
+	ColumnWalk,
+	Checkerboard,
+	/// Brightness levels from left to right
+	Gradient,
+	MovingPixel,
+	Border
+}
+
+impl Pattern {
+	/// Every gray level, then line walks and test images
+	pub fn all() -> Vec<Pattern> {
+		(0..=LEVEL_MAX).map(Pattern::Level)
+			.chain([Pattern::RowWalk, Pattern::ColumnWalk, Pattern::Checkerboard,
+				Pattern::Gradient, Pattern::MovingPixel, Pattern::Border])
+			.collect()
+	}
+
+	pub fn name(&self) -> String {
+		match self {
+			Pattern::Level(level) => format!("Level {}", level),
+			Pattern::RowWalk => String::from("Row walk"),
+			Pattern::ColumnWalk => String::from("Column walk"),
+			Pattern::Checkerboard => String::from("Checkerboard"),
+			Pattern::Gradient => String::from("Gradient"),
+			Pattern::MovingPixel => String::from("Moving pixel"),
+			Pattern::Border => String::from("Border")
+		}
+	}
+
+	/// What operator has to confirm
+	fn question(&self) -> String {
+		match self {
+			Pattern::Level(0) => String::from("All LEDs are off?"),
+			Pattern::Level(level) => format!("All LEDs are lit evenly at level {} of {}?", level, LEVEL_MAX),
+			Pattern::RowWalk => String::from("Lit row runs from top to bottom, every row is complete?"),
+			Pattern::ColumnWalk => String::from("Lit column runs from left to right, every column is complete?"),
+			Pattern::Checkerboard => String::from("Checkerboard inverts every second, no LED stays the same?"),
+			Pattern::Gradient => format!("Brightness rises from left to right in {} even steps?", LEVEL_MAX + 1),
+			Pattern::MovingPixel => String::from("Single pixel moves row by row, no other LED is lit?"),
+			Pattern::Border => String::from("Border is complete on all four edges?")
+		}
+	}
+
+	/// Steps of animation, 1 for still pattern
+	pub fn steps(&self, fb: &Framebuffer) -> u32 {
+		match self {
+			Pattern::RowWalk => fb.height(),
+			Pattern::ColumnWalk => fb.width(),
+			Pattern::Checkerboard => 2,
+			Pattern::MovingPixel => fb.width() * fb.height(),
+			_ => 1
+		}
+	}
+
+	fn step_delay(&self, pixel_delay: Duration) -> Duration {
+		match self {
+			Pattern::RowWalk | Pattern::ColumnWalk => WALK_DELAY,
+			Pattern::Checkerboard => CHECKER_DELAY,
+			_ => pixel_delay
+		}
+	}
+
+	/// Draw animation step of pattern
+	pub fn render(&self, fb: &mut Framebuffer, step: u32) {
+		let (w, h) = (fb.width(), fb.height());
+		fb.clear();
+		match self {
+			Pattern::Level(level) => fb.fill(*level),
+			Pattern::RowWalk => fb.fill_rect(0, (step % h) as i32, w, 1, LEVEL_MAX),
+			Pattern::ColumnWalk => fb.fill_rect((step % w) as i32, 0, 1, h, LEVEL_MAX),
+			Pattern::Checkerboard => {
+				for y in 0..h {
+					for x in 0..w {
+						if (x + y + step).is_multiple_of(2) {
+							fb.set_pixel(x as i32, y as i32, LEVEL_MAX);
+						}
+					}
+				}
+			},
+			Pattern::Gradient => {
+				for x in 0..w {
+					let level = (x * (LEVEL_MAX as u32 + 1) / w) as u8;
+					fb.fill_rect(x as i32, 0, 1, h, level);
+				}
+			},
+			Pattern::MovingPixel => {
+				let i = step % (w * h);
+				fb.set_pixel((i % w) as i32, (i / w) as i32, LEVEL_MAX);
+			},
+			Pattern::Border => fb.rect(0, 0, w, h, LEVEL_MAX)
+		}
+	}
+}
+
+/// Result of inspection, patterns not reached before exit are missing
+#[derive(Default)]
+pub struct Report {
+	pub failed: Vec<Pattern>,
+	pub passed: Vec<Pattern>,
+	pub dead_rows: Vec<u32>,
+	pub dead_columns: Vec<u32>,
+	pub aborted: bool
+}
+
+impl Report {
+	pub fn print(&self) {
+		println!("Inspection report:");
+		println!("\tPassed: {} patterns", self.passed.len());
+		for p in &self.failed {
+			println!("\tFailed: {}", p.name());
+		}
+		if !self.dead_rows.is_empty() {
+			println!("\tDead rows: {:?}", self.dead_rows);
+		}
+		if !self.dead_columns.is_empty() {
+			println!("\tDead columns: {:?}", self.dead_columns);
+		}
+		if self.aborted {
+			println!("\tInspection was not finished");
+		}
+	}
+
+	/// Error when any pattern failed or inspection was aborted
+	pub fn result(&self) -> Result<(), String> {
+		if !self.failed.is_empty() {
+			Err(format!("{} of {} patterns failed", self.failed.len(), self.failed.len() + self.passed.len()))
+		} else if self.aborted {
+			Err(String::from("Inspection aborted"))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+/// Show pattern animated until operator answers
+fn ask(display: &mut Display, ctl: &InController, pattern: Pattern, pixel_delay: Duration) -> Result<char, String> {
+	let mut fb = display.frame();
+	let steps = pattern.steps(&fb);
+	let delay = pattern.step_delay(pixel_delay);
+	println!("{}: {}", pattern.name(), pattern.question());
+	let mut step = 0;
+	loop {
+		pattern.render(&mut fb, step);
+		display.show(&fb)?;
+		if steps == 1 {
+			return Ok(ctl.get());
+		}
+		step = (step + 1) % steps;
+		// answer is polled often, slow animation does not delay it
+		let mut waited = Duration::ZERO;
+		while waited < delay {
+			if let Some(c) = ctl.try_get() {
+				return Ok(c);
+			}
+			let d = POLL_DELAY.min(delay - waited);
+			thread::sleep(d);
+			waited += d;
+		}
+	}
+}
+
+/// Step through rows or columns of walk pattern and mark dead ones, returns false on exit
+fn mark_lines(display: &mut Display, ctl: &InController, pattern: Pattern, dead: &mut Vec<u32>) -> Result<bool, String> {
+	let kind = if pattern == Pattern::RowWalk { "row" } else { "column" };
+	println!("\tMark dead {}s: Enter - next, p - previous, d - mark/unmark, y - done", kind);
+	let mut fb = display.frame();
+	let count = pattern.steps(&fb);
+	let mut i = 0;
+	loop {
+		pattern.render(&mut fb, i);
+		display.show(&fb)?;
+		println!("\t{} {}{}", kind, i, if dead.contains(&i) { " (dead)" } else { "" });
+		match ctl.get() {
+			'\n' => i = (i + 1) % count,
+			'p' => i = (i + count - 1) % count,
+			'd' => {
+				match dead.iter().position(|d| *d == i) {
+					Some(pos) => { dead.remove(pos); },
+					None => dead.push(i)
+				}
+			},
+			'y' => break,
+			'q' => return Ok(false),
+			_ => println!("Unknown command")
+		}
+	}
+	dead.sort();
+	Ok(true)
+}
+
+/// Show every pattern and collect answers of operator. Failed row or column walk
+/// offers marking of dead lines.
+pub fn run(display: &mut Display, ctl: &InController, pixel_delay: Duration) -> Result<Report, String> {
+	let mut report = Report::default();
+	for pattern in Pattern::all() {
+		loop {
+			match ask(display, ctl, pattern, pixel_delay)? {
+				'y' => report.passed.push(pattern),
+				'n' => {
+					report.failed.push(pattern);
+					let dead = match pattern {
+						Pattern::RowWalk => &mut report.dead_rows,
+						Pattern::ColumnWalk => &mut report.dead_columns,
+						_ => break
+					};
+					if !mark_lines(display, ctl, pattern, dead)? {
+						report.aborted = true;
+						return Ok(report);
+					}
+				},
+				'q' => {
+					report.aborted = true;
+					return Ok(report);
+				},
+				_ => {
+					println!("Type 'y' or 'n'");
+					continue;
+				}
+			}
+			break;
+		}
+	}
+	display.show(&display.frame())?;
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn patterns_cover_display() {
+		let mut fb = Framebuffer::new(8, 4);
+		let lit = |fb: &Framebuffer| (0..4).flat_map(|y| (0..8).map(move |x| (x, y)))
+			.filter(|(x, y)| fb.pixel(*x, *y) != 0).count();
+		Pattern::Checkerboard.render(&mut fb, 0);
+		let even = fb.clone();
+		Pattern::Checkerboard.render(&mut fb, 1);
+		assert_eq!(lit(&even) + lit(&fb), 32);
+		Pattern::Border.render(&mut fb, 0);
+		assert_eq!(lit(&fb), 20);
+		Pattern::MovingPixel.render(&mut fb, 9);
+		assert_eq!((lit(&fb), fb.pixel(1, 1)), (1, LEVEL_MAX));
+		Pattern::Gradient.render(&mut fb, 0);
+		assert_eq!((fb.pixel(0, 0), fb.pixel(7, 3)), (0, 14));
+		assert_eq!(Pattern::all().len(), LEVEL_MAX as usize + 7);
+	}
+}
@@ -0,0 +1,209 @@
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, TTYPort};
+
+use crate::frameformat::FrameFormat;
+use crate::ledmatrix::LedmatrixConfig;
+use crate::sink::{FrameSink, PngSink, TermSink};
+use crate::utils;
+
+const READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+pub enum Event {
+	/// Valid draw data and silence between break and data, zero if they came as one burst
+	Frame(Vec<u8>, Duration),
+	Error(String)
+}
+
+/// Splits received bytes into bursts by silence. Burst of single zero byte is the break,
+/// burst after it must be draw data of one frame.
+pub struct FrameDecoder {
+	format: FrameFormat,
+	/// Silence which ends a burst, shorter than break delay of sender
+	idle_gap: Duration,
+	burst: Vec<u8>,
+	burst_start: Instant,
+	last: Option<Instant>,
+	/// End of last break burst
+	break_at: Option<Instant>
+}
+
+impl FrameDecoder {
+	pub fn new(format: FrameFormat, idle_gap: Duration) -> Self {
+		Self {
+			format,
+			idle_gap,
+			burst: Vec::new(),
+			burst_start: Instant::now(),
+			last: None,
+			break_at: None
+		}
+	}
+
+	/// Bytes received at `now`
+	pub fn push(&mut self, data: &[u8], now: Instant) -> Option<Event> {
+		let event = self.idle(now);
+		if self.burst.is_empty() {
+			self.burst_start = now;
+		}
+		self.burst.extend_from_slice(data);
+		self.last = Some(now);
+		event
+	}
+
+	/// Nothing received until `now`
+	pub fn idle(&mut self, now: Instant) -> Option<Event> {
+		match self.last {
+			Some(last) if now.duration_since(last) >= self.idle_gap && !self.burst.is_empty() => self.end_burst(last),
+			_ => None
+		}
+	}
+
+	fn end_burst(&mut self, end: Instant) -> Option<Event> {
+		let burst = std::mem::take(&mut self.burst);
+		let size = self.format.encoded_size();
+		if burst == [0] {
+			let prev = self.break_at.replace(end);
+			return prev.map(|_| Event::Error(String::from("Break without draw data")));
+		}
+		let (data, gap) = match self.break_at.take() {
+			Some(brk) => (burst, self.burst_start.duration_since(brk)),
+			// break and data merged when sender did not wait long enough
+			None if burst.len() == size + 1 && burst[0] == 0 => (burst[1..].to_vec(), Duration::ZERO),
+			None => return Some(Event::Error(format!("{} bytes without break", burst.len())))
+		};
+		if data.len() != size {
+			return Some(Event::Error(format!("Frame is {} bytes, expected {}", data.len(), size)));
+		}
+		match self.format.decode(&data) {
+			Ok(_) => Some(Event::Frame(data, gap)),
+			Err(e) => Some(Event::Error(e))
+		}
+	}
+}
+
+/// Receive frames on serial port or new pty and show them in terminal or as PNG
+pub fn run(config: &LedmatrixConfig, port: Option<&str>, png: Option<&str>) -> Result<(), String> {
+	println!("\n[LEDMATRIX] Emulator begin..");
+	let format = config.format()?;
+	let timing = config.timing();
+	// slave end of pty is kept open, so the port survives reconnects of sender
+	let (mut tty, _slave): (Box<dyn SerialPort>, Option<TTYPort>) = match port {
+		Some(path) => match serialport::new(path, timing.data_speed).open() {
+			Ok(tty) => (tty, None),
+			Err(e) => return Err(format!("Fail to open serial port: {}", e))
+		},
+		None => {
+			let (master, slave) = TTYPort::pair().map_err(|e| format!("Fail to create pty: {}", e))?;
+			println!("\tSet ledmatrix driver to {}", slave.name().unwrap_or_default());
+			(Box::new(master), Some(slave))
+		}
+	};
+	tty.set_timeout(READ_TIMEOUT).map_err(|e| format!("Fail to set timeout: {}", e))?;
+	let mut sink: Box<dyn FrameSink> = match png {
+		Some(path) => Box::new(PngSink::new(path, format.clone())),
+		None => Box::new(TermSink::new(format.clone()))
+	};
+
+	let mut decoder = FrameDecoder::new(format, timing.break_delay / 2);
+	let mut buf = [0u8;4096];
+	let (mut frames, mut errors) = (0u64, 0u64);
+	let mut gaps: Option<(Duration, Duration)> = None;
+	let exiter = utils::Exiter::new();
+	while !exiter.check() {
+		let event = match tty.read(&mut buf) {
+			Ok(n) => decoder.push(&buf[..n], Instant::now()),
+			Err(e) if e.kind() == ErrorKind::TimedOut => decoder.idle(Instant::now()),
+			Err(e) => return Err(format!("Fail to read serial port: {}", e))
+		};
+		match event {
+			Some(Event::Frame(data, gap)) => {
+				frames += 1;
+				gaps = Some(gaps.map_or((gap, gap), |(min, max)| (min.min(gap), max.max(gap))));
+				sink.send(&data)?;
+			},
+			Some(Event::Error(e)) => {
+				errors += 1;
+				println!("\tFrame error: {}", e);
+			},
+			None => ()
+		}
+	}
+	println!("\tFrames: {}, errors: {}", frames, errors);
+	if let Some((min, max)) = gaps {
+		println!("\tBreak to data gap: {}..{} ms", min.as_millis(), max.as_millis());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use crate::framebuffer::Framebuffer;
+	use crate::ledmatrix::{self, LineTiming};
+
+	use super::*;
+
+	const IDLE_GAP: Duration = Duration::from_millis(5);
+
+	fn frame() -> Framebuffer {
+		let mut fb = FrameFormat::default().frame();
+		fb.line(0, 0, 63, 31, 15);
+		fb.fill_rect(10, 10, 5, 5, 7);
+		fb
+	}
+
+	#[test]
+	fn frames_sent_over_pty_are_decoded() {
+		let (mut master, slave) = TTYPort::pair().unwrap();
+		master.set_timeout(READ_TIMEOUT).unwrap();
+		let data = FrameFormat::default().encode(&frame());
+		let sent = data.clone();
+		let timing = LineTiming { data_speed: 500000, break_speed: 1200, break_delay: IDLE_GAP * 2 };
+		let sender = thread::spawn(move || {
+			let mut tty: Box<dyn SerialPort> = Box::new(slave);
+			for _ in 0..3 {
+				ledmatrix::send_draw_data(&mut tty, &sent, &timing).unwrap();
+				thread::sleep(IDLE_GAP * 2);
+			}
+			tty
+		});
+		let mut decoder = FrameDecoder::new(FrameFormat::default(), IDLE_GAP);
+		let mut received = Vec::new();
+		let mut buf = [0u8;4096];
+		let start = Instant::now();
+		while received.len() < 3 && start.elapsed() < Duration::from_secs(2) {
+			let event = match master.read(&mut buf) {
+				Ok(n) => decoder.push(&buf[..n], Instant::now()),
+				Err(_) => decoder.idle(Instant::now())
+			};
+			match event {
+				Some(Event::Frame(frame, gap)) => {
+					assert!(gap >= IDLE_GAP);
+					received.push(frame);
+				},
+				Some(Event::Error(e)) => panic!("{}", e),
+				None => ()
+			}
+		}
+		let _tty = sender.join().unwrap();
+		assert_eq!(received, vec![data; 3]);
+	}
+
+	#[test]
+	fn short_frame_and_missing_break_are_reported() {
+		let mut decoder = FrameDecoder::new(FrameFormat::default(), IDLE_GAP);
+		let t = Instant::now();
+		let data = FrameFormat::default().encode(&frame());
+		assert!(decoder.push(&[0], t).is_none());
+		assert!(decoder.push(&data[..100], t + IDLE_GAP * 2).is_none());
+		match decoder.idle(t + IDLE_GAP * 4) {
+			Some(Event::Error(e)) => assert!(e.contains("100 bytes")),
+			_ => panic!("short frame is not reported")
+		}
+		decoder.push(&data, t + IDLE_GAP * 6);
+		assert!(matches!(decoder.idle(t + IDLE_GAP * 8), Some(Event::Error(_))));
+	}
+}
@@ -1,65 +1,739 @@
+use std::collections::HashMap;
 use std::io::Write;
-use std::thread;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use serde::Deserialize;
-use spidev::{Spidev, SpidevOptions, SpiModeFlags};
+use spidev::{Spidev, SpidevOptions, SpidevTransfer, SpiModeFlags};
 use gpio::GpioValue;
-use gpio::sysfs::SysFsGpioOutput;
-use gpio::{GpioOut};
 
 use crate::utils;
+use crate::gpioline::{self, OutputPin, PinConfig};
+use crate::segment;
+use crate::brightness::{self, Dimmer, DimmingEntry, HwPwmConfig, Schedule, SoftPwm, SysfsPwm, BRIGHTNESS_MAX};
 
 
 const CS_NORMAL: GpioValue = GpioValue::High;
 const CS_ACTIVE: GpioValue = GpioValue::Low;
 
+/// Period of refresh loop, blinking phase is checked this often
+const REFRESH_PERIOD: Duration = Duration::from_millis(50);
+
+/// Marker clocked through the chain by self-test, inverted on every second round
+const CHECK_PATTERN: [u8;4] = [0xA5, 0x3C, 0x96, 0x0F];
+const CHECK_ROUNDS: usize = 4;
+/// Longer chains than configured are still measured up to this many extra registers
+const CHECK_EXTRA_REGISTERS: usize = 8;
+
+/// Dimming schedule is checked this often
+const SCHEDULE_CHECK: Duration = Duration::from_secs(30);
+const BRIGHTNESS_STEP: u8 = 10;
+const ALL_SEGMENTS_DELAY: Duration = Duration::from_secs(2);
+
+/// Keys of interactive test, one per indicator, 'q' is taken by exit
+const TEST_KEYS: &str = "123456789abcdefghijklmnoprstuvwxyz";
+
+/// Lamp driven by one output of the chain
+#[derive(Deserialize, Debug, Clone)]
+pub struct IndicatorConfig {
+	name: String,
+	/// Output `bit % 8` of register `bit / 8`, register 0 is the first after controller
+	bit: u32
+}
+
+/// Row of seven-segment digits
+#[derive(Deserialize, Debug, Clone)]
+pub struct DigitsConfig {
+	name: String,
+	/// Bits of segments a, b, c, d, e, f, g and dp of every digit, leftmost digit first
+	digits: Vec<[u32;8]>
+}
+
+/// LED bar graph
+#[derive(Deserialize, Debug, Clone)]
+pub struct BarConfig {
+	name: String,
+	/// Bits of LEDs from the lowest level
+	bits: Vec<u32>
+}
+
 #[derive(Deserialize)]
 pub struct LedpanelConfig {
 	driver: String,
-	pin_cs: u16,
+	pin_cs: PinConfig,
 	speed: u32,
-	led_delay: u32
+	led_delay: u32,
+	/// 74HC595 registers in chain
+	#[serde(default = "default_chain")]
+	chain: usize,
+	#[serde(default)]
+	indicators: Vec<IndicatorConfig>,
+	#[serde(default)]
+	digits: Vec<DigitsConfig>,
+	#[serde(default)]
+	bars: Vec<BarConfig>,
+	/// Half period of blinking
+	#[serde(default = "default_blink_ms")]
+	blink_ms: u64,
+	/// Output enable line driven by software PWM
+	#[serde(default)]
+	pin_oe: Option<PinConfig>,
+	/// Hardware PWM on output enable line, used instead of `pin_oe`
+	#[serde(default)]
+	pwm: Option<HwPwmConfig>,
+	#[serde(default = "default_pwm_period_us")]
+	pwm_period_us: u64,
+	/// Percent, used when dimming schedule is empty
+	#[serde(default = "default_brightness")]
+	brightness: u8,
+	#[serde(default)]
+	dimming: Vec<DimmingEntry>,
+	/// Serial out of the last register is wired back to MISO, SPI runs in 4-wire mode
+	#[serde(default)]
+	readback: bool
+}
+
+fn default_chain() -> usize {
+	2
+}
+
+fn default_blink_ms() -> u64 {
+	500
+}
+
+fn default_pwm_period_us() -> u64 {
+	5000
+}
+
+fn default_brightness() -> u8 {
+	BRIGHTNESS_MAX
+}
+
+/// Shadow of outputs of the chain with names of indicators, digits and bar graphs
+pub struct PanelState {
+	bytes: Vec<u8>,
+	indicators: HashMap<String, u32>,
+	digits: HashMap<String, Vec<[u32;8]>>,
+	bars: HashMap<String, Vec<u32>>,
+	/// Outputs which blink and whether they are in off phase now
+	blink: Vec<u8>,
+	blink_off: bool
+}
+
+/// Check that every bit of named element is in chain and the name is not taken
+fn check_element(names: &mut Vec<String>, name: &str, bits: &[u32], chain: usize) -> Result<(), String> {
+	if let Some(bit) = bits.iter().find(|b| **b as usize >= chain * 8) {
+		return Err(format!("'{}' bit {} is out of {} registers", name, bit, chain));
+	}
+	if names.iter().any(|n| n == name) {
+		return Err(format!("'{}' is defined twice", name));
+	}
+	names.push(name.to_string());
+	Ok(())
+}
+
+impl PanelState {
+	pub fn new(chain: usize, indicators: &[IndicatorConfig], digits: &[DigitsConfig], bars: &[BarConfig]) -> Result<Self, String> {
+		let mut names = Vec::new();
+		let mut state = Self {
+			bytes: vec![0; chain],
+			indicators: HashMap::new(),
+			digits: HashMap::new(),
+			bars: HashMap::new(),
+			blink: vec![0; chain],
+			blink_off: false
+		};
+		for ind in indicators {
+			check_element(&mut names, &ind.name, &[ind.bit], chain)?;
+			state.indicators.insert(ind.name.clone(), ind.bit);
+		}
+		for dig in digits {
+			check_element(&mut names, &dig.name, dig.digits.as_flattened(), chain)?;
+			state.digits.insert(dig.name.clone(), dig.digits.clone());
+		}
+		for bar in bars {
+			check_element(&mut names, &bar.name, &bar.bits, chain)?;
+			state.bars.insert(bar.name.clone(), bar.bits.clone());
+		}
+		Ok(state)
+	}
+
+	/// Outputs of the chain
+	pub fn bits(&self) -> u32 {
+		self.bytes.len() as u32 * 8
+	}
+
+	pub fn bit(&self, name: &str) -> Result<u32, String> {
+		self.indicators.get(name).copied().ok_or_else(|| format!("Unknown indicator '{}'", name))
+	}
+
+	pub fn get_bit(&self, bit: u32) -> bool {
+		self.bytes[(bit / 8) as usize] & 1 << (bit % 8) != 0
+	}
+
+	pub fn set_bit(&mut self, bit: u32, on: bool) {
+		let byte = &mut self.bytes[(bit / 8) as usize];
+		if on {
+			*byte |= 1 << (bit % 8);
+		} else {
+			*byte &= !(1 << (bit % 8));
+		}
+	}
+
+	pub fn get(&self, name: &str) -> Result<bool, String> {
+		Ok(self.get_bit(self.bit(name)?))
+	}
+
+	pub fn set(&mut self, name: &str, on: bool) -> Result<(), String> {
+		let bit = self.bit(name)?;
+		self.set_bit(bit, on);
+		Ok(())
+	}
+
+	/// Turn all outputs off and stop blinking
+	pub fn clear_all(&mut self) {
+		self.bytes.fill(0);
+		self.blink.fill(0);
+	}
+
+	fn digits(&self, name: &str) -> Result<&Vec<[u32;8]>, String> {
+		self.digits.get(name).ok_or_else(|| format!("Unknown digits '{}'", name))
+	}
+
+	fn show_segments(&mut self, name: &str, segs: &[u8]) -> Result<(), String> {
+		let digits = self.digits(name)?.clone();
+		for (digit, seg) in digits.iter().zip(segs) {
+			for (i, bit) in digit.iter().enumerate() {
+				self.set_bit(*bit, seg & 1 << i != 0);
+			}
+		}
+		Ok(())
+	}
+
+	/// Left aligned text on digits, see `segment::render_text`
+	pub fn show_text(&mut self, name: &str, text: &str) -> Result<(), String> {
+		let segs = segment::render_text(text, self.digits(name)?.len())?;
+		self.show_segments(name, &segs)
+	}
+
+	/// Right aligned number on digits, `decimals` digits after point
+	pub fn show_number(&mut self, name: &str, value: i64, decimals: u32) -> Result<(), String> {
+		let segs = segment::render_number(value, decimals, self.digits(name)?.len())?;
+		self.show_segments(name, &segs)
+	}
+
+	/// Light bar graph for `value` of `max`
+	pub fn show_level(&mut self, name: &str, value: u32, max: u32) -> Result<(), String> {
+		let bits = self.bars.get(name).ok_or_else(|| format!("Unknown bar graph '{}'", name))?.clone();
+		let lit = segment::bar_level(value, max, bits.len());
+		for (i, bit) in bits.iter().enumerate() {
+			self.set_bit(*bit, i < lit);
+		}
+		Ok(())
+	}
+
+	/// All bits of indicator, digits or bar graph
+	fn element_bits(&self, name: &str) -> Result<Vec<u32>, String> {
+		if let Some(bit) = self.indicators.get(name) {
+			Ok(vec![*bit])
+		} else if let Some(digits) = self.digits.get(name) {
+			Ok(digits.as_flattened().to_vec())
+		} else if let Some(bits) = self.bars.get(name) {
+			Ok(bits.clone())
+		} else {
+			Err(format!("Unknown element '{}'", name))
+		}
+	}
+
+	/// Blink lit outputs of indicator, digits or bar graph
+	pub fn set_blink(&mut self, name: &str, on: bool) -> Result<(), String> {
+		for bit in self.element_bits(name)? {
+			let byte = &mut self.blink[(bit / 8) as usize];
+			if on {
+				*byte |= 1 << (bit % 8);
+			} else {
+				*byte &= !(1 << (bit % 8));
+			}
+		}
+		Ok(())
+	}
+
+	fn is_blinking(&self) -> bool {
+		self.blink.iter().any(|b| *b != 0)
+	}
+
+	/// Bytes in order of shifting: last register of the chain goes first,
+	/// most significant bit of byte ends up on output 7
+	pub fn shift_data(&self) -> Vec<u8> {
+		self.bytes.iter().zip(&self.blink).rev()
+			.map(|(byte, blink)| if self.blink_off { byte & !blink } else { *byte })
+			.collect()
+	}
+}
+
+fn get_bit(buf: &[u8], i: usize) -> bool {
+	buf[i / 8] & 0x80 >> (i % 8) != 0
+}
+
+/// Data of one readback transfer: `lead` zero bytes flush the chain, then marker,
+/// then zeros to push marker out
+fn check_frame(marker: &[u8], lead: usize) -> Vec<u8> {
+	let mut tx = vec![0; lead];
+	tx.extend_from_slice(marker);
+	tx.resize(lead * 2 + marker.len(), 0);
+	tx
+}
+
+/// What came back from the chain after `check_frame`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Readback {
+	/// Marker returned delayed by this many bits
+	Delay(usize),
+	/// Serial out keeps one level
+	Stuck(bool),
+	Garbled
+}
+
+/// Find delay of marker in received data of `check_frame`
+pub fn analyze_readback(marker: &[u8], lead: usize, rx: &[u8]) -> Readback {
+	let marker_bits = marker.len() * 8;
+	let start = lead * 8;
+	for delay in 0..=start {
+		if start + delay + marker_bits > rx.len() * 8 {
+			break;
+		}
+		if (0..marker_bits).all(|i| get_bit(rx, start + delay + i) == get_bit(marker, i)) {
+			return Readback::Delay(delay);
+		}
+	}
+	// older contents of the chain come out first, only the tail is checked
+	let tail = start..rx.len() * 8;
+	let level = get_bit(rx, start);
+	if tail.clone().all(|i| get_bit(rx, i) == level) {
+		Readback::Stuck(level)
+	} else {
+		Readback::Garbled
+	}
+}
+
+/// Error describing chain fault, `registers` is configured length
+pub fn check_readback(readback: &Readback, registers: usize) -> Result<(), String> {
+	match readback {
+		Readback::Delay(bits) if bits % 8 != 0 => {
+			Err(format!("Chain delays data by {} bits, not by whole registers", bits))
+		},
+		// shorter delay means registers are missing or bypassed, not where chain breaks
+		Readback::Delay(bits) if bits / 8 < registers => {
+			Err(format!("{} registers missing: chain delays data by {} of {} registers", registers - bits / 8, bits / 8, registers))
+		},
+		Readback::Delay(bits) if bits / 8 > registers => {
+			Err(format!("Chain has {} registers, config says {}", bits / 8, registers))
+		},
+		Readback::Delay(_) => Ok(()),
+		Readback::Stuck(level) => {
+			Err(format!("Serial out of chain is stuck at {}: chain broken before last register or readback not wired", *level as u8))
+		},
+		Readback::Garbled => Err(String::from("Marker did not come back, chain data is garbled"))
+	}
+}
+
+/// Chain of shift registers, every update shifts whole shadow and latches it by CS edge
+pub struct LedPanel {
+	spi: Spidev,
+	pin_cs: Box<dyn OutputPin>,
+	state: PanelState,
+	readback: bool,
+	blink_period: Duration,
+	blink_at: Instant,
+	dimmer: Option<Box<dyn Dimmer>>,
+	brightness: u8,
+	schedule: Schedule,
+	/// Last brightness taken from schedule, manual setting holds until it changes
+	scheduled: Option<u8>,
+	schedule_at: Instant
+}
+
+impl LedPanel {
+	/// Open SPI and CS pin, all outputs are turned off
+	pub fn open(config: &LedpanelConfig) -> Result<Self, String> {
+		let state = PanelState::new(config.chain, &config.indicators, &config.digits, &config.bars)?;
+		let mut spi = match Spidev::open(&config.driver) {
+			Ok(spi) => spi,
+			Err(e) => return Err(format!("Fail to open driver: {}", e))
+		};
+		let options = SpidevOptions::new()
+			.bits_per_word(8)
+			.max_speed_hz(config.speed)
+			.mode(if config.readback {
+				SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_NO_CS
+			} else {
+				SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_3WIRE | SpiModeFlags::SPI_NO_CS
+			})
+			.build();
+		spi.configure(&options).map_err(|e| format!("Fail to configure driver: {}", e))?;
+		let pin_cs = match gpioline::open_output(&config.pin_cs, CS_NORMAL) {
+			Ok(pin) => pin,
+			Err(e) => return Err(format!("Fail to open CS pin {}: {}", config.pin_cs, e))
+		};
+		let period = Duration::from_micros(config.pwm_period_us);
+		let dimmer: Option<Box<dyn Dimmer>> = match (&config.pwm, &config.pin_oe) {
+			(Some(pwm), _) => Some(Box::new(SysfsPwm::open(pwm, period)?)),
+			(None, Some(pin)) => Some(Box::new(SoftPwm::open(pin, period)?)),
+			(None, None) => None
+		};
+		let schedule = Schedule::new(&config.dimming)?;
+		let mut panel = Self {
+			spi,
+			pin_cs,
+			state,
+			readback: config.readback,
+			blink_period: Duration::from_millis(config.blink_ms),
+			blink_at: Instant::now(),
+			dimmer,
+			brightness: BRIGHTNESS_MAX,
+			schedule,
+			scheduled: None,
+			schedule_at: Instant::now()
+		};
+		panel.latch()?;
+		if panel.dimmer.is_some() {
+			panel.scheduled = panel.schedule.at(brightness::local_minute());
+			panel.set_brightness(panel.scheduled.unwrap_or(config.brightness))?;
+		}
+		Ok(panel)
+	}
+
+	pub fn has_dimmer(&self) -> bool {
+		self.dimmer.is_some()
+	}
+
+	pub fn brightness(&self) -> u8 {
+		self.brightness
+	}
+
+	/// Percent of time outputs are enabled, needs OE pin or PWM in config
+	pub fn set_brightness(&mut self, percent: u8) -> Result<(), String> {
+		let percent = percent.min(BRIGHTNESS_MAX);
+		match &mut self.dimmer {
+			Some(dimmer) => dimmer.set(percent)?,
+			None => return Err(String::from("Brightness needs pin_oe or pwm in config"))
+		}
+		self.brightness = percent;
+		Ok(())
+	}
+
+	/// Apply dimming schedule when its entry changes
+	fn check_schedule(&mut self) -> Result<(), String> {
+		if self.dimmer.is_none() || self.schedule_at.elapsed() < SCHEDULE_CHECK {
+			return Ok(());
+		}
+		self.schedule_at = Instant::now();
+		let value = self.schedule.at(brightness::local_minute());
+		if value == self.scheduled {
+			return Ok(());
+		}
+		self.scheduled = value;
+		match value {
+			Some(percent) => self.set_brightness(percent),
+			None => Ok(())
+		}
+	}
+
+	pub fn state(&self) -> &PanelState {
+		&self.state
+	}
+
+	/// Shift shadow state out, outputs change together on CS rising edge
+	fn latch(&mut self) -> Result<(), String> {
+		let data = self.state.shift_data();
+		self.pin_cs.write(CS_ACTIVE).map_err(|e| format!("Fail to set CS: {}", e))?;
+		let res = self.spi.write_all(&data).map_err(|e| format!("Fail to write SPI: {}", e));
+		self.pin_cs.write(CS_NORMAL).map_err(|e| format!("Fail to set CS: {}", e))?;
+		res
+	}
+
+	/// Clock markers through the chain and read them back from the last register.
+	/// Outputs flicker during the check, shadow state is latched again after it.
+	pub fn check_chain(&mut self) -> Result<Readback, String> {
+		if !self.readback {
+			return Err(String::from("Chain check needs readback in config"));
+		}
+		let lead = self.state.bytes.len() + CHECK_EXTRA_REGISTERS;
+		let mut result = Readback::Garbled;
+		for round in 0..CHECK_ROUNDS {
+			let marker: Vec<u8> = CHECK_PATTERN.iter().map(|b| if round % 2 == 1 { !b } else { *b }).collect();
+			let tx = check_frame(&marker, lead);
+			let mut rx = vec![0; tx.len()];
+			self.pin_cs.write(CS_ACTIVE).map_err(|e| format!("Fail to set CS: {}", e))?;
+			let res = self.spi.transfer(&mut SpidevTransfer::read_write(&tx, &mut rx));
+			self.pin_cs.write(CS_NORMAL).map_err(|e| format!("Fail to set CS: {}", e))?;
+			res.map_err(|e| format!("Fail to transfer SPI: {}", e))?;
+			result = analyze_readback(&marker, lead, &rx);
+			if !matches!(result, Readback::Delay(_)) {
+				break;
+			}
+		}
+		self.latch()?;
+		Ok(result)
+	}
+
+	/// Change several outputs in one latch
+	pub fn update<F>(&mut self, f: F) -> Result<(), String>
+		where F: FnOnce(&mut PanelState) -> Result<(), String>
+	{
+		f(&mut self.state)?;
+		self.latch()
+	}
+
+	pub fn toggle(&mut self, name: &str) -> Result<(), String> {
+		self.update(|s| s.set(name, !s.get(name)?))
+	}
+
+	pub fn clear_all(&mut self) -> Result<(), String> {
+		self.update(|s| {
+			s.clear_all();
+			Ok(())
+		})
+	}
+
+	/// Follow dimming schedule and switch blinking phase when its time has come
+	pub fn tick(&mut self) -> Result<(), String> {
+		self.check_schedule()?;
+		if self.blink_at.elapsed() < self.blink_period {
+			return Ok(());
+		}
+		self.blink_at = Instant::now();
+		if !self.state.is_blinking() && !self.state.blink_off {
+			return Ok(());
+		}
+		self.state.blink_off = !self.state.blink_off && self.state.is_blinking();
+		self.latch()
+	}
 }
 
-fn show_buf(spi: &mut Spidev, pin_cs: &mut SysFsGpioOutput, buf: &[u8]) {
-	pin_cs.set_value(CS_ACTIVE).unwrap();
-	spi.write_all(buf).unwrap();
-	pin_cs.set_value(CS_NORMAL).unwrap();
+fn refresh_handler(panel: Arc<Mutex<LedPanel>>, rx: std::sync::mpsc::Receiver<()>) {
+	while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(REFRESH_PERIOD) {
+		// errors show up on next update from owner
+		let _ = panel.lock().unwrap().tick();
+	}
+}
+
+/// Refresh loop owning the panel, drives blinking and dimming schedule. Stops when dropped.
+pub struct PanelRefresh {
+	panel: Arc<Mutex<LedPanel>>,
+	stop: Option<Sender<()>>,
+	thread: Option<JoinHandle<()>>
 }
 
+impl PanelRefresh {
+	pub fn new(panel: LedPanel) -> Self {
+		let panel = Arc::new(Mutex::new(panel));
+		let (tx, rx) = channel();
+		let thread_panel = panel.clone();
+		let thread = thread::spawn(move || refresh_handler(thread_panel, rx));
+		Self { panel, stop: Some(tx), thread: Some(thread) }
+	}
+
+	/// Change several outputs in one latch
+	pub fn update<F>(&self, f: F) -> Result<(), String>
+		where F: FnOnce(&mut PanelState) -> Result<(), String>
+	{
+		self.panel.lock().unwrap().update(f)
+	}
+}
+
+impl Drop for PanelRefresh {
+	fn drop(&mut self) {
+		if let Some(stop) = self.stop.take() {
+			let _ = stop.send(());
+		}
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+/// Fill outputs one by one until exit, then toggle indicators by keys
 pub fn test(config: &LedpanelConfig) -> Result<(), String> {
 	println!("\n[LEDPANEL] Test begin..");
-	let mut spi = match Spidev::open(&config.driver) {
-		Ok(spi) => spi,
-		Err(e) => return Err(format!("Fail to open driver: {}", e.to_string()))
-	};
-    let options = SpidevOptions::new()
-         .bits_per_word(8)
-         .max_speed_hz(config.speed)
-		 .mode(SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_3WIRE | SpiModeFlags::SPI_NO_CS)
-         .build();
-    spi.configure(&options).unwrap();
-	let mut pin_cs = SysFsGpioOutput::open(config.pin_cs).unwrap();
-	pin_cs.set_value(CS_NORMAL).unwrap();
+	let mut panel = LedPanel::open(config)?;
+	if config.readback {
+		let readback = panel.check_chain()?;
+		check_readback(&readback, config.chain)?;
+		println!("Chain of {} registers passes data", config.chain);
+	}
 	println!("Leds on panel should be filling");
 	let exiter = utils::Exiter::new();
-	let mut buf: [u8;2] = [0;2];
-	let mut pos: usize = 0;
+	let mut pos = 0;
+	while !exiter.check() {
+		panel.update(|s| {
+			for bit in 0..s.bits() {
+				s.set_bit(bit, bit < pos);
+			}
+			Ok(())
+		})?;
+		thread::sleep(Duration::from_millis(config.led_delay as u64));
+		pos = (pos + 1) % (panel.state().bits() + 1);
+	}
+	panel.clear_all()?;
+	if panel.has_dimmer() {
+		test_brightness(&mut panel)?;
+	}
+	if !config.indicators.is_empty() {
+		test_indicators(config, &mut panel)?;
+	}
+	if !config.digits.is_empty() || !config.bars.is_empty() {
+		test_displays(config, panel)?;
+	}
+	Ok(())
+}
+
+/// Step brightness with all outputs on
+fn test_brightness(panel: &mut LedPanel) -> Result<(), String> {
+	println!("All leds should be on, brightness changes by keys");
+	panel.update(|s| {
+		(0..s.bits()).for_each(|bit| s.set_bit(bit, true));
+		Ok(())
+	})?;
+	let ctl = utils::InController::new(&["u - brighter", "d - dimmer"]);
 	loop {
-		if exiter.check() {
-			break;
+		let percent = match ctl.get() {
+			'q' => break,
+			'u' => panel.brightness().saturating_add(BRIGHTNESS_STEP),
+			'd' => panel.brightness().saturating_sub(BRIGHTNESS_STEP),
+			_ => {
+				println!("Unknown command");
+				continue;
+			}
+		};
+		panel.set_brightness(percent)?;
+		println!("\tBrightness: {}%", panel.brightness());
+	}
+	panel.clear_all()
+}
+
+/// Toggle indicators by keys
+fn test_indicators(config: &LedpanelConfig, panel: &mut LedPanel) -> Result<(), String> {
+	let names: Vec<&str> = config.indicators.iter().map(|i| i.name.as_str()).collect();
+	let mut map: Vec<String> = names.iter().zip(TEST_KEYS.chars())
+		.map(|(name, key)| format!("{} - toggle '{}'", key, name))
+		.collect();
+	map.push(String::from("+ - turn all indicators on"));
+	map.push(String::from("- - turn all indicators off"));
+	let map: Vec<&str> = map.iter().map(String::as_str).collect();
+	let ctl = utils::InController::new(&map);
+	loop {
+		match ctl.get() {
+			'q' => break,
+			'+' => panel.update(|s| names.iter().try_for_each(|name| s.set(name, true)))?,
+			'-' => panel.update(|s| names.iter().try_for_each(|name| s.set(name, false)))?,
+			c => match TEST_KEYS.find(c).and_then(|i| names.get(i)) {
+				Some(name) => {
+					panel.toggle(name)?;
+					println!("\t{}: {}", name, if panel.state().get(name)? { "on" } else { "off" });
+				},
+				None => println!("Unknown command")
+			}
 		}
-		show_buf(&mut spi, &mut pin_cs, &buf);
+	}
+	panel.clear_all()
+}
+
+/// Count on digits and sweep bar graphs, every second ten values blink
+fn test_displays(config: &LedpanelConfig, panel: LedPanel) -> Result<(), String> {
+	println!("All segments of digits should light, then digits should count with point moving,");
+	println!("bar graphs should rise, every second ten values blink");
+	let refresh = PanelRefresh::new(panel);
+	refresh.update(|s| {
+		config.digits.iter().try_for_each(|dig| s.show_text(&dig.name, &"8.".repeat(dig.digits.len())))
+	})?;
+	thread::sleep(ALL_SEGMENTS_DELAY);
+	let exiter = utils::Exiter::new();
+	let mut n: u32 = 0;
+	while !exiter.check() {
+		refresh.update(|s| {
+			for dig in &config.digits {
+				let len = dig.digits.len() as u32;
+				let value = n % 10u32.pow(len.min(9));
+				s.show_number(&dig.name, value as i64, n / 10 % len)?;
+				s.set_blink(&dig.name, n / 10 % 2 == 1)?;
+			}
+			for bar in &config.bars {
+				let len = bar.bits.len() as u32;
+				s.show_level(&bar.name, n % (len + 1), len)?;
+				s.set_blink(&bar.name, n / 10 % 2 == 1)?;
+			}
+			Ok(())
+		})?;
 		thread::sleep(Duration::from_millis(config.led_delay as u64));
-		buf.fill(0);
-		for i in 0..pos {
-			buf[i/8] |= 1 >> (i % 8);
-		}
-		pos += 1;
-		if pos >= 16 {
-			pos = 0;
+		n += 1;
+	}
+	refresh.update(|s| {
+		s.clear_all();
+		Ok(())
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn indicator(name: &str, bit: u32) -> IndicatorConfig {
+		IndicatorConfig { name: name.to_string(), bit }
+	}
+
+	#[test]
+	fn indicators_map_to_chain_bits() {
+		let mut state = PanelState::new(2, &[indicator("insert_money", 0), indicator("program_1", 9)], &[], &[]).unwrap();
+		state.set("insert_money", true).unwrap();
+		state.set("program_1", true).unwrap();
+		// second register is shifted first
+		assert_eq!(state.shift_data(), vec![0x02, 0x01]);
+		state.set("insert_money", false).unwrap();
+		assert_eq!(state.shift_data(), vec![0x02, 0x00]);
+		assert!(state.set("program_9", true).is_err());
+		assert!(PanelState::new(1, &[indicator("far", 8)], &[], &[]).is_err());
+		assert!(PanelState::new(2, &[indicator("a", 1), indicator("a", 2)], &[], &[]).is_err());
+	}
+
+	#[test]
+	fn digits_bars_and_blinking() {
+		let digits = DigitsConfig { name: String::from("balance"), digits: vec![[0, 1, 2, 3, 4, 5, 6, 7], [8, 9, 10, 11, 12, 13, 14, 15]] };
+		let bar = BarConfig { name: String::from("time"), bits: vec![16, 17, 18, 19] };
+		let mut state = PanelState::new(3, &[], &[digits], &[bar]).unwrap();
+		state.show_number("balance", 17, 1).unwrap();
+		state.show_level("time", 2, 4).unwrap();
+		assert_eq!(state.shift_data(), vec![0x03, 0x07, 0x86]);
+		state.set_blink("time", true).unwrap();
+		state.blink_off = true;
+		assert_eq!(state.shift_data(), vec![0x00, 0x07, 0x86]);
+		assert!(state.show_number("balance", 100, 0).is_err());
+	}
+
+	/// Received data of chain `delay` bits long, holding `old` bits before the transfer
+	fn chain_output(tx: &[u8], delay: usize, old: bool) -> Vec<u8> {
+		let mut rx = vec![0; tx.len()];
+		for i in 0..tx.len() * 8 {
+			let bit = if i < delay { old } else { get_bit(tx, i - delay) };
+			if bit {
+				rx[i / 8] |= 0x80 >> (i % 8);
+			}
 		}
+		rx
 	}
-	Ok(())
-}
\ No newline at end of file
+
+	#[test]
+	fn readback_measures_chain_length() {
+		let lead = 2 + CHECK_EXTRA_REGISTERS;
+		let tx = check_frame(&CHECK_PATTERN, lead);
+		let rx = chain_output(&tx, 16, true);
+		assert_eq!(analyze_readback(&CHECK_PATTERN, lead, &rx), Readback::Delay(16));
+		assert!(check_readback(&Readback::Delay(16), 2).is_ok());
+		let short = analyze_readback(&CHECK_PATTERN, lead, &chain_output(&tx, 8, false));
+		assert!(check_readback(&short, 2).unwrap_err().contains("1 registers missing"));
+		assert_eq!(analyze_readback(&CHECK_PATTERN, lead, &vec![0xFF; tx.len()]), Readback::Stuck(true));
+		assert_eq!(analyze_readback(&CHECK_PATTERN, lead, &chain_output(&tx, 9, false)), Readback::Delay(9));
+	}
+}
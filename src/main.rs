@@ -1,16 +1,43 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 
+mod brightness;
+mod eeprom;
+mod eeprom_dev;
+mod expander;
 mod extbus;
+mod font;
+mod framebuffer;
+mod frameformat;
+mod gpioline;
+#[cfg(test)]
+mod gpiomock;
+mod i2cbus;
+#[cfg(test)]
+mod i2cmock;
+mod i2cscan;
+mod idstore;
 mod intio;
 mod iobus;
 mod ledmatrix;
+mod ledmatrix_diag;
+mod ledmatrix_emu;
 mod ledpanel;
+mod modbus;
+mod modbus_sim;
+mod outsched;
+mod picture;
+mod pulse;
+mod scene;
+mod segment;
+mod sensor;
+mod sink;
 mod ccnet;
 mod ccnet_dev;
 mod cctalk_dev;
 mod wiegand;
 mod wiegand_dev;
 mod terminal;
+mod text;
 mod utils;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
@@ -19,6 +46,7 @@ enum Module {
     Extbus,
     Intio,
     Iobus,
+    IobusScan,
     Ledmatrix,
     Ledpanel,
     Ccnet,
@@ -27,6 +55,136 @@ enum Module {
     Rfid
 }
 
+#[derive(Subcommand, Debug)]
+enum EepromAction {
+    /// Print latest record
+    Read,
+    /// Write record to next slot, unset fields keep stored values
+    Write {
+        #[arg(long)]
+        serial: Option<String>,
+        #[arg(long)]
+        hw_rev: Option<u16>,
+        #[arg(long)]
+        money: Option<u64>,
+        #[arg(long)]
+        cycles: Option<u32>,
+        #[arg(long)]
+        add_money: Option<u64>,
+        #[arg(long)]
+        add_cycles: Option<u32>
+    },
+    /// Check CRC of all record slots
+    Verify
+}
+
+#[derive(Subcommand, Debug)]
+enum LedmatrixAction {
+    /// Show text on display, "\n" in text starts new line
+    Text {
+        text: String,
+        /// Built-in font name or BDF/PCF file
+        #[arg(long, default_value_t = String::from(font::DEFAULT_FONT))]
+        font: String,
+        #[arg(long, value_enum, default_value_t = text::Align::Center)]
+        align: text::Align
+    },
+    /// Cycle scroll, countdown, blink and sequence scenes, print dropped frames
+    Demo {
+        /// Built-in font name or BDF/PCF file
+        #[arg(long, default_value_t = String::from(font::DEFAULT_FONT))]
+        font: String
+    },
+    /// Show PNG/BMP image, play animated GIF or pre-encoded frames file
+    Image {
+        path: String,
+        #[arg(long, value_enum, default_value_t = picture::Fit::Contain)]
+        fit: picture::Fit,
+        #[arg(long, value_enum, default_value_t = picture::Dither::FloydSteinberg)]
+        dither: picture::Dither,
+        /// Write converted frames to file instead of display
+        #[arg(long)]
+        save: Option<String>
+    },
+    /// List built-in fonts
+    Fonts,
+    /// Emulate display controller: receive frames on serial port or new pty and preview them
+    Emulate {
+        /// Serial port to listen on, new pty when omitted
+        #[arg(long)]
+        port: Option<String>,
+        /// Write frames as PNG instead of terminal preview, "{}" is replaced by frame number
+        #[arg(long)]
+        png: Option<String>
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum ExtbusAction {
+    /// Read coils, discrete inputs, holding or input registers
+    Read {
+        #[arg(value_enum)]
+        kind: extbus::RegisterKind,
+        addr: u16,
+        #[arg(default_value_t = 1)]
+        count: u16,
+        /// Slave address, `addr` of config when omitted
+        #[arg(long)]
+        slave: Option<u8>
+    },
+    /// Write coils or holding registers
+    Write {
+        #[arg(value_enum)]
+        kind: extbus::RegisterKind,
+        addr: u16,
+        #[arg(required = true)]
+        values: Vec<u16>,
+        /// Slave address, `addr` of config when omitted
+        #[arg(long)]
+        slave: Option<u8>,
+        /// Use multiple write function for single value
+        #[arg(long)]
+        multiple: bool
+    },
+    /// Write holding registers and read registers in one request
+    ReadWrite {
+        read_addr: u16,
+        read_count: u16,
+        write_addr: u16,
+        #[arg(required = true)]
+        values: Vec<u16>,
+        /// Slave address, `addr` of config when omitted
+        #[arg(long)]
+        slave: Option<u8>
+    },
+    /// Simulate slaves with register map from TOML file
+    Simulate {
+        map: String,
+        /// Serial port to answer on, new pty when omitted
+        #[arg(long)]
+        port: Option<String>
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Machine identity and lifetime counters in I2C EEPROM
+    Eeprom {
+        #[command(subcommand)]
+        action: EepromAction
+    },
+    /// Modbus requests on external bus
+    Extbus {
+        #[command(subcommand)]
+        action: ExtbusAction
+    },
+    /// Draw on LED matrix
+    Ledmatrix {
+        #[command(subcommand)]
+        action: LedmatrixAction
+    }
+}
+
 #[derive(Parser, Debug)]
 struct Mode {
     /// Specify module for test
@@ -35,11 +193,26 @@ struct Mode {
     
     /// Config path
     #[arg(short, long, default_value_t = String::from("./config.toml"))]
-    config: String
+    config: String,
+
+    /// Run only this part of intio test, all configured parts when omitted
+    #[arg(long, value_enum)]
+    intio: Option<intio::IntioMode>,
+
+    /// Let iobus scan read registers of chips at PCF857x addresses, a PCF there latches written bytes on outputs
+    #[arg(long)]
+    probe_registers: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>
 }
 
 fn print_test<T>(name: &str, config: &T, func: fn(&T) -> Result<(), String>) -> Result<(), ()> {
-    match func(config) {
+    print_result(name, func(config))
+}
+
+fn print_result(name: &str, result: Result<(), String>) -> Result<(), ()> {
+    match result {
         Ok(_) => {
             println!("Module '{}' tested ok", name);
             Ok(())
@@ -51,9 +224,59 @@ fn print_test<T>(name: &str, config: &T, func: fn(&T) -> Result<(), String>) ->
     }
 }
 
+fn run_command(config: &utils::Config, command: Command) -> Result<(), String> {
+    match command {
+        Command::Eeprom { action } => match action {
+            EepromAction::Read => eeprom_dev::read(&config.iobus),
+            EepromAction::Write { serial, hw_rev, money, cycles, add_money, add_cycles } => {
+                let upd = eeprom_dev::RecordUpdate { serial, hw_rev, money, cycles, add_money, add_cycles };
+                eeprom_dev::write(&config.iobus, &upd)
+            },
+            EepromAction::Verify => eeprom_dev::verify(&config.iobus)
+        },
+        Command::Extbus { action } => match action {
+            ExtbusAction::Read { kind, addr, count, slave } => extbus::read(&config.extbus, slave, kind, addr, count),
+            ExtbusAction::Write { kind, addr, values, slave, multiple } => {
+                extbus::write(&config.extbus, slave, kind, addr, &values, multiple)
+            },
+            ExtbusAction::ReadWrite { read_addr, read_count, write_addr, values, slave } => {
+                extbus::read_write(&config.extbus, slave, read_addr, read_count, write_addr, &values)
+            },
+            ExtbusAction::Simulate { map, port } => modbus_sim::run(&map, port.as_deref())
+        },
+        Command::Ledmatrix { action } => match action {
+            LedmatrixAction::Text { text, font, align } => {
+                ledmatrix::show_text(&config.ledmatrix, &text.replace("\\n", "\n"), &font, align)
+            },
+            LedmatrixAction::Demo { font } => ledmatrix::demo(&config.ledmatrix, &font),
+            LedmatrixAction::Image { path, fit, dither, save } => {
+                ledmatrix::show_image(&config.ledmatrix, &path, fit, dither, save.as_deref())
+            },
+            LedmatrixAction::Fonts => {
+                for name in font::Font::builtin_names() {
+                    println!("{}", name);
+                }
+                Ok(())
+            },
+            LedmatrixAction::Emulate { port, png } => {
+                ledmatrix_emu::run(&config.ledmatrix, port.as_deref(), png.as_deref())
+            }
+        }
+    }
+}
+
 fn main() -> Result<(), ()> {
     let mode = Mode::parse();
     let config = utils::parse_config(&mode.config);
+    if let Some(command) = mode.command {
+        return match run_command(&config, command) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                println!("Command fail: {}", e);
+                Err(())
+            }
+        };
+    }
     match mode.module {
         Module::All => {
             print_test("Internal IO", &config.intio, intio::test)?;
@@ -66,9 +289,13 @@ fn main() -> Result<(), ()> {
             print_test("Terminal", &config.terminal, terminal::test)?;
             Ok(())
         },
-        Module::Extbus => Err(()),
-        Module::Intio => print_test("Internal IO", &config.intio, intio::test),
+        Module::Extbus => print_test("Extbus", &config.extbus, extbus::test),
+        Module::Intio => match mode.intio {
+            Some(part) => print_result("Internal IO", intio::test_mode(&config.intio, part)),
+            None => print_test("Internal IO", &config.intio, intio::test)
+        },
         Module::Iobus => print_test("IO Bus", &config.iobus, iobus::test),
+        Module::IobusScan => print_result("IO Bus scan", iobus::scan(&config.iobus, mode.probe_registers)),
         Module::Ledmatrix => print_test("Ledmatrix", &config.ledmatrix, ledmatrix::test),
         Module::Ledpanel => print_test("Ledpanel", &config.ledpanel, ledpanel::test),
         Module::Rfid => print_test("Rfid", &config.rfid, wiegand_dev::test),
@@ -0,0 +1,461 @@
+use std::fmt;
+use std::time::Duration;
+
+pub mod function {
+	pub const READ_COILS: u8 = 0x01;
+	pub const READ_DISCRETE_INPUTS: u8 = 0x02;
+	pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+	pub const READ_INPUT_REGISTERS: u8 = 0x04;
+	pub const WRITE_SINGLE_COIL: u8 = 0x05;
+	pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+	pub const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+	pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+	pub const READ_WRITE_MULTIPLE_REGISTERS: u8 = 0x17;
+}
+
+pub mod exception {
+	pub const ILLEGAL_FUNCTION: u8 = 0x01;
+	pub const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+	pub const ILLEGAL_DATA_VALUE: u8 = 0x03;
+	pub const SERVER_DEVICE_FAILURE: u8 = 0x04;
+	pub const ACKNOWLEDGE: u8 = 0x05;
+	pub const SERVER_DEVICE_BUSY: u8 = 0x06;
+}
+
+/// Function code of exception response has this bit set
+pub const EXCEPTION_FLAG: u8 = 0x80;
+/// Requests to this address are executed by all slaves without response
+pub const BROADCAST: u8 = 0;
+pub const MAX_ADU_SIZE: usize = 256;
+
+pub const MAX_READ_BITS: u16 = 2000;
+pub const MAX_READ_REGISTERS: u16 = 125;
+pub const MAX_WRITE_BITS: u16 = 1968;
+pub const MAX_WRITE_REGISTERS: u16 = 123;
+pub const MAX_RW_WRITE_REGISTERS: u16 = 121;
+
+const COIL_ON: u16 = 0xFF00;
+const COIL_OFF: u16 = 0x0000;
+
+/// Bits of one character on the line: start, 8 data, parity or second stop, stop
+const CHAR_BITS: u64 = 11;
+/// Above this speed frame gap is fixed
+const FIXED_GAP_BAUDRATE: u32 = 19200;
+const FIXED_FRAME_GAP: Duration = Duration::from_micros(1750);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	Io(String),
+	Timeout,
+	Crc { expected: u16, received: u16 },
+	/// Malformed or unexpected frame
+	Frame(String),
+	/// Slave answered with exception code
+	Exception(u8),
+	InvalidRequest(String)
+}
+
+fn exception_name(code: u8) -> &'static str {
+	match code {
+		exception::ILLEGAL_FUNCTION => "illegal function",
+		exception::ILLEGAL_DATA_ADDRESS => "illegal data address",
+		exception::ILLEGAL_DATA_VALUE => "illegal data value",
+		exception::SERVER_DEVICE_FAILURE => "server device failure",
+		exception::ACKNOWLEDGE => "acknowledge",
+		exception::SERVER_DEVICE_BUSY => "server device busy",
+		_ => "unknown"
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "IO error: {}", e),
+			Error::Timeout => write!(f, "No response"),
+			Error::Crc { expected, received } => write!(f, "CRC 0x{:04X}, expected 0x{:04X}", received, expected),
+			Error::Frame(e) => write!(f, "Bad frame: {}", e),
+			Error::Exception(code) => write!(f, "Exception 0x{:02X} ({})", code, exception_name(*code)),
+			Error::InvalidRequest(e) => write!(f, "Invalid request: {}", e)
+		}
+	}
+}
+
+/// CRC-16/MODBUS, sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+	let mut crc: u16 = 0xFFFF;
+	for b in data {
+		crc ^= *b as u16;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { crc >> 1 ^ 0xA001 } else { crc >> 1 };
+		}
+	}
+	crc
+}
+
+/// RTU frame: address, PDU, CRC
+pub fn frame(addr: u8, pdu: &[u8]) -> Vec<u8> {
+	let mut adu = Vec::with_capacity(pdu.len() + 3);
+	adu.push(addr);
+	adu.extend_from_slice(pdu);
+	adu.extend_from_slice(&crc16(&adu).to_le_bytes());
+	adu
+}
+
+/// Check CRC of RTU frame, returns address and PDU
+pub fn unframe(adu: &[u8]) -> Result<(u8, &[u8]), Error> {
+	if adu.len() < 4 {
+		return Err(Error::Frame(format!("{} bytes are too short", adu.len())));
+	}
+	let (body, crc) = adu.split_at(adu.len() - 2);
+	let expected = crc16(body);
+	let received = u16::from_le_bytes([crc[0], crc[1]]);
+	if expected != received {
+		return Err(Error::Crc { expected, received });
+	}
+	Ok((body[0], &body[1..]))
+}
+
+pub fn char_time(baudrate: u32) -> Duration {
+	Duration::from_nanos(CHAR_BITS * 1_000_000_000 / baudrate.max(1) as u64)
+}
+
+/// Silence of 3.5 characters which separates frames
+pub fn frame_gap(baudrate: u32) -> Duration {
+	if baudrate > FIXED_GAP_BAUDRATE {
+		FIXED_FRAME_GAP
+	} else {
+		char_time(baudrate) * 7 / 2
+	}
+}
+
+/// Length of response frame from its first 3 bytes, `None` for unknown function
+pub fn response_len(head: &[u8]) -> Option<usize> {
+	match head[1] {
+		f if f & EXCEPTION_FLAG != 0 => Some(5),
+		function::READ_COILS | function::READ_DISCRETE_INPUTS | function::READ_HOLDING_REGISTERS |
+		function::READ_INPUT_REGISTERS | function::READ_WRITE_MULTIPLE_REGISTERS => Some(5 + head[2] as usize),
+		function::WRITE_SINGLE_COIL | function::WRITE_SINGLE_REGISTER |
+		function::WRITE_MULTIPLE_COILS | function::WRITE_MULTIPLE_REGISTERS => Some(8),
+		_ => None
+	}
+}
+
+pub fn pack_bits(bits: &[bool]) -> Vec<u8> {
+	let mut bytes = vec![0; bits.len().div_ceil(8)];
+	for (i, _) in bits.iter().enumerate().filter(|(_, b)| **b) {
+		bytes[i / 8] |= 1 << (i % 8);
+	}
+	bytes
+}
+
+pub fn unpack_bits(bytes: &[u8], count: usize) -> Vec<bool> {
+	(0..count).map(|i| bytes[i / 8] & 1 << (i % 8) != 0).collect()
+}
+
+fn push_u16(pdu: &mut Vec<u8>, value: u16) {
+	pdu.extend_from_slice(&value.to_be_bytes());
+}
+
+fn get_u16(pdu: &[u8], at: usize) -> u16 {
+	u16::from_be_bytes([pdu[at], pdu[at + 1]])
+}
+
+fn registers(data: &[u8]) -> Vec<u16> {
+	data.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+	ReadCoils { addr: u16, count: u16 },
+	ReadDiscreteInputs { addr: u16, count: u16 },
+	ReadHoldingRegisters { addr: u16, count: u16 },
+	ReadInputRegisters { addr: u16, count: u16 },
+	WriteSingleCoil { addr: u16, value: bool },
+	WriteSingleRegister { addr: u16, value: u16 },
+	WriteMultipleCoils { addr: u16, values: Vec<bool> },
+	WriteMultipleRegisters { addr: u16, values: Vec<u16> },
+	ReadWriteMultipleRegisters { read_addr: u16, read_count: u16, write_addr: u16, values: Vec<u16> }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+	Bits(Vec<bool>),
+	Registers(Vec<u16>),
+	/// Write is confirmed
+	Written
+}
+
+fn check_count(what: &str, count: usize, max: u16) -> Result<(), Error> {
+	if count == 0 || count > max as usize {
+		return Err(Error::InvalidRequest(format!("{} {} is out of 1..={}", count, what, max)));
+	}
+	Ok(())
+}
+
+impl Request {
+	pub fn function(&self) -> u8 {
+		match self {
+			Request::ReadCoils { .. } => function::READ_COILS,
+			Request::ReadDiscreteInputs { .. } => function::READ_DISCRETE_INPUTS,
+			Request::ReadHoldingRegisters { .. } => function::READ_HOLDING_REGISTERS,
+			Request::ReadInputRegisters { .. } => function::READ_INPUT_REGISTERS,
+			Request::WriteSingleCoil { .. } => function::WRITE_SINGLE_COIL,
+			Request::WriteSingleRegister { .. } => function::WRITE_SINGLE_REGISTER,
+			Request::WriteMultipleCoils { .. } => function::WRITE_MULTIPLE_COILS,
+			Request::WriteMultipleRegisters { .. } => function::WRITE_MULTIPLE_REGISTERS,
+			Request::ReadWriteMultipleRegisters { .. } => function::READ_WRITE_MULTIPLE_REGISTERS
+		}
+	}
+
+	/// Only writes may be broadcast
+	pub fn is_write(&self) -> bool {
+		matches!(self, Request::WriteSingleCoil { .. } | Request::WriteSingleRegister { .. } |
+			Request::WriteMultipleCoils { .. } | Request::WriteMultipleRegisters { .. })
+	}
+
+	/// Check quantities against protocol limits
+	pub fn validate(&self) -> Result<(), Error> {
+		match self {
+			Request::ReadCoils { count, .. } | Request::ReadDiscreteInputs { count, .. } => {
+				check_count("bits", *count as usize, MAX_READ_BITS)
+			},
+			Request::ReadHoldingRegisters { count, .. } | Request::ReadInputRegisters { count, .. } => {
+				check_count("registers", *count as usize, MAX_READ_REGISTERS)
+			},
+			Request::WriteSingleCoil { .. } | Request::WriteSingleRegister { .. } => Ok(()),
+			Request::WriteMultipleCoils { values, .. } => check_count("bits", values.len(), MAX_WRITE_BITS),
+			Request::WriteMultipleRegisters { values, .. } => check_count("registers", values.len(), MAX_WRITE_REGISTERS),
+			Request::ReadWriteMultipleRegisters { read_count, values, .. } => {
+				check_count("registers", *read_count as usize, MAX_READ_REGISTERS)?;
+				check_count("registers", values.len(), MAX_RW_WRITE_REGISTERS)
+			}
+		}
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut pdu = vec![self.function()];
+		match self {
+			Request::ReadCoils { addr, count } | Request::ReadDiscreteInputs { addr, count } |
+			Request::ReadHoldingRegisters { addr, count } | Request::ReadInputRegisters { addr, count } => {
+				push_u16(&mut pdu, *addr);
+				push_u16(&mut pdu, *count);
+			},
+			Request::WriteSingleCoil { addr, value } => {
+				push_u16(&mut pdu, *addr);
+				push_u16(&mut pdu, if *value { COIL_ON } else { COIL_OFF });
+			},
+			Request::WriteSingleRegister { addr, value } => {
+				push_u16(&mut pdu, *addr);
+				push_u16(&mut pdu, *value);
+			},
+			Request::WriteMultipleCoils { addr, values } => {
+				let bytes = pack_bits(values);
+				push_u16(&mut pdu, *addr);
+				push_u16(&mut pdu, values.len() as u16);
+				pdu.push(bytes.len() as u8);
+				pdu.extend_from_slice(&bytes);
+			},
+			Request::WriteMultipleRegisters { addr, values } => {
+				push_u16(&mut pdu, *addr);
+				push_u16(&mut pdu, values.len() as u16);
+				pdu.push(values.len() as u8 * 2);
+				values.iter().for_each(|v| push_u16(&mut pdu, *v));
+			},
+			Request::ReadWriteMultipleRegisters { read_addr, read_count, write_addr, values } => {
+				push_u16(&mut pdu, *read_addr);
+				push_u16(&mut pdu, *read_count);
+				push_u16(&mut pdu, *write_addr);
+				push_u16(&mut pdu, values.len() as u16);
+				pdu.push(values.len() as u8 * 2);
+				values.iter().for_each(|v| push_u16(&mut pdu, *v));
+			}
+		}
+		pdu
+	}
+
+	/// Decode request PDU received by slave, error is exception code to answer
+	pub fn decode(pdu: &[u8]) -> Result<Request, u8> {
+		let function = *pdu.first().ok_or(exception::ILLEGAL_FUNCTION)?;
+		let len_is = |len: usize| if pdu.len() == len { Ok(()) } else { Err(exception::ILLEGAL_DATA_VALUE) };
+		// multiple writes carry byte count of values after fixed part
+		let values = |at: usize, size: usize| -> Result<&[u8], u8> {
+			if pdu.len() <= at || pdu[at] as usize != size {
+				return Err(exception::ILLEGAL_DATA_VALUE);
+			}
+			len_is(at + 1 + size)?;
+			Ok(&pdu[at + 1..])
+		};
+		let req = match function {
+			function::READ_COILS | function::READ_DISCRETE_INPUTS |
+			function::READ_HOLDING_REGISTERS | function::READ_INPUT_REGISTERS => {
+				len_is(5)?;
+				let (addr, count) = (get_u16(pdu, 1), get_u16(pdu, 3));
+				match function {
+					function::READ_COILS => Request::ReadCoils { addr, count },
+					function::READ_DISCRETE_INPUTS => Request::ReadDiscreteInputs { addr, count },
+					function::READ_HOLDING_REGISTERS => Request::ReadHoldingRegisters { addr, count },
+					_ => Request::ReadInputRegisters { addr, count }
+				}
+			},
+			function::WRITE_SINGLE_COIL => {
+				len_is(5)?;
+				let value = match get_u16(pdu, 3) {
+					COIL_ON => true,
+					COIL_OFF => false,
+					_ => return Err(exception::ILLEGAL_DATA_VALUE)
+				};
+				Request::WriteSingleCoil { addr: get_u16(pdu, 1), value }
+			},
+			function::WRITE_SINGLE_REGISTER => {
+				len_is(5)?;
+				Request::WriteSingleRegister { addr: get_u16(pdu, 1), value: get_u16(pdu, 3) }
+			},
+			function::WRITE_MULTIPLE_COILS if pdu.len() > 5 => {
+				let count = get_u16(pdu, 3) as usize;
+				let data = values(5, count.div_ceil(8))?;
+				Request::WriteMultipleCoils { addr: get_u16(pdu, 1), values: unpack_bits(data, count) }
+			},
+			function::WRITE_MULTIPLE_REGISTERS if pdu.len() > 5 => {
+				let count = get_u16(pdu, 3) as usize;
+				let data = values(5, count * 2)?;
+				Request::WriteMultipleRegisters { addr: get_u16(pdu, 1), values: registers(data) }
+			},
+			function::READ_WRITE_MULTIPLE_REGISTERS if pdu.len() > 9 => {
+				let count = get_u16(pdu, 7) as usize;
+				let data = values(9, count * 2)?;
+				Request::ReadWriteMultipleRegisters {
+					read_addr: get_u16(pdu, 1),
+					read_count: get_u16(pdu, 3),
+					write_addr: get_u16(pdu, 5),
+					values: registers(data)
+				}
+			},
+			function::WRITE_MULTIPLE_COILS | function::WRITE_MULTIPLE_REGISTERS |
+			function::READ_WRITE_MULTIPLE_REGISTERS => return Err(exception::ILLEGAL_DATA_VALUE),
+			_ => return Err(exception::ILLEGAL_FUNCTION)
+		};
+		req.validate().map_err(|_| exception::ILLEGAL_DATA_VALUE)?;
+		Ok(req)
+	}
+
+	/// Response PDU of slave which executed this request
+	pub fn encode_response(&self, resp: &Response) -> Vec<u8> {
+		let mut pdu = vec![self.function()];
+		match resp {
+			Response::Bits(bits) => {
+				let bytes = pack_bits(bits);
+				pdu.push(bytes.len() as u8);
+				pdu.extend_from_slice(&bytes);
+			},
+			Response::Registers(regs) => {
+				pdu.push(regs.len() as u8 * 2);
+				regs.iter().for_each(|v| push_u16(&mut pdu, *v));
+			},
+			Response::Written => pdu = self.encode()[..5].to_vec()
+		}
+		pdu
+	}
+
+	/// Decode response PDU to this request, exception response becomes `Error::Exception`
+	pub fn decode_response(&self, pdu: &[u8]) -> Result<Response, Error> {
+		let function = self.function();
+		match pdu.first() {
+			Some(f) if *f == function | EXCEPTION_FLAG && pdu.len() == 2 => return Err(Error::Exception(pdu[1])),
+			Some(f) if *f == function => (),
+			Some(f) => return Err(Error::Frame(format!("Function 0x{:02X} in response to 0x{:02X}", f, function))),
+			None => return Err(Error::Frame(String::from("Empty response")))
+		}
+		let sized = |data_len: usize| -> Result<&[u8], Error> {
+			if pdu.len() < 2 || pdu[1] as usize != data_len || pdu.len() != data_len + 2 {
+				return Err(Error::Frame(format!("Response of {} bytes, expected {} data bytes", pdu.len(), data_len)));
+			}
+			Ok(&pdu[2..])
+		};
+		match self {
+			Request::ReadCoils { count, .. } | Request::ReadDiscreteInputs { count, .. } => {
+				let data = sized((*count as usize).div_ceil(8))?;
+				Ok(Response::Bits(unpack_bits(data, *count as usize)))
+			},
+			Request::ReadHoldingRegisters { count, .. } | Request::ReadInputRegisters { count, .. } |
+			Request::ReadWriteMultipleRegisters { read_count: count, .. } => {
+				Ok(Response::Registers(registers(sized(*count as usize * 2)?)))
+			},
+			_ => {
+				// writes echo address and value or quantity
+				let request = self.encode();
+				if pdu.len() != 5 || pdu[..5] != request[..5] {
+					return Err(Error::Frame(String::from("Write confirmation does not match request")));
+				}
+				Ok(Response::Written)
+			}
+		}
+	}
+}
+
+/// Exception response PDU
+pub fn exception_response(function: u8, code: u8) -> Vec<u8> {
+	vec![function | EXCEPTION_FLAG, code]
+}
+
+/// Length of request frame from its head, `None` while more bytes are needed to know it
+pub fn request_len(head: &[u8]) -> Option<usize> {
+	match *head.get(1)? {
+		function::WRITE_MULTIPLE_COILS | function::WRITE_MULTIPLE_REGISTERS => head.get(6).map(|n| 9 + *n as usize),
+		function::READ_WRITE_MULTIPLE_REGISTERS => head.get(10).map(|n| 13 + *n as usize),
+		_ => Some(8)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn crc_and_framing() {
+		let req = Request::ReadHoldingRegisters { addr: 0, count: 10 };
+		let adu = frame(1, &req.encode());
+		assert_eq!(adu, vec![0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]);
+		assert_eq!(unframe(&adu).unwrap(), (1, &adu[1..6]));
+		let mut bad = adu.clone();
+		bad[3] ^= 1;
+		assert!(matches!(unframe(&bad), Err(Error::Crc { .. })));
+		assert_eq!(frame_gap(9600), Duration::from_nanos(4010415));
+		assert_eq!(frame_gap(115200), FIXED_FRAME_GAP);
+	}
+
+	#[test]
+	fn responses_are_decoded() {
+		let req = Request::ReadCoils { addr: 19, count: 10 };
+		assert_eq!(req.decode_response(&[0x01, 0x02, 0xCD, 0x01]).unwrap(),
+			Response::Bits(vec![true, false, true, true, false, false, true, true, true, false]));
+		assert_eq!(req.decode_response(&[0x81, 0x02]), Err(Error::Exception(exception::ILLEGAL_DATA_ADDRESS)));
+		let req = Request::WriteMultipleRegisters { addr: 1, values: vec![0x000A, 0x0102] };
+		assert_eq!(req.encode(), vec![0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02]);
+		assert_eq!(req.decode_response(&[0x10, 0x00, 0x01, 0x00, 0x02]).unwrap(), Response::Written);
+		assert!(req.decode_response(&[0x10, 0x00, 0x01, 0x00, 0x03]).is_err());
+		assert!(Request::ReadInputRegisters { addr: 0, count: 126 }.validate().is_err());
+		assert_eq!(response_len(&[1, 0x83, 2]), Some(5));
+		assert_eq!(response_len(&[1, 0x17, 6]), Some(11));
+	}
+
+	#[test]
+	fn requests_are_decoded_by_slave() {
+		let reqs = [
+			Request::ReadDiscreteInputs { addr: 7, count: 3 },
+			Request::WriteSingleCoil { addr: 2, value: true },
+			Request::WriteMultipleCoils { addr: 20, values: vec![true, false, true, true, false, false, true, true, true, false] },
+			Request::ReadWriteMultipleRegisters { read_addr: 3, read_count: 6, write_addr: 14, values: vec![0x00FF, 0x00FF, 0x00FF] }
+		];
+		for req in reqs {
+			let adu = frame(1, &req.encode());
+			assert_eq!(request_len(&adu), Some(adu.len()));
+			assert_eq!(Request::decode(&adu[1..adu.len() - 2]), Ok(req));
+		}
+		assert_eq!(Request::decode(&[0x05, 0x00, 0x02, 0x12, 0x34]), Err(exception::ILLEGAL_DATA_VALUE));
+		assert_eq!(Request::decode(&[0x10, 0x00, 0x01, 0x00, 0x02, 0x02, 0x00, 0x0A]), Err(exception::ILLEGAL_DATA_VALUE));
+		assert_eq!(Request::decode(&[0x2B, 0x0E]), Err(exception::ILLEGAL_FUNCTION));
+		let req = Request::ReadHoldingRegisters { addr: 0, count: 2 };
+		let pdu = req.encode_response(&Response::Registers(vec![0x022B, 7]));
+		assert_eq!(req.decode_response(&pdu), Ok(Response::Registers(vec![0x022B, 7])));
+	}
+}
@@ -0,0 +1,513 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serialport::{SerialPort, TTYPort};
+
+use crate::extbus::{Parity, RegisterKind};
+use crate::modbus::{self, exception, Request, Response};
+use crate::utils;
+
+const READ_TIMEOUT: Duration = Duration::from_millis(5);
+/// Unfinished request is dropped after this silence. USB adapters deliver
+/// frames in chunks, so 3.5 characters are too short for it.
+const INCOMPLETE_TIMEOUT: Duration = Duration::from_millis(50);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BitsConfig {
+	addr: u16,
+	values: Vec<bool>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WordsConfig {
+	addr: u16,
+	values: Vec<u16>
+}
+
+/// Values set at `at_ms` after start, bits are set by non-zero values
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScriptStep {
+	at_ms: u64,
+	kind: RegisterKind,
+	addr: u16,
+	values: Vec<u16>
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+	/// Request is dropped without response
+	Timeout,
+	/// Response is sent with broken CRC
+	Crc,
+	/// Exception code is sent instead of response
+	Exception(u8)
+}
+
+fn default_every() -> u32 { 1 }
+fn default_baudrate() -> u32 { 9600 }
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FaultConfig {
+	fault: Fault,
+	/// Only requests with this function code, any when omitted
+	function: Option<u8>,
+	/// Inject into every n-th matching request
+	#[serde(default = "default_every")]
+	every: u32,
+	/// Number of injections, unlimited when omitted
+	count: Option<u32>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SlaveConfig {
+	addr: u8,
+	#[serde(default)]
+	coils: Vec<BitsConfig>,
+	#[serde(default)]
+	discrete: Vec<BitsConfig>,
+	#[serde(default)]
+	holding: Vec<WordsConfig>,
+	#[serde(default)]
+	input: Vec<WordsConfig>,
+	#[serde(default)]
+	script: Vec<ScriptStep>,
+	/// Script restarts with this period, runs once when omitted
+	loop_ms: Option<u64>,
+	#[serde(default)]
+	faults: Vec<FaultConfig>
+}
+
+/// Register map of simulated slaves
+#[derive(Deserialize, Debug, Clone)]
+pub struct SimConfig {
+	#[serde(default = "default_baudrate")]
+	baudrate: u32,
+	#[serde(default)]
+	parity: Parity,
+	slaves: Vec<SlaveConfig>
+}
+
+impl SimConfig {
+	pub fn parse(text: &str) -> Result<Self, String> {
+		toml::from_str(text).map_err(|e| format!("Fail to parse register map: {}", e))
+	}
+
+	pub fn load(path: &str) -> Result<Self, String> {
+		let text = fs::read_to_string(path).map_err(|e| format!("Fail to read {}: {}", path, e))?;
+		Self::parse(&text)
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SimStats {
+	pub requests: u64,
+	pub responses: u64,
+	/// Frames with bad CRC or dropped unfinished
+	pub broken: u64,
+	pub faults: u64
+}
+
+struct FaultState {
+	cfg: FaultConfig,
+	seen: u32,
+	injected: u32
+}
+
+struct Slave {
+	addr: u8,
+	coils: BTreeMap<u16, bool>,
+	discrete: BTreeMap<u16, bool>,
+	holding: BTreeMap<u16, u16>,
+	input: BTreeMap<u16, u16>,
+	script: Vec<ScriptStep>,
+	loop_ms: Option<u64>,
+	next_step: usize,
+	script_start: Instant,
+	faults: Vec<FaultState>
+}
+
+/// Addresses of `count` registers from `addr`, error when they pass end of address space
+fn span(addr: u16, count: usize) -> Result<impl Iterator<Item = u16>, u8> {
+	if addr as usize + count > 0x10000 {
+		return Err(exception::ILLEGAL_DATA_ADDRESS);
+	}
+	Ok((0..count).map(move |i| (addr as usize + i) as u16))
+}
+
+fn read_map<T: Copy>(map: &BTreeMap<u16, T>, addr: u16, count: u16) -> Result<Vec<T>, u8> {
+	span(addr, count as usize)?
+		.map(|a| map.get(&a).copied().ok_or(exception::ILLEGAL_DATA_ADDRESS))
+		.collect()
+}
+
+/// All registers must exist, nothing is written otherwise
+fn write_map<T: Copy>(map: &mut BTreeMap<u16, T>, addr: u16, values: &[T]) -> Result<(), u8> {
+	if !span(addr, values.len())?.all(|a| map.contains_key(&a)) {
+		return Err(exception::ILLEGAL_DATA_ADDRESS);
+	}
+	span(addr, values.len())?.zip(values).for_each(|(a, v)| { map.insert(a, *v); });
+	Ok(())
+}
+
+fn fill<T: Copy>(map: &mut BTreeMap<u16, T>, addr: u16, values: &[T]) -> Result<(), String> {
+	let addrs = span(addr, values.len()).map_err(|_| format!("Values at {} pass end of address space", addr))?;
+	addrs.zip(values).for_each(|(a, v)| { map.insert(a, *v); });
+	Ok(())
+}
+
+impl Slave {
+	fn new(cfg: &SlaveConfig, start: Instant) -> Result<Self, String> {
+		if cfg.addr == modbus::BROADCAST || cfg.addr > 247 {
+			return Err(format!("Slave address {} is out of 1..=247", cfg.addr));
+		}
+		let mut script = cfg.script.clone();
+		script.sort_by_key(|s| s.at_ms);
+		if let Some(step) = script.iter().find(|s| span(s.addr, s.values.len()).is_err()) {
+			return Err(format!("Script values at {} pass end of address space", step.addr));
+		}
+		if let Some(period) = cfg.loop_ms {
+			if period == 0 || script.last().is_some_and(|s| s.at_ms >= period) {
+				return Err(format!("Script loop of slave {} must be longer than its last step", cfg.addr));
+			}
+		}
+		let mut slave = Self {
+			addr: cfg.addr,
+			coils: BTreeMap::new(),
+			discrete: BTreeMap::new(),
+			holding: BTreeMap::new(),
+			input: BTreeMap::new(),
+			script,
+			loop_ms: cfg.loop_ms,
+			next_step: 0,
+			script_start: start,
+			faults: cfg.faults.iter().map(|f| FaultState { cfg: f.clone(), seen: 0, injected: 0 }).collect()
+		};
+		for b in &cfg.coils {
+			fill(&mut slave.coils, b.addr, &b.values)?;
+		}
+		for b in &cfg.discrete {
+			fill(&mut slave.discrete, b.addr, &b.values)?;
+		}
+		for w in &cfg.holding {
+			fill(&mut slave.holding, w.addr, &w.values)?;
+		}
+		for w in &cfg.input {
+			fill(&mut slave.input, w.addr, &w.values)?;
+		}
+		Ok(slave)
+	}
+
+	fn set(&mut self, kind: RegisterKind, addr: u16, values: &[u16]) -> Result<(), String> {
+		let bits: Vec<bool> = values.iter().map(|v| *v != 0).collect();
+		match kind {
+			RegisterKind::Coil => fill(&mut self.coils, addr, &bits),
+			RegisterKind::Discrete => fill(&mut self.discrete, addr, &bits),
+			RegisterKind::Holding => fill(&mut self.holding, addr, values),
+			RegisterKind::Input => fill(&mut self.input, addr, values)
+		}
+	}
+
+	/// Apply script steps which are due
+	fn run_script(&mut self, now: Instant) {
+		loop {
+			let Some(step) = self.script.get(self.next_step) else {
+				match self.loop_ms {
+					Some(period) if now >= self.script_start + Duration::from_millis(period) => {
+						self.script_start += Duration::from_millis(period);
+						self.next_step = 0;
+						continue;
+					},
+					_ => return
+				}
+			};
+			if now < self.script_start + Duration::from_millis(step.at_ms) {
+				return;
+			}
+			let (kind, addr, values) = (step.kind, step.addr, step.values.clone());
+			// address space of steps is checked at load
+			let _ = self.set(kind, addr, &values);
+			self.next_step += 1;
+		}
+	}
+
+	/// Fault to inject into request with function code
+	fn fault(&mut self, function: u8) -> Option<Fault> {
+		for f in self.faults.iter_mut() {
+			if f.cfg.function.is_some_and(|fc| fc != function) || f.cfg.count.is_some_and(|c| f.injected >= c) {
+				continue;
+			}
+			f.seen += 1;
+			if f.seen.is_multiple_of(f.cfg.every.max(1)) {
+				f.injected += 1;
+				return Some(f.cfg.fault);
+			}
+		}
+		None
+	}
+
+	fn execute(&mut self, req: &Request) -> Result<Response, u8> {
+		match req {
+			Request::ReadCoils { addr, count } => read_map(&self.coils, *addr, *count).map(Response::Bits),
+			Request::ReadDiscreteInputs { addr, count } => read_map(&self.discrete, *addr, *count).map(Response::Bits),
+			Request::ReadHoldingRegisters { addr, count } => read_map(&self.holding, *addr, *count).map(Response::Registers),
+			Request::ReadInputRegisters { addr, count } => read_map(&self.input, *addr, *count).map(Response::Registers),
+			Request::WriteSingleCoil { addr, value } => write_map(&mut self.coils, *addr, &[*value]).map(|_| Response::Written),
+			Request::WriteSingleRegister { addr, value } => write_map(&mut self.holding, *addr, &[*value]).map(|_| Response::Written),
+			Request::WriteMultipleCoils { addr, values } => write_map(&mut self.coils, *addr, values).map(|_| Response::Written),
+			Request::WriteMultipleRegisters { addr, values } => write_map(&mut self.holding, *addr, values).map(|_| Response::Written),
+			Request::ReadWriteMultipleRegisters { read_addr, read_count, write_addr, values } => {
+				// read must fail before anything is written
+				read_map(&self.holding, *read_addr, *read_count)?;
+				write_map(&mut self.holding, *write_addr, values)?;
+				read_map(&self.holding, *read_addr, *read_count).map(Response::Registers)
+			}
+		}
+	}
+}
+
+/// Response frame to request frame, `None` when nothing is sent
+fn handle(slaves: &mut [Slave], adu: &[u8], stats: &mut SimStats, log: bool) -> Option<Vec<u8>> {
+	let (addr, pdu) = match modbus::unframe(adu) {
+		Ok(frame) => frame,
+		Err(e) => {
+			stats.broken += 1;
+			if log {
+				println!("\t{}", e);
+			}
+			return None;
+		}
+	};
+	stats.requests += 1;
+	let function = pdu[0];
+	let req = Request::decode(pdu);
+	if addr == modbus::BROADCAST {
+		match &req {
+			Ok(req) if req.is_write() => slaves.iter_mut().for_each(|s| { let _ = s.execute(req); }),
+			_ => ()
+		}
+		return None;
+	}
+	let slave = slaves.iter_mut().find(|s| s.addr == addr)?;
+	let fault = slave.fault(function);
+	let result = match fault {
+		Some(Fault::Timeout) => None,
+		Some(Fault::Exception(code)) => Some(Err(code)),
+		_ => Some(req.and_then(|r| slave.execute(&r).map(|resp| r.encode_response(&resp))))
+	};
+	if log {
+		let text = match (&result, fault) {
+			(_, Some(f)) => format!("fault {:?}", f),
+			(Some(Ok(_)), None) => String::from("ok"),
+			(Some(Err(code)), None) => modbus::Error::Exception(*code).to_string(),
+			(None, None) => String::new()
+		};
+		println!("\tslave {} function 0x{:02X}: {}", addr, function, text);
+	}
+	if fault.is_some() {
+		stats.faults += 1;
+	}
+	let pdu = match result? {
+		Ok(pdu) => pdu,
+		Err(code) => modbus::exception_response(function, code)
+	};
+	let mut resp = modbus::frame(addr, &pdu);
+	if fault == Some(Fault::Crc) {
+		*resp.last_mut().unwrap() ^= 0xFF;
+	}
+	stats.responses += 1;
+	Some(resp)
+}
+
+fn serve_handler(mut port: Box<dyn SerialPort>, slaves: Arc<Mutex<Vec<Slave>>>, stats: Arc<Mutex<SimStats>>,
+	rx: Receiver<()>, frame_gap: Duration, log: bool) {
+	let mut buf: Vec<u8> = Vec::new();
+	let mut chunk = [0u8; modbus::MAX_ADU_SIZE];
+	let mut last_rx = Instant::now();
+	while let Err(TryRecvError::Empty) = rx.try_recv() {
+		let now = Instant::now();
+		slaves.lock().unwrap().iter_mut().for_each(|s| s.run_script(now));
+		match port.read(&mut chunk) {
+			Ok(0) => thread::sleep(READ_TIMEOUT),
+			Ok(n) => {
+				buf.extend_from_slice(&chunk[..n]);
+				last_rx = Instant::now();
+			},
+			Err(e) if e.kind() == ErrorKind::TimedOut => (),
+			// pty reports error while the other side is closed
+			Err(_) => thread::sleep(READ_TIMEOUT)
+		}
+		while let Some(len) = modbus::request_len(&buf).filter(|len| buf.len() >= *len) {
+			let adu: Vec<u8> = buf.drain(..len).collect();
+			let resp = handle(&mut slaves.lock().unwrap(), &adu, &mut stats.lock().unwrap(), log);
+			if let Some(resp) = resp {
+				thread::sleep(frame_gap);
+				if let Err(e) = port.write_all(&resp) {
+					println!("\tFail to send response: {}", e);
+				}
+			}
+		}
+		if !buf.is_empty() && last_rx.elapsed() > INCOMPLETE_TIMEOUT {
+			buf.clear();
+			stats.lock().unwrap().broken += 1;
+		}
+	}
+}
+
+/// Modbus RTU slaves answering on serial port or new pty until dropped
+pub struct Simulator {
+	slaves: Arc<Mutex<Vec<Slave>>>,
+	stats: Arc<Mutex<SimStats>>,
+	path: String,
+	tx: Option<Sender<()>>,
+	thread: Option<JoinHandle<()>>,
+	/// Kept open, so the pty survives reopen by master
+	_pty: Option<TTYPort>
+}
+
+impl Simulator {
+	/// Start on `port` or on new pty when omitted, `log` prints every request
+	pub fn start(config: &SimConfig, port: Option<&str>, log: bool) -> Result<Self, String> {
+		let start = Instant::now();
+		let mut slaves = Vec::with_capacity(config.slaves.len());
+		for cfg in &config.slaves {
+			if slaves.iter().any(|s: &Slave| s.addr == cfg.addr) {
+				return Err(format!("Slave {} is defined twice", cfg.addr));
+			}
+			slaves.push(Slave::new(cfg, start)?);
+		}
+		let (mut tty, path, pty): (Box<dyn SerialPort>, String, Option<TTYPort>) = match port {
+			Some(path) => {
+				let tty = serialport::new(path, config.baudrate)
+					.parity(config.parity.serial())
+					.open()
+					.map_err(|e| format!("Fail to open serial port: {}", e))?;
+				(tty, path.to_string(), None)
+			},
+			None => {
+				let (master, slave) = TTYPort::pair().map_err(|e| format!("Fail to create pty: {}", e))?;
+				let name = slave.name().ok_or_else(|| String::from("Pty has no name"))?;
+				(Box::new(master), name, Some(slave))
+			}
+		};
+		tty.set_timeout(READ_TIMEOUT).map_err(|e| format!("Fail to set timeout: {}", e))?;
+		let slaves = Arc::new(Mutex::new(slaves));
+		let stats = Arc::new(Mutex::new(SimStats::default()));
+		let (tx, rx) = channel();
+		let frame_gap = modbus::frame_gap(config.baudrate);
+		let (thread_slaves, thread_stats) = (slaves.clone(), stats.clone());
+		let thread = thread::spawn(move || serve_handler(tty, thread_slaves, thread_stats, rx, frame_gap, log));
+		Ok(Self { slaves, stats, path, tx: Some(tx), thread: Some(thread), _pty: pty })
+	}
+
+	/// Device for master
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	pub fn stats(&self) -> SimStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	/// Print writable registers of every slave
+	pub fn print_state(&self) {
+		for s in self.slaves.lock().unwrap().iter() {
+			println!("\tSlave {}:", s.addr);
+			if !s.coils.is_empty() {
+				println!("\t\tcoils: {:?}", s.coils.iter().map(|(a, v)| (*a, *v as u8)).collect::<Vec<_>>());
+			}
+			if !s.holding.is_empty() {
+				println!("\t\tholding: {:?}", s.holding.iter().collect::<Vec<_>>());
+			}
+		}
+	}
+}
+
+impl Drop for Simulator {
+	fn drop(&mut self) {
+		drop(self.tx.take());
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+/// Run simulator with register map file until Enter
+pub fn run(map: &str, port: Option<&str>) -> Result<(), String> {
+	println!("\n[EXTBUS] Slave simulator begin..");
+	let config = SimConfig::load(map)?;
+	let sim = Simulator::start(&config, port, true)?;
+	match port {
+		Some(_) => println!("\tListen on {}", sim.path()),
+		None => println!("\tSet extbus driver to {}", sim.path())
+	}
+	let exiter = utils::Exiter::new();
+	while !exiter.check() {
+		thread::sleep(Duration::from_millis(100));
+	}
+	sim.print_state();
+	let stats = sim.stats();
+	println!("\tRequests: {}, responses: {}, broken frames: {}, faults: {}",
+		stats.requests, stats.responses, stats.broken, stats.faults);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::extbus::Master;
+	use crate::modbus::Error;
+
+	const MAP: &str = r#"
+		baudrate = 115200
+		parity = "None"
+
+		[[slaves]]
+		addr = 1
+		coils = [{ addr = 0, values = [true, false, true] }]
+		holding = [{ addr = 100, values = [10, 20, 30] }]
+		input = [{ addr = 0, values = [555] }]
+		script = [{ at_ms = 300, kind = "Input", addr = 0, values = [777] }]
+
+		[[slaves]]
+		addr = 2
+		holding = [{ addr = 0, values = [1] }]
+		faults = [
+			{ fault = "Crc", function = 3, every = 2 },
+			{ fault = { Exception = 6 }, function = 6, count = 1 }
+		]
+	"#;
+
+	fn master(sim: &Simulator) -> Master {
+		let port = serialport::new(sim.path(), 115200).open().unwrap();
+		Master::new(port, 115200, Duration::from_millis(200))
+	}
+
+	#[test]
+	fn master_against_simulator() {
+		let start = Instant::now();
+		let sim = Simulator::start(&SimConfig::parse(MAP).unwrap(), None, false).unwrap();
+		let mut m = master(&sim);
+		assert_eq!(m.read_input_registers(1, 0, 1).unwrap(), vec![555]);
+		assert_eq!(m.read_coils(1, 0, 3).unwrap(), vec![true, false, true]);
+		m.write_multiple_coils(1, 1, &[true, false]).unwrap();
+		assert_eq!(m.read_coils(1, 0, 3).unwrap(), vec![true, true, false]);
+		assert_eq!(m.read_write_multiple_registers(1, 100, 3, 101, &[21, 31]).unwrap(), vec![10, 21, 31]);
+		assert_eq!(m.read_holding_registers(1, 101, 3), Err(Error::Exception(exception::ILLEGAL_DATA_ADDRESS)));
+		assert_eq!(m.read_coils(5, 0, 1), Err(Error::Timeout));
+		thread::sleep(Duration::from_millis(350).saturating_sub(start.elapsed()));
+		assert_eq!(m.read_input_registers(1, 0, 1).unwrap(), vec![777]);
+
+		assert_eq!(m.read_holding_registers(2, 0, 1).unwrap(), vec![1]);
+		assert!(matches!(m.read_holding_registers(2, 0, 1), Err(Error::Crc { .. })));
+		assert_eq!(m.write_single_register(2, 0, 5), Err(Error::Exception(exception::SERVER_DEVICE_BUSY)));
+		m.write_single_register(2, 0, 5).unwrap();
+		assert_eq!(m.read_holding_registers(2, 0, 1).unwrap(), vec![5]);
+		assert_eq!(sim.stats().faults, 2);
+	}
+}
@@ -0,0 +1,285 @@
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use gpio::GpioValue;
+
+use crate::gpioline::OutputPin;
+
+/// Handle of output registered in `OutputScheduler`
+#[derive(Clone, Copy, Debug)]
+pub struct OutputId(usize);
+
+enum Mode {
+	Level,
+	Pulse,
+	Blink { on: Duration, off: Duration, remaining: Option<u32> },
+	Pwm { on: Duration, off: Duration }
+}
+
+enum Action {
+	Set(bool),
+	Pulse(Duration),
+	Blink(Duration, Duration, Option<u32>),
+	Pwm(Duration, f64)
+}
+
+enum Cmd {
+	Add(Box<dyn OutputPin>, GpioValue),
+	Ctl(usize, Action)
+}
+
+/// Output pin which is turned off when dropped, also while unwinding after panic
+struct SafeOutput {
+	pin: Box<dyn OutputPin>,
+	active: GpioValue,
+	inactive: GpioValue,
+	state: bool
+}
+
+impl SafeOutput {
+	fn set(&mut self, on: bool) {
+		let val = if on { self.active } else { self.inactive };
+		if let Err(e) = self.pin.write(val) {
+			println!("\tFail to set output: {}", e);
+		}
+		self.state = on;
+	}
+}
+
+impl Drop for SafeOutput {
+	fn drop(&mut self) {
+		let _ = self.pin.write(self.inactive);
+	}
+}
+
+struct Channel {
+	out: SafeOutput,
+	mode: Mode,
+	next: Option<Instant>
+}
+
+impl Channel {
+	fn apply(&mut self, action: Action, now: Instant) {
+		match action {
+			Action::Set(on) => {
+				self.mode = Mode::Level;
+				self.next = None;
+				self.out.set(on);
+			},
+			Action::Pulse(dur) => {
+				self.mode = Mode::Pulse;
+				self.next = Some(now + dur);
+				self.out.set(true);
+			},
+			Action::Blink(on, off, count) => {
+				if count == Some(0) {
+					self.apply(Action::Set(false), now);
+					return;
+				}
+				self.mode = Mode::Blink { on, off, remaining: count };
+				self.next = Some(now + on);
+				self.out.set(true);
+			},
+			Action::Pwm(period, duty) => {
+				let duty = duty.clamp(0.0, 1.0);
+				if duty == 0.0 || duty == 1.0 {
+					self.apply(Action::Set(duty == 1.0), now);
+					return;
+				}
+				let on = period.mul_f64(duty);
+				self.mode = Mode::Pwm { on, off: period - on };
+				self.next = Some(now + on);
+				self.out.set(true);
+			}
+		}
+	}
+
+	/// Perform transition scheduled at `self.next`
+	fn step(&mut self, now: Instant) {
+		let due = match self.next {
+			Some(t) if t <= now => t,
+			_ => return
+		};
+		match &mut self.mode {
+			Mode::Level => self.next = None,
+			Mode::Pulse => {
+				self.mode = Mode::Level;
+				self.next = None;
+				self.out.set(false);
+			},
+			Mode::Blink { on, off, remaining } => {
+				if self.out.state {
+					if let Some(n) = remaining {
+						*n -= 1;
+						if *n == 0 {
+							self.mode = Mode::Level;
+							self.next = None;
+							self.out.set(false);
+							return;
+						}
+					}
+					self.next = Some(due + *off);
+					self.out.set(false);
+				} else {
+					self.next = Some(due + *on);
+					self.out.set(true);
+				}
+			},
+			Mode::Pwm { on, off } => {
+				let state = !self.out.state;
+				self.next = Some(due + if state { *on } else { *off });
+				self.out.set(state);
+			}
+		}
+	}
+}
+
+fn scheduler_handler(rx: Receiver<Cmd>) {
+	let mut channels: Vec<Channel> = Vec::new();
+	loop {
+		let next = channels.iter().filter_map(|c| c.next).min();
+		let res = match next {
+			Some(t) => rx.recv_timeout(t.saturating_duration_since(Instant::now())),
+			None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+		};
+		let now = Instant::now();
+		match res {
+			Ok(Cmd::Add(pin, active)) => {
+				let inactive = match active {
+					GpioValue::High => GpioValue::Low,
+					GpioValue::Low => GpioValue::High
+				};
+				let mut out = SafeOutput { pin, active, inactive, state: false };
+				out.set(false);
+				channels.push(Channel { out, mode: Mode::Level, next: None });
+			},
+			Ok(Cmd::Ctl(id, action)) => channels[id].apply(action, now),
+			Err(RecvTimeoutError::Timeout) => (),
+			Err(RecvTimeoutError::Disconnected) => break
+		}
+		for c in channels.iter_mut() {
+			c.step(now);
+		}
+	}
+}
+
+/// Timed outputs driven by dedicated thread.
+///
+/// Outputs are switched to inactive level when the scheduler is dropped,
+/// also if the scheduler thread or its owner panics.
+pub struct OutputScheduler {
+	tx: Option<Sender<Cmd>>,
+	thread: Option<JoinHandle<()>>,
+	count: usize
+}
+
+impl OutputScheduler {
+	pub fn new() -> Self {
+		let (tx, rx) = channel();
+		let thread = thread::spawn(|| scheduler_handler(rx));
+		Self {
+			tx: Some(tx),
+			thread: Some(thread),
+			count: 0
+		}
+	}
+
+	fn send(&self, cmd: Cmd) -> Result<(), String> {
+		match &self.tx {
+			Some(tx) => tx.send(cmd).map_err(|_| String::from("Output scheduler is stopped")),
+			None => Err(String::from("Output scheduler is stopped"))
+		}
+	}
+
+	/// Register output, it is set to inactive level at once
+	pub fn add(&mut self, pin: Box<dyn OutputPin>, active: GpioValue) -> Result<OutputId, String> {
+		self.send(Cmd::Add(pin, active))?;
+		self.count += 1;
+		Ok(OutputId(self.count - 1))
+	}
+
+	/// Static level, cancels running pulse, blink or PWM
+	pub fn set(&self, id: OutputId, on: bool) -> Result<(), String> {
+		self.send(Cmd::Ctl(id.0, Action::Set(on)))
+	}
+
+	/// Turn output on for `dur`
+	pub fn pulse(&self, id: OutputId, dur: Duration) -> Result<(), String> {
+		self.send(Cmd::Ctl(id.0, Action::Pulse(dur)))
+	}
+
+	/// Blink `count` times or endlessly if `None`
+	pub fn blink(&self, id: OutputId, on: Duration, off: Duration, count: Option<u32>) -> Result<(), String> {
+		self.send(Cmd::Ctl(id.0, Action::Blink(on, off, count)))
+	}
+
+	/// Software PWM, `duty` is in range 0.0..=1.0
+	pub fn pwm(&self, id: OutputId, period: Duration, duty: f64) -> Result<(), String> {
+		self.send(Cmd::Ctl(id.0, Action::Pwm(period, duty)))
+	}
+}
+
+impl Drop for OutputScheduler {
+	fn drop(&mut self) {
+		drop(self.tx.take());
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gpiomock::{self, MockOutput, Net};
+
+	fn channel(net: &Net) -> Channel {
+		let out = SafeOutput {
+			pin: Box::new(MockOutput(vec![net.clone()])),
+			active: GpioValue::High,
+			inactive: GpioValue::Low,
+			state: false
+		};
+		Channel { out, mode: Mode::Level, next: None }
+	}
+
+	fn ms(start: Instant, ms: u64) -> Instant {
+		start + Duration::from_millis(ms)
+	}
+
+	#[test]
+	fn pulse_blink_and_pwm_steps() {
+		let net = gpiomock::net(GpioValue::Low);
+		let level = || *net.lock().unwrap();
+		let mut ch = channel(&net);
+		let t = Instant::now();
+
+		ch.apply(Action::Pulse(Duration::from_millis(100)), t);
+		ch.step(ms(t, 50));
+		assert_eq!(level(), GpioValue::High);
+		ch.step(ms(t, 100));
+		assert_eq!((level(), ch.next), (GpioValue::Low, None));
+
+		ch.apply(Action::Blink(Duration::from_millis(10), Duration::from_millis(20), Some(2)), t);
+		let mut seen = vec![level()];
+		for at in [10, 30, 40] {
+			ch.step(ms(t, at));
+			seen.push(level());
+		}
+		assert_eq!(seen, [GpioValue::High, GpioValue::Low, GpioValue::High, GpioValue::Low]);
+		assert_eq!(ch.next, None);
+
+		ch.apply(Action::Pwm(Duration::from_millis(100), 0.25), t);
+		ch.step(ms(t, 25));
+		assert_eq!((level(), ch.next), (GpioValue::Low, Some(ms(t, 100))));
+		// late step keeps period, next edge is counted from scheduled one
+		ch.step(ms(t, 103));
+		assert_eq!((level(), ch.next), (GpioValue::High, Some(ms(t, 125))));
+		ch.apply(Action::Pwm(Duration::from_millis(100), 1.0), t);
+		assert_eq!((level(), ch.next), (GpioValue::High, None));
+
+		drop(ch);
+		assert_eq!(level(), GpioValue::Low);
+	}
+}
@@ -0,0 +1,421 @@
+use std::fs;
+use std::io::{BufReader, Cursor};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::framebuffer::{Framebuffer, LEVEL_MAX};
+use crate::frameformat::FrameFormat;
+
+/// Pre-encoded frames file: magic, version, width, height, frame count,
+/// then every frame as delay in ms and draw data, see `cache_format`
+const CACHE_MAGIC: &[u8] = b"LMXF";
+const CACHE_VERSION: u8 = 2;
+const CACHE_HEADER_SIZE: usize = 11;
+
+/// Frame delays below this are shown with default delay, same as browsers do
+const GIF_MIN_DELAY: Duration = Duration::from_millis(20);
+const GIF_DEFAULT_DELAY: Duration = Duration::from_millis(100);
+/// Samples per axis of one target pixel when scaling down
+const MAX_SAMPLES: u32 = 8;
+/// Largest decoded image, 64 MiB of RGBA
+const MAX_PIXELS: usize = 1 << 24;
+
+const BAYER4: [[u8;4];4] = [
+	[0, 8, 2, 10],
+	[12, 4, 14, 6],
+	[3, 11, 1, 9],
+	[15, 7, 13, 5]
+];
+
+/// Decoded image, 8-bit RGBA
+#[derive(Clone)]
+pub struct Bitmap {
+	width: u32,
+	height: u32,
+	rgba: Vec<u8>
+}
+
+impl Bitmap {
+	/// Blank image, size from file header is checked before allocation
+	fn new(width: u32, height: u32) -> Result<Self, String> {
+		match (width as usize).checked_mul(height as usize) {
+			Some(pixels) if pixels <= MAX_PIXELS => Ok(Self { width, height, rgba: vec![0; pixels * 4] }),
+			_ => Err(format!("Image {}x{} exceeds {} pixels", width, height, MAX_PIXELS))
+		}
+	}
+
+	/// Brightness in range 0.0..=1.0, transparent pixels are black
+	fn luma(&self, x: u32, y: u32) -> f32 {
+		let i = ((y * self.width + x) * 4) as usize;
+		let p = &self.rgba[i..i + 4];
+		let l = 0.2126 * p[0] as f32 + 0.7152 * p[1] as f32 + 0.0722 * p[2] as f32;
+		l * p[3] as f32 / (255.0 * 255.0)
+	}
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Fit {
+	/// Whole image is visible, free space is black
+	Contain,
+	/// Image covers display, edges are cropped
+	Cover,
+	Stretch
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dither {
+	None,
+	/// 4x4 Bayer matrix, stable between animation frames
+	Ordered,
+	FloydSteinberg
+}
+
+/// Decode PNG, BMP or GIF, returns frames with their delays
+pub fn decode(data: &[u8]) -> Result<Vec<(Bitmap, Duration)>, String> {
+	if data.starts_with(b"\x89PNG") {
+		decode_png(data).map(|b| vec![(b, Duration::ZERO)])
+	} else if data.starts_with(b"GIF8") {
+		decode_gif(data)
+	} else if data.starts_with(b"BM") {
+		decode_bmp(data).map(|b| vec![(b, Duration::ZERO)])
+	} else {
+		Err(String::from("Unknown image format, PNG, BMP and GIF are supported"))
+	}
+}
+
+fn decode_png(data: &[u8]) -> Result<Bitmap, String> {
+	let mut decoder = png::Decoder::new(BufReader::new(Cursor::new(data)));
+	decoder.set_transformations(png::Transformations::normalize_to_color8());
+	let mut reader = decoder.read_info().map_err(|e| format!("Fail to decode PNG: {}", e))?;
+	let mut buf = vec![0; reader.output_buffer_size().ok_or("PNG is too large")?];
+	let info = reader.next_frame(&mut buf).map_err(|e| format!("Fail to decode PNG: {}", e))?;
+	let mut bmp = Bitmap::new(info.width, info.height)?;
+	let channels = info.color_type.samples();
+	for y in 0..info.height as usize {
+		let row = &buf[y * info.line_size..];
+		for x in 0..info.width as usize {
+			let src = &row[x * channels..(x + 1) * channels];
+			let rgba = match src {
+				[l] => [*l, *l, *l, 0xFF],
+				[l, a] => [*l, *l, *l, *a],
+				[r, g, b] => [*r, *g, *b, 0xFF],
+				[r, g, b, a] => [*r, *g, *b, *a],
+				_ => return Err(format!("Unsupported PNG color type {:?}", info.color_type))
+			};
+			let i = (y * info.width as usize + x) * 4;
+			bmp.rgba[i..i + 4].copy_from_slice(&rgba);
+		}
+	}
+	Ok(bmp)
+}
+
+fn decode_gif(data: &[u8]) -> Result<Vec<(Bitmap, Duration)>, String> {
+	let mut opts = gif::DecodeOptions::new();
+	opts.set_color_output(gif::ColorOutput::RGBA);
+	let mut decoder = opts.read_info(data).map_err(|e| format!("Fail to decode GIF: {}", e))?;
+	let mut canvas = Bitmap::new(decoder.width() as u32, decoder.height() as u32)?;
+	let mut frames = Vec::new();
+	while let Some(frame) = decoder.read_next_frame().map_err(|e| format!("Fail to decode GIF: {}", e))? {
+		let previous = (frame.dispose == gif::DisposalMethod::Previous).then(|| canvas.clone());
+		let (left, top, w, h) = (frame.left as u32, frame.top as u32, frame.width as u32, frame.height as u32);
+		for y in 0..h {
+			for x in 0..w {
+				let src = ((y * w + x) * 4) as usize;
+				let (cx, cy) = (left + x, top + y);
+				if frame.buffer[src + 3] == 0 || cx >= canvas.width || cy >= canvas.height {
+					continue;
+				}
+				let dst = ((cy * canvas.width + cx) * 4) as usize;
+				canvas.rgba[dst..dst + 4].copy_from_slice(&frame.buffer[src..src + 4]);
+			}
+		}
+		let delay = Duration::from_millis(frame.delay as u64 * 10);
+		frames.push((canvas.clone(), if delay < GIF_MIN_DELAY { GIF_DEFAULT_DELAY } else { delay }));
+		match (frame.dispose, previous) {
+			(gif::DisposalMethod::Previous, Some(prev)) => canvas = prev,
+			(gif::DisposalMethod::Background, _) => {
+				for y in top..(top + h).min(canvas.height) {
+					for x in left..(left + w).min(canvas.width) {
+						let dst = ((y * canvas.width + x) * 4) as usize;
+						canvas.rgba[dst..dst + 4].fill(0);
+					}
+				}
+			},
+			_ => ()
+		}
+	}
+	if frames.is_empty() {
+		return Err(String::from("GIF has no frames"));
+	}
+	Ok(frames)
+}
+
+fn le16(data: &[u8], pos: usize) -> Result<u16, String> {
+	data.get(pos..pos + 2).map(|b| u16::from_le_bytes([b[0], b[1]])).ok_or(String::from("Truncated BMP"))
+}
+
+fn le32(data: &[u8], pos: usize) -> Result<u32, String> {
+	data.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or(String::from("Truncated BMP"))
+}
+
+/// Value of masked field scaled to 8 bits
+fn mask_field(px: u32, mask: u32) -> u8 {
+	if mask == 0 {
+		return 0xFF;
+	}
+	let max = mask >> mask.trailing_zeros();
+	(((px & mask) >> mask.trailing_zeros()) * 255 / max) as u8
+}
+
+/// Uncompressed BMP with 1, 4, 8, 16, 24 or 32 bits per pixel
+fn decode_bmp(data: &[u8]) -> Result<Bitmap, String> {
+	let offset = le32(data, 10)? as usize;
+	let dib_size = le32(data, 14)? as usize;
+	// BITMAPCOREHEADER of OS/2 BMP has 16-bit size fields and 3-byte palette entries
+	if dib_size < 40 {
+		return Err(format!("Unsupported BMP header of {} bytes", dib_size));
+	}
+	let width = le32(data, 18)? as i32;
+	let height = le32(data, 22)? as i32;
+	let bpp = le16(data, 28)? as u32;
+	let compression = le32(data, 30)?;
+	if width <= 0 || height == 0 {
+		return Err(format!("Bad BMP size {}x{}", width, height));
+	}
+	let masks = match (compression, bpp) {
+		(0, 16) => [0x7C00, 0x03E0, 0x001F, 0],
+		(0, 32) => [0xFF0000, 0xFF00, 0xFF, 0],
+		(0, _) => [0; 4],
+		// BI_BITFIELDS, masks follow BITMAPINFOHEADER
+		(3, 16 | 32) => [
+			le32(data, 54)?,
+			le32(data, 58)?,
+			le32(data, 62)?,
+			if dib_size >= 56 { le32(data, 66)? } else { 0 }
+		],
+		_ => return Err(format!("Unsupported BMP compression {} at {} bits", compression, bpp))
+	};
+	let colors = match le32(data, 46)? {
+		0 if bpp <= 8 => 1 << bpp,
+		n => n as usize
+	};
+	let palette_at = 14 + dib_size;
+	let (w, h) = (width as u32, height.unsigned_abs());
+	let mut bmp = Bitmap::new(w, h)?;
+	let stride = (w as usize * bpp as usize).div_ceil(32) * 4;
+	for y in 0..h {
+		// rows are stored bottom-up unless height is negative
+		let row_at = offset + stride * if height > 0 { (h - 1 - y) as usize } else { y as usize };
+		let row = data.get(row_at..row_at + stride).ok_or("Truncated BMP")?;
+		for x in 0..w as usize {
+			let rgba = match bpp {
+				1 | 4 | 8 => {
+					let bit = x * bpp as usize;
+					let idx = (row[bit / 8] >> (8 - bpp as usize - bit % 8)) & ((1 << bpp) - 1) as u8;
+					if idx as usize >= colors {
+						return Err(format!("BMP color index {} out of palette", idx));
+					}
+					let p = data.get(palette_at + idx as usize * 4..palette_at + idx as usize * 4 + 3).ok_or("Truncated BMP palette")?;
+					[p[2], p[1], p[0], 0xFF]
+				},
+				24 => [row[x * 3 + 2], row[x * 3 + 1], row[x * 3], 0xFF],
+				16 | 32 => {
+					let px = if bpp == 16 {
+						u16::from_le_bytes([row[x * 2], row[x * 2 + 1]]) as u32
+					} else {
+						u32::from_le_bytes([row[x * 4], row[x * 4 + 1], row[x * 4 + 2], row[x * 4 + 3]])
+					};
+					[mask_field(px, masks[0]), mask_field(px, masks[1]), mask_field(px, masks[2]), mask_field(px, masks[3])]
+				},
+				_ => return Err(format!("Unsupported BMP depth {} bits", bpp))
+			};
+			let i = ((y * w + x as u32) * 4) as usize;
+			bmp.rgba[i..i + 4].copy_from_slice(&rgba);
+		}
+	}
+	Ok(bmp)
+}
+
+/// Scale image into `fb` by `fit` and quantize brightness to 4-bit levels
+pub fn convert(bmp: &Bitmap, fb: &mut Framebuffer, fit: Fit, dither: Dither) {
+	let (dw, dh) = (fb.width() as f32, fb.height() as f32);
+	let (sw, sh) = (bmp.width as f32, bmp.height as f32);
+	let (kx, ky) = match fit {
+		Fit::Contain => { let k = (dw / sw).min(dh / sh); (k, k) },
+		Fit::Cover => { let k = (dw / sw).max(dh / sh); (k, k) },
+		Fit::Stretch => (dw / sw, dh / sh)
+	};
+	// image placed centred, target pixel (x, y) covers source area from (x - ox) / kx
+	let (ox, oy) = ((dw - sw * kx) / 2.0, (dh - sh * ky) / 2.0);
+	let nx = ((1.0 / kx).ceil() as u32).clamp(1, MAX_SAMPLES);
+	let ny = ((1.0 / ky).ceil() as u32).clamp(1, MAX_SAMPLES);
+
+	let (w, h) = (fb.width() as usize, fb.height() as usize);
+	let mut lum = vec![0f32; w * h];
+	for y in 0..h {
+		for x in 0..w {
+			let mut sum = 0.0;
+			for j in 0..ny {
+				for i in 0..nx {
+					let sx = (x as f32 + (i as f32 + 0.5) / nx as f32 - ox) / kx;
+					let sy = (y as f32 + (j as f32 + 0.5) / ny as f32 - oy) / ky;
+					if sx >= 0.0 && sy >= 0.0 && sx < sw && sy < sh {
+						sum += bmp.luma(sx as u32, sy as u32);
+					}
+				}
+			}
+			lum[y * w + x] = sum / (nx * ny) as f32;
+		}
+	}
+
+	let max = LEVEL_MAX as f32;
+	for y in 0..h {
+		for x in 0..w {
+			let v = lum[y * w + x] * max;
+			let level = match dither {
+				Dither::None => v.round(),
+				Dither::Ordered => (v + (BAYER4[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5).round(),
+				Dither::FloydSteinberg => {
+					let q = v.round().clamp(0.0, max);
+					let err = (v - q) / max;
+					let mut spread = |dx: isize, dy: usize, k: f32| {
+						let (tx, ty) = (x as isize + dx, y + dy);
+						if tx >= 0 && (tx as usize) < w && ty < h {
+							lum[ty * w + tx as usize] += err * k;
+						}
+					};
+					spread(1, 0, 7.0 / 16.0);
+					spread(-1, 1, 3.0 / 16.0);
+					spread(0, 1, 5.0 / 16.0);
+					spread(1, 1, 1.0 / 16.0);
+					q
+				}
+			};
+			fb.set_pixel(x as i32, y as i32, level.clamp(0.0, max) as u8);
+		}
+	}
+}
+
+/// Read pre-encoded frames file or decode and convert image into frames like `blank`
+pub fn load(path: &str, blank: &Framebuffer, fit: Fit, dither: Dither) -> Result<Vec<(Framebuffer, Duration)>, String> {
+	let data = match fs::read(path) {
+		Ok(data) => data,
+		Err(e) => return Err(format!("Fail to read image {}: {}", path, e))
+	};
+	if data.starts_with(CACHE_MAGIC) {
+		return load_frames(&data, blank).map_err(|e| format!("Fail to load frames {}: {}", path, e));
+	}
+	let frames = decode(&data).map_err(|e| format!("Fail to load image {}: {}", path, e))?;
+	Ok(frames.iter().map(|(bmp, delay)| {
+		let mut fb = blank.clone();
+		convert(bmp, &mut fb, fit, dither);
+		(fb, *delay)
+	}).collect())
+}
+
+/// Draw data of frames file: single panel of frame size, default pixels and header
+fn cache_format(width: u32, height: u32) -> FrameFormat {
+	FrameFormat { panel_width: width, panel_height: height, ..Default::default() }
+}
+
+fn load_frames(data: &[u8], blank: &Framebuffer) -> Result<Vec<(Framebuffer, Duration)>, String> {
+	let header = data.get(..CACHE_HEADER_SIZE).ok_or("Truncated file")?;
+	if header[4] != CACHE_VERSION {
+		return Err(format!("Unsupported version {}", header[4]));
+	}
+	let width = u16::from_le_bytes([header[5], header[6]]) as u32;
+	let height = u16::from_le_bytes([header[7], header[8]]) as u32;
+	let count = u16::from_le_bytes([header[9], header[10]]) as usize;
+	if width != blank.width() || height != blank.height() {
+		return Err(format!("Frames are {}x{}, display is {}x{}", width, height, blank.width(), blank.height()));
+	}
+	let format = cache_format(width, height);
+	let size = 4 + format.encoded_size();
+	let mut frames = Vec::with_capacity(count);
+	for i in 0..count {
+		let at = CACHE_HEADER_SIZE + i * size;
+		let rec = data.get(at..at + size).ok_or("Truncated file")?;
+		let delay = Duration::from_millis(u32::from_le_bytes([rec[0], rec[1], rec[2], rec[3]]) as u64);
+		frames.push((format.decode(&rec[4..])?, delay));
+	}
+	Ok(frames)
+}
+
+/// Write frames as pre-encoded file, playing it needs no decoding or scaling
+pub fn save_frames(path: &str, frames: &[(Framebuffer, Duration)]) -> Result<(), String> {
+	let data = encode_frames(frames).map_err(|e| format!("Fail to save frames {}: {}", path, e))?;
+	fs::write(path, data).map_err(|e| format!("Fail to write frames {}: {}", path, e))
+}
+
+fn encode_frames(frames: &[(Framebuffer, Duration)]) -> Result<Vec<u8>, String> {
+	let (width, height) = frames.first().map_or((0, 0), |(fb, _)| (fb.width(), fb.height()));
+	let field = |name: &str, val: usize| u16::try_from(val).map_err(|_| format!("{} {} exceeds {}", name, val, u16::MAX));
+	let mut data = Vec::from(CACHE_MAGIC);
+	data.push(CACHE_VERSION);
+	data.extend_from_slice(&field("Width", width as usize)?.to_le_bytes());
+	data.extend_from_slice(&field("Height", height as usize)?.to_le_bytes());
+	data.extend_from_slice(&field("Frame count", frames.len())?.to_le_bytes());
+	let format = cache_format(width, height);
+	for (fb, delay) in frames {
+		let ms = u32::try_from(delay.as_millis()).map_err(|_| format!("Frame delay {:?} is too long", delay))?;
+		data.extend_from_slice(&ms.to_le_bytes());
+		data.extend_from_slice(&format.encode(fb));
+	}
+	Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn frames_file_round_trip() {
+		let mut first = Framebuffer::new(5, 3);
+		first.line(0, 0, 4, 2, LEVEL_MAX);
+		let mut second = first.clone();
+		second.fill_rect(1, 1, 2, 1, 7);
+		let frames = vec![(first, Duration::from_millis(40)), (second, Duration::from_millis(1500))];
+		let data = encode_frames(&frames).unwrap();
+		let loaded = load_frames(&data, &Framebuffer::new(5, 3)).unwrap();
+		assert_eq!(loaded.len(), 2);
+		for ((fb, delay), (lfb, ldelay)) in frames.iter().zip(&loaded) {
+			assert!(fb == lfb);
+			assert_eq!(delay, ldelay);
+		}
+		assert!(load_frames(&data, &Framebuffer::new(3, 5)).is_err());
+		assert!(load_frames(&data[..data.len() - 1], &Framebuffer::new(5, 3)).is_err());
+	}
+
+	#[test]
+	fn frames_beyond_header_fields_are_rejected() {
+		let wide = vec![(Framebuffer::new(u16::MAX as u32 + 1, 1), Duration::ZERO)];
+		assert!(encode_frames(&wide).is_err());
+		let many = vec![(Framebuffer::new(1, 1), Duration::ZERO); u16::MAX as usize + 1];
+		assert!(encode_frames(&many).is_err());
+	}
+
+	#[test]
+	fn core_header_bmp_is_rejected() {
+		// 2x1 24-bit BMP with 12-byte header
+		let mut data = b"BM".to_vec();
+		data.extend_from_slice(&[32, 0, 0, 0, 0, 0, 0, 0, 26, 0, 0, 0]);
+		data.extend_from_slice(&[12, 0, 0, 0, 2, 0, 1, 0, 1, 0, 24, 0]);
+		data.extend_from_slice(&[0u8;8]);
+		let err = decode_bmp(&data).err().unwrap();
+		assert!(err.contains("12 bytes"));
+	}
+
+	#[test]
+	fn oversized_bmp_is_rejected_before_allocation() {
+		// 24-bit BMP header claiming 0x7FFFFFFF x 0x7FFFFFFF pixels, no pixel data
+		let mut data = b"BM".to_vec();
+		data.extend_from_slice(&[54, 0, 0, 0, 0, 0, 0, 0, 54, 0, 0, 0]);
+		data.extend_from_slice(&[40, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0xFF, 0xFF, 0x7F, 1, 0, 24, 0]);
+		data.extend_from_slice(&[0u8;24]);
+		let err = decode_bmp(&data).err().unwrap();
+		assert!(err.contains("exceeds"));
+		assert!(Bitmap::new(u32::MAX, u32::MAX).is_err());
+		assert!(Bitmap::new(4096, 4097).is_err());
+	}
+}
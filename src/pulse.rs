@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use gpio::GpioValue;
+
+use crate::gpioline::InputPin;
+
+const MIN_WIDTH_DEF: Duration = Duration::from_millis(2);
+const POLL_PERIOD_DEF: Duration = Duration::from_micros(200);
+const WINDOW_DEF: Duration = Duration::from_secs(1);
+const ACTIVE_LEVEL_DEF: GpioValue = GpioValue::Low;
+
+/// Edge counter for pulse inputs (flow meters, pulse coin mechs).
+///
+/// Input is sampled on `poll`, a level change is accepted only after it
+/// was stable for `min_width`, so contact bounce and spikes are dropped.
+/// Every accepted active edge is counted as one pulse.
+pub struct PulseCounter {
+	pin: Box<dyn InputPin>,
+
+	min_width: Duration,
+	poll_period: Duration,
+	window: Duration,
+	active_level: GpioValue,
+
+	count: u64,
+	level: GpioValue,
+	pending: Option<(GpioValue, Instant)>,
+	tl_poll: Instant,
+	pulses: VecDeque<Instant>
+}
+
+impl PulseCounter {
+	pub fn new(pin: Box<dyn InputPin>) -> Self {
+		Self {
+			pin,
+
+			min_width: MIN_WIDTH_DEF,
+			poll_period: POLL_PERIOD_DEF,
+			window: WINDOW_DEF,
+			active_level: ACTIVE_LEVEL_DEF,
+
+			count: 0,
+			level: inverse(ACTIVE_LEVEL_DEF),
+			pending: None,
+			tl_poll: Instant::now(),
+			pulses: VecDeque::new()
+		}
+	}
+
+	pub fn set_min_width(&mut self, dur: Duration) {
+		self.min_width = dur;
+	}
+
+	pub fn set_poll_period(&mut self, dur: Duration) {
+		self.poll_period = dur;
+	}
+
+	/// Length of sliding window used by `rate`
+	pub fn set_window(&mut self, dur: Duration) {
+		self.window = dur;
+	}
+
+	pub fn set_active_level(&mut self, level: GpioValue) {
+		self.active_level = level;
+		self.level = inverse(level);
+	}
+
+	/// Total number of pulses since creation
+	pub fn count(&self) -> u64 {
+		self.count
+	}
+
+	/// Pulses per second over the sliding window
+	pub fn rate(&mut self) -> f64 {
+		self.expire(Instant::now());
+		self.pulses.len() as f64 / self.window.as_secs_f64()
+	}
+
+	/// Sample input, returns number of new pulses (0 or 1)
+	pub fn poll(&mut self) -> Result<u64, std::io::Error> {
+		if self.tl_poll.elapsed() < self.poll_period {
+			return Ok(0);
+		}
+		let now = Instant::now();
+		self.tl_poll = now;
+		let val = self.pin.read()?;
+		Ok(self.sample(val, now))
+	}
+
+	/// Debounce input level sampled at `now`, returns number of new pulses
+	fn sample(&mut self, val: GpioValue, now: Instant) -> u64 {
+		let mut new = 0;
+		if val == self.level {
+			self.pending = None;
+		} else {
+			match self.pending {
+				Some((level, since)) if level == val => {
+					if now.duration_since(since) >= self.min_width {
+						self.level = val;
+						self.pending = None;
+						if val == self.active_level {
+							self.count += 1;
+							self.pulses.push_back(since);
+							new = 1;
+						}
+					}
+				},
+				_ => self.pending = Some((val, now))
+			}
+		}
+		self.expire(now);
+		new
+	}
+
+	fn expire(&mut self, now: Instant) {
+		while let Some(t) = self.pulses.front() {
+			if now.duration_since(*t) > self.window {
+				self.pulses.pop_front();
+			} else {
+				break;
+			}
+		}
+	}
+}
+
+/// Groups pulses into trains separated by a pause of at least `gap`.
+///
+/// Pulse coin mechs report one coin as a burst of N pulses, the burst
+/// length is returned by `poll` once the line stays quiet for `gap`.
+pub struct PulseTrain {
+	gap: Duration,
+	count: u32,
+	tl_pulse: Instant
+}
+
+impl PulseTrain {
+	pub fn new(gap: Duration) -> Self {
+		Self {
+			gap,
+			count: 0,
+			tl_pulse: Instant::now()
+		}
+	}
+
+	/// Add pulses counted at `now`
+	pub fn push(&mut self, pulses: u64, now: Instant) {
+		if pulses > 0 {
+			self.count += pulses as u32;
+			self.tl_pulse = now;
+		}
+	}
+
+	/// Returns length of train completed before `now`
+	pub fn poll(&mut self, now: Instant) -> Option<u32> {
+		if self.count > 0 && now.duration_since(self.tl_pulse) >= self.gap {
+			let count = self.count;
+			self.count = 0;
+			Some(count)
+		} else {
+			None
+		}
+	}
+}
+
+fn inverse(val: GpioValue) -> GpioValue {
+	match val {
+		GpioValue::High => GpioValue::Low,
+		GpioValue::Low => GpioValue::High
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gpiomock::{self, MockInput};
+
+	fn ms(start: Instant, ms: u64) -> Instant {
+		start + Duration::from_millis(ms)
+	}
+
+	#[test]
+	fn bounce_is_dropped_and_rate_is_windowed() {
+		let mut counter = PulseCounter::new(Box::new(MockInput(gpiomock::net(GpioValue::High))));
+		let t = Instant::now();
+		// spike shorter than min width
+		assert_eq!(counter.sample(GpioValue::Low, ms(t, 0)), 0);
+		assert_eq!(counter.sample(GpioValue::High, ms(t, 1)), 0);
+		// bouncing contact settles low
+		assert_eq!(counter.sample(GpioValue::Low, ms(t, 10)), 0);
+		assert_eq!(counter.sample(GpioValue::Low, ms(t, 11)), 0);
+		assert_eq!(counter.sample(GpioValue::Low, ms(t, 12)), 1);
+		assert_eq!(counter.sample(GpioValue::Low, ms(t, 20)), 0);
+		// release is not a pulse
+		assert_eq!(counter.sample(GpioValue::High, ms(t, 30)), 0);
+		assert_eq!(counter.sample(GpioValue::High, ms(t, 33)), 0);
+		assert_eq!(counter.sample(GpioValue::Low, ms(t, 40)), 0);
+		assert_eq!(counter.sample(GpioValue::Low, ms(t, 42)), 1);
+		assert_eq!(counter.count(), 2);
+		assert_eq!(counter.pulses.len(), 2);
+		counter.expire(ms(t, 1011));
+		assert_eq!(counter.pulses.len(), 1);
+	}
+
+	#[test]
+	fn trains_are_split_by_gap() {
+		let gap = Duration::from_millis(150);
+		let t = Instant::now();
+		let mut train = PulseTrain::new(gap);
+		assert_eq!(train.poll(t), None);
+		for i in 0..5 {
+			train.push(1, ms(t, i * 50));
+		}
+		train.push(0, ms(t, 300));
+		assert_eq!(train.poll(ms(t, 300)), None);
+		assert_eq!(train.poll(ms(t, 350)), Some(5));
+		assert_eq!(train.poll(ms(t, 400)), None);
+		train.push(2, ms(t, 500));
+		assert_eq!(train.poll(ms(t, 650)), Some(2));
+	}
+}
@@ -0,0 +1,186 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::font::Font;
+use crate::framebuffer::Framebuffer;
+use crate::ledmatrix::Display;
+use crate::text::{self, Align};
+
+/// Blink period of expired countdown
+const COUNTDOWN_BLINK: Duration = Duration::from_millis(500);
+
+/// Content of display, rendered on every frame with time since scene start
+pub enum Scene {
+	Blank,
+	Static(Framebuffer),
+	Blink { frame: Framebuffer, on: Duration, off: Duration },
+	/// Single line running from right to left, `speed` in pixels per second
+	Scroll { text: String, font: Arc<Font>, speed: u32, level: u8 },
+	/// Time left to `until` as MM:SS after `prefix`, blinks zero when expired
+	Countdown { until: Instant, prefix: String, font: Arc<Font>, level: u8 },
+	/// Frames shown for their durations, last one stays if not repeated
+	Sequence { frames: Vec<(Framebuffer, Duration)>, repeat: bool }
+}
+
+impl Scene {
+	pub fn render(&self, fb: &mut Framebuffer, t: Duration) {
+		match self {
+			Self::Blank => (),
+			Self::Static(frame) => fb.blit(frame, 0, 0),
+			Self::Blink { frame, on, off } => {
+				let period = (*on + *off).as_millis().max(1);
+				if t.as_millis() % period < on.as_millis() {
+					fb.blit(frame, 0, 0);
+				}
+			},
+			Self::Scroll { text, font, speed, level } => {
+				let width = fb.width() as i32;
+				let span = (width + font.text_width(text)).max(1) as u128;
+				let x = width - (t.as_millis() * *speed as u128 / 1000 % span) as i32;
+				let top = (fb.height() as i32 - font.line_height()) / 2;
+				text::draw_line(fb, font, text, x, top + font.ascent - 1, *level);
+			},
+			Self::Countdown { until, prefix, font, level } => {
+				let left = until.saturating_duration_since(Instant::now());
+				if left.is_zero() && t.as_millis() / COUNTDOWN_BLINK.as_millis() % 2 == 1 {
+					return;
+				}
+				// round up, zero is shown only when time is over
+				let secs = left.as_millis().div_ceil(1000);
+				let msg = format!("{}{:02}:{:02}", prefix, secs / 60, secs % 60);
+				text::draw_text(fb, font, &msg, Align::Center, *level);
+			},
+			Self::Sequence { frames, repeat } => {
+				let total: u128 = frames.iter().map(|(_, d)| d.as_millis()).sum();
+				if frames.is_empty() || total == 0 {
+					return;
+				}
+				let mut at = if *repeat { t.as_millis() % total } else { t.as_millis().min(total - 1) };
+				for (frame, dur) in frames {
+					if at < dur.as_millis() {
+						fb.blit(frame, 0, 0);
+						break;
+					}
+					at -= dur.as_millis();
+				}
+			}
+		}
+	}
+}
+
+/// Counters of render loop
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+	pub frames: u64,
+	/// Frame slots missed because rendering or sending took too long
+	pub dropped: u64,
+	pub max_frame_time: Duration,
+	pub errors: u64,
+	pub last_error: Option<String>
+}
+
+enum Cmd {
+	Scene(Scene),
+	Stop
+}
+
+/// Sets scene of `FrameScheduler` from any thread
+#[derive(Clone)]
+pub struct SceneHandle {
+	tx: Sender<Cmd>
+}
+
+impl SceneHandle {
+	pub fn set(&self, scene: Scene) -> Result<(), String> {
+		self.tx.send(Cmd::Scene(scene)).map_err(|_| String::from("Frame scheduler is stopped"))
+	}
+}
+
+fn render_handler(mut display: Display, period: Duration, rx: Receiver<Cmd>, stats: Arc<Mutex<FrameStats>>) {
+	let mut scene = Scene::Blank;
+	let mut started = Instant::now();
+	let mut fb = display.frame();
+	let mut tick = Instant::now();
+	loop {
+		match rx.recv_timeout(tick.saturating_duration_since(Instant::now())) {
+			Ok(Cmd::Scene(s)) => {
+				scene = s;
+				started = Instant::now();
+				continue;
+			},
+			Ok(Cmd::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+			Err(RecvTimeoutError::Timeout) => ()
+		}
+		let begin = Instant::now();
+		fb.clear();
+		scene.render(&mut fb, begin.duration_since(started));
+		let res = display.show(&fb);
+		let end = Instant::now();
+		tick += period;
+
+		let mut st = stats.lock().unwrap();
+		st.frames += 1;
+		st.max_frame_time = st.max_frame_time.max(end.duration_since(begin));
+		if let Err(e) = res {
+			st.errors += 1;
+			st.last_error = Some(e);
+		}
+		if end > tick {
+			// skip missed slots instead of sending frames late in a burst
+			let missed = (end.duration_since(tick).as_nanos() / period.as_nanos()) as u32 + 1;
+			st.dropped += missed as u64;
+			tick += period * missed;
+		}
+	}
+}
+
+/// Render loop owning the display, sends a frame every period.
+///
+/// Stops when dropped, also if scene handles are still held by other threads.
+/// The last frame stays on display.
+pub struct FrameScheduler {
+	handle: Option<SceneHandle>,
+	thread: Option<JoinHandle<()>>,
+	stats: Arc<Mutex<FrameStats>>
+}
+
+impl FrameScheduler {
+	pub fn new(display: Display, frame_rate: u32) -> Self {
+		let (tx, rx) = channel();
+		let stats = Arc::new(Mutex::new(FrameStats::default()));
+		let period = Duration::from_secs(1) / frame_rate.max(1);
+		let thread_stats = stats.clone();
+		let thread = thread::spawn(move || render_handler(display, period, rx, thread_stats));
+		Self {
+			handle: Some(SceneHandle { tx }),
+			thread: Some(thread),
+			stats
+		}
+	}
+
+	/// Handle for other threads
+	pub fn handle(&self) -> SceneHandle {
+		self.handle.clone().unwrap()
+	}
+
+	pub fn set(&self, scene: Scene) -> Result<(), String> {
+		self.handle().set(scene)
+	}
+
+	pub fn stats(&self) -> FrameStats {
+		self.stats.lock().unwrap().clone()
+	}
+}
+
+impl Drop for FrameScheduler {
+	fn drop(&mut self) {
+		if let Some(handle) = self.handle.take() {
+			let _ = handle.tx.send(Cmd::Stop);
+		}
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
@@ -0,0 +1,85 @@
+/// Segment bits of a digit: a..g are bits 0..6, decimal point is bit 7
+pub const SEG_DP: u8 = 1 << 7;
+
+/// Characters a seven-segment digit can show
+const FONT: &[(char, u8)] = &[
+	('0', 0x3F), ('1', 0x06), ('2', 0x5B), ('3', 0x4F), ('4', 0x66),
+	('5', 0x6D), ('6', 0x7D), ('7', 0x07), ('8', 0x7F), ('9', 0x6F),
+	('A', 0x77), ('b', 0x7C), ('C', 0x39), ('c', 0x58), ('d', 0x5E),
+	('E', 0x79), ('F', 0x71), ('G', 0x3D), ('H', 0x76), ('h', 0x74),
+	('I', 0x30), ('J', 0x1E), ('L', 0x38), ('n', 0x54), ('o', 0x5C),
+	('O', 0x3F), ('P', 0x73), ('q', 0x67), ('r', 0x50), ('S', 0x6D),
+	('t', 0x78), ('U', 0x3E), ('u', 0x1C), ('y', 0x6E),
+	(' ', 0x00), ('-', 0x40), ('_', 0x08), ('=', 0x48)
+];
+
+/// Segments of character, other case is used when the asked one has no glyph
+pub fn glyph(c: char) -> Option<u8> {
+	let find = |c: char| FONT.iter().find(|(f, _)| *f == c).map(|(_, s)| *s);
+	find(c)
+		.or_else(|| c.to_uppercase().next().and_then(find))
+		.or_else(|| c.to_lowercase().next().and_then(find))
+}
+
+/// Segments of `digits` digits, leftmost first. Text is left aligned,
+/// '.' lights decimal point of previous character.
+pub fn render_text(text: &str, digits: usize) -> Result<Vec<u8>, String> {
+	let mut segs: Vec<u8> = Vec::with_capacity(digits);
+	for c in text.chars() {
+		match c {
+			'.' if segs.last().is_some_and(|s| s & SEG_DP == 0) => *segs.last_mut().unwrap() |= SEG_DP,
+			'.' => segs.push(SEG_DP),
+			c => segs.push(glyph(c).ok_or_else(|| format!("Character '{}' has no segment glyph", c))?)
+		}
+	}
+	if segs.len() > digits {
+		return Err(format!("Text '{}' does not fit in {} digits", text, digits));
+	}
+	segs.resize(digits, 0);
+	Ok(segs)
+}
+
+/// Right aligned number with `decimals` digits after point, `1234` with 2 decimals is "12.34"
+pub fn render_number(value: i64, decimals: u32, digits: usize) -> Result<Vec<u8>, String> {
+	let abs = value.unsigned_abs();
+	let scale = 10u64.checked_pow(decimals).ok_or_else(|| format!("{} decimals are too many", decimals))?;
+	let mut text = if decimals == 0 {
+		abs.to_string()
+	} else {
+		format!("{}.{:0width$}", abs / scale, abs % scale, width = decimals as usize)
+	};
+	if value < 0 {
+		text.insert(0, '-');
+	}
+	let len = text.chars().filter(|c| *c != '.').count();
+	if len > digits {
+		return Err(format!("Number {} does not fit in {} digits", text, digits));
+	}
+	render_text(&format!("{}{}", " ".repeat(digits - len), text), digits)
+}
+
+/// Lit segments of bar graph with `count` LEDs for `value` of `max`, rounded to nearest
+pub fn bar_level(value: u32, max: u32, count: usize) -> usize {
+	if max == 0 {
+		return 0;
+	}
+	((value.min(max) as u64 * count as u64 * 2 + max as u64) / (max as u64 * 2)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn numbers_text_and_levels() {
+		assert_eq!(render_number(1234, 2, 5).unwrap(), vec![0, 0x06, 0x5B | SEG_DP, 0x4F, 0x66]);
+		assert_eq!(render_number(-5, 0, 3).unwrap(), vec![0, 0x40, 0x6D]);
+		assert_eq!(render_number(7, 2, 4).unwrap(), vec![0, 0x3F | SEG_DP, 0x3F, 0x07]);
+		assert!(render_number(12345, 0, 4).is_err());
+		assert!(render_number(1, 20, 30).is_err());
+		assert_eq!(render_number(1, 19, 20).unwrap()[0], 0x3F | SEG_DP);
+		assert_eq!(render_text("Err.", 4).unwrap(), vec![0x79, 0x50, 0x50 | SEG_DP, 0]);
+		assert!(render_text("W", 4).is_err());
+		assert_eq!((bar_level(0, 100, 10), bar_level(54, 100, 10), bar_level(200, 100, 10)), (0, 5, 10));
+	}
+}
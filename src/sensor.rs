@@ -0,0 +1,482 @@
+use std::{thread, time::{Duration, Instant}};
+
+use serde::Deserialize;
+
+use crate::i2cbus::{I2cBus, read_reg, write};
+
+const CONVERSION_TIMEOUT: Duration = Duration::from_millis(100);
+const CONVERSION_POLL: Duration = Duration::from_millis(1);
+
+mod ads1x15 {
+	pub const CONVERSION: u8 = 0x00;
+	pub const CONFIG: u8 = 0x01;
+
+	pub const OS: u16 = 1 << 15;
+	pub const MUX_SHIFT: u16 = 12;
+	pub const PGA_SHIFT: u16 = 9;
+	pub const MODE_SINGLE: u16 = 1 << 8;
+	/// 128 SPS for ADS1115, 1600 SPS for ADS1015
+	pub const DR_DEFAULT: u16 = 4 << 5;
+	pub const COMP_DISABLE: u16 = 0x0003;
+}
+
+mod bme280 {
+	pub const CHIP_ID: u8 = 0xD0;
+	pub const CHIP_ID_VAL: u8 = 0x60;
+	pub const CALIB_00: u8 = 0x88;
+	pub const CALIB_26: u8 = 0xE1;
+	pub const CTRL_HUM: u8 = 0xF2;
+	pub const STATUS: u8 = 0xF3;
+	pub const CTRL_MEAS: u8 = 0xF4;
+	pub const DATA: u8 = 0xF7;
+
+	pub const STATUS_MEASURING: u8 = 1 << 3;
+	/// humidity oversampling x1
+	pub const OSRS_H_X1: u8 = 0x01;
+	/// temperature and pressure oversampling x1, forced mode
+	pub const MEAS_FORCED_X1: u8 = (1 << 5) | (1 << 2) | 0x01;
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorChip {
+	ADS1115,
+	ADS1015,
+	LM75,
+	TMP102,
+	BME280
+}
+
+/// ADC input, single-ended against GND or differential pair
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum AdcInput {
+	A0,
+	A1,
+	A2,
+	A3,
+	A0A1,
+	A0A3,
+	A1A3,
+	A2A3
+}
+
+impl AdcInput {
+	fn mux(&self) -> u16 {
+		match self {
+			Self::A0A1 => 0,
+			Self::A0A3 => 1,
+			Self::A1A3 => 2,
+			Self::A2A3 => 3,
+			Self::A0 => 4,
+			Self::A1 => 5,
+			Self::A2 => 6,
+			Self::A3 => 7
+		}
+	}
+}
+
+/// ADC channel, `value = volts * scale + offset`
+#[derive(Deserialize)]
+pub struct AdcChannelConfig {
+	name: String,
+	input: AdcInput,
+	/// Full scale range in mV: 6144, 4096, 2048, 1024, 512 or 256
+	fsr_mv: u16,
+	#[serde(default = "scale_def")]
+	scale: f64,
+	#[serde(default)]
+	offset: f64,
+	#[serde(default = "unit_def")]
+	unit: String
+}
+
+fn scale_def() -> f64 {
+	1.0
+}
+
+fn unit_def() -> String {
+	String::from("V")
+}
+
+#[derive(Deserialize)]
+pub struct SensorConfig {
+	pub name: String,
+	pub chip: SensorChip,
+	pub addr: u8,
+	#[serde(default)]
+	channels: Vec<AdcChannelConfig>
+}
+
+pub struct Reading {
+	pub name: String,
+	pub value: f64,
+	pub unit: String
+}
+
+pub trait Sensor: Send {
+	fn init(&mut self, bus: &mut dyn I2cBus) -> Result<(), String>;
+	fn read(&mut self, bus: &mut dyn I2cBus) -> Result<Vec<Reading>, String>;
+}
+
+impl SensorConfig {
+	pub fn driver(&self) -> Result<Box<dyn Sensor>, String> {
+		match self.chip {
+			SensorChip::ADS1115 | SensorChip::ADS1015 => {
+				let mut channels = Vec::new();
+				for ch in &self.channels {
+					channels.push(AdcChannel {
+						name: format!("{}.{}", self.name, ch.name),
+						mux: ch.input.mux(),
+						pga: pga_bits(ch.fsr_mv)?,
+						fsr: ch.fsr_mv as f64 / 1000.0,
+						scale: ch.scale,
+						offset: ch.offset,
+						unit: ch.unit.clone()
+					});
+				}
+				Ok(Box::new(Ads1x15 { addr: self.addr, is_1015: self.chip == SensorChip::ADS1015, channels }))
+			},
+			SensorChip::LM75 => Ok(Box::new(Thermometer { name: self.name.clone(), addr: self.addr, shift: 5, lsb: 0.125 })),
+			SensorChip::TMP102 => Ok(Box::new(Thermometer { name: self.name.clone(), addr: self.addr, shift: 4, lsb: 0.0625 })),
+			SensorChip::BME280 => Ok(Box::new(Bme280 { name: self.name.clone(), addr: self.addr, calib: None }))
+		}
+	}
+}
+
+fn pga_bits(fsr_mv: u16) -> Result<u16, String> {
+	match fsr_mv {
+		6144 => Ok(0),
+		4096 => Ok(1),
+		2048 => Ok(2),
+		1024 => Ok(3),
+		512 => Ok(4),
+		256 => Ok(5),
+		_ => Err(format!("Unsupported ADC full scale range {} mV", fsr_mv))
+	}
+}
+
+fn read_be16(bus: &mut dyn I2cBus, addr: u8, reg: u8) -> Result<u16, String> {
+	let mut buf = [0u8;2];
+	read_reg(bus, addr, reg, &mut buf)?;
+	Ok(u16::from_be_bytes(buf))
+}
+
+struct AdcChannel {
+	name: String,
+	mux: u16,
+	pga: u16,
+	/// Full scale range, V
+	fsr: f64,
+	scale: f64,
+	offset: f64,
+	unit: String
+}
+
+/// ADS1115 (16 bit) and ADS1015 (12 bit) in single-shot mode
+struct Ads1x15 {
+	addr: u8,
+	is_1015: bool,
+	channels: Vec<AdcChannel>
+}
+
+impl Ads1x15 {
+	fn convert(&self, bus: &mut dyn I2cBus, ch: &AdcChannel) -> Result<f64, String> {
+		let config = ads1x15::OS | (ch.mux << ads1x15::MUX_SHIFT) | (ch.pga << ads1x15::PGA_SHIFT)
+			| ads1x15::MODE_SINGLE | ads1x15::DR_DEFAULT | ads1x15::COMP_DISABLE;
+		let [hi, lo] = config.to_be_bytes();
+		write(bus, self.addr, &[ads1x15::CONFIG, hi, lo])?;
+		let start = Instant::now();
+		// OS reads 0 while conversion is in progress
+		while read_be16(bus, self.addr, ads1x15::CONFIG)? & ads1x15::OS == 0 {
+			if start.elapsed() >= CONVERSION_TIMEOUT {
+				return Err(format!("ADC conversion timeout at addr {}", self.addr));
+			}
+			thread::sleep(CONVERSION_POLL);
+		}
+		let raw = read_be16(bus, self.addr, ads1x15::CONVERSION)? as i16;
+		let volts = if self.is_1015 {
+			(raw >> 4) as f64 * ch.fsr / 2048.0
+		} else {
+			raw as f64 * ch.fsr / 32768.0
+		};
+		Ok(volts)
+	}
+}
+
+impl Sensor for Ads1x15 {
+	fn init(&mut self, bus: &mut dyn I2cBus) -> Result<(), String> {
+		read_be16(bus, self.addr, ads1x15::CONFIG).map(|_| ())
+	}
+
+	fn read(&mut self, bus: &mut dyn I2cBus) -> Result<Vec<Reading>, String> {
+		let mut readings = Vec::new();
+		for ch in &self.channels {
+			let volts = self.convert(bus, ch)?;
+			readings.push(Reading {
+				name: ch.name.clone(),
+				value: volts * ch.scale + ch.offset,
+				unit: ch.unit.clone()
+			});
+		}
+		Ok(readings)
+	}
+}
+
+/// LM75 and TMP102, temperature register is left aligned two's complement
+struct Thermometer {
+	name: String,
+	addr: u8,
+	shift: u8,
+	/// Degrees per LSB
+	lsb: f64
+}
+
+impl Sensor for Thermometer {
+	fn init(&mut self, bus: &mut dyn I2cBus) -> Result<(), String> {
+		read_be16(bus, self.addr, 0x00).map(|_| ())
+	}
+
+	fn read(&mut self, bus: &mut dyn I2cBus) -> Result<Vec<Reading>, String> {
+		let raw = read_be16(bus, self.addr, 0x00)? as i16;
+		Ok(vec![Reading {
+			name: self.name.clone(),
+			value: (raw >> self.shift) as f64 * self.lsb,
+			unit: String::from("°C")
+		}])
+	}
+}
+
+/// Trimming parameters of BME280
+struct Bme280Calib {
+	t1: f64, t2: f64, t3: f64,
+	p1: f64, p2: f64, p3: f64, p4: f64, p5: f64, p6: f64, p7: f64, p8: f64, p9: f64,
+	h1: f64, h2: f64, h3: f64, h4: f64, h5: f64, h6: f64
+}
+
+impl Bme280Calib {
+	fn parse(c: &[u8;26], h: &[u8;7]) -> Self {
+		let u16le = |i: usize| u16::from_le_bytes([c[i], c[i + 1]]) as f64;
+		let i16le = |i: usize| i16::from_le_bytes([c[i], c[i + 1]]) as f64;
+		Self {
+			t1: u16le(0), t2: i16le(2), t3: i16le(4),
+			p1: u16le(6), p2: i16le(8), p3: i16le(10), p4: i16le(12), p5: i16le(14),
+			p6: i16le(16), p7: i16le(18), p8: i16le(20), p9: i16le(22),
+			h1: c[25] as f64,
+			h2: i16::from_le_bytes([h[0], h[1]]) as f64,
+			h3: h[2] as f64,
+			h4: (((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16) as f64,
+			h5: (((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16) as f64,
+			h6: h[6] as i8 as f64
+		}
+	}
+
+	/// Floating point compensation from BME280 datasheet, returns °C, hPa, %RH
+	fn compensate(&self, adc_t: f64, adc_p: f64, adc_h: f64) -> (f64, f64, f64) {
+		let v1 = (adc_t / 16384.0 - self.t1 / 1024.0) * self.t2;
+		let v2 = (adc_t / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+		let t_fine = v1 + v2;
+		let temp = t_fine / 5120.0;
+
+		let mut v1 = t_fine / 2.0 - 64000.0;
+		let mut v2 = v1 * v1 * self.p6 / 32768.0;
+		v2 += v1 * self.p5 * 2.0;
+		v2 = v2 / 4.0 + self.p4 * 65536.0;
+		v1 = (self.p3 * v1 * v1 / 524288.0 + self.p2 * v1) / 524288.0;
+		v1 = (1.0 + v1 / 32768.0) * self.p1;
+		let press = if v1 == 0.0 {
+			0.0
+		} else {
+			let mut p = 1048576.0 - adc_p;
+			p = (p - v2 / 4096.0) * 6250.0 / v1;
+			let v1 = self.p9 * p * p / 2147483648.0;
+			let v2 = p * self.p8 / 32768.0;
+			p + (v1 + v2 + self.p7) / 16.0
+		};
+
+		let mut h = t_fine - 76800.0;
+		h = (adc_h - (self.h4 * 64.0 + self.h5 / 16384.0 * h))
+			* (self.h2 / 65536.0 * (1.0 + self.h6 / 67108864.0 * h * (1.0 + self.h3 / 67108864.0 * h)));
+		h *= 1.0 - self.h1 * h / 524288.0;
+		(temp, press / 100.0, h.clamp(0.0, 100.0))
+	}
+}
+
+/// BME280 temperature, pressure and humidity sensor in forced mode
+struct Bme280 {
+	name: String,
+	addr: u8,
+	calib: Option<Bme280Calib>
+}
+
+impl Sensor for Bme280 {
+	fn init(&mut self, bus: &mut dyn I2cBus) -> Result<(), String> {
+		let mut id = [0u8;1];
+		read_reg(bus, self.addr, bme280::CHIP_ID, &mut id)?;
+		if id[0] != bme280::CHIP_ID_VAL {
+			return Err(format!("Unexpected BME280 chip id 0x{:02X}", id[0]));
+		}
+		let mut c = [0u8;26];
+		let mut h = [0u8;7];
+		read_reg(bus, self.addr, bme280::CALIB_00, &mut c)?;
+		read_reg(bus, self.addr, bme280::CALIB_26, &mut h)?;
+		self.calib = Some(Bme280Calib::parse(&c, &h));
+		write(bus, self.addr, &[bme280::CTRL_HUM, bme280::OSRS_H_X1])
+	}
+
+	fn read(&mut self, bus: &mut dyn I2cBus) -> Result<Vec<Reading>, String> {
+		let calib = match &self.calib {
+			Some(calib) => calib,
+			None => return Err(String::from("BME280 is not initialized"))
+		};
+		write(bus, self.addr, &[bme280::CTRL_MEAS, bme280::MEAS_FORCED_X1])?;
+		let start = Instant::now();
+		let mut status = [bme280::STATUS_MEASURING];
+		while status[0] & bme280::STATUS_MEASURING != 0 {
+			if start.elapsed() >= CONVERSION_TIMEOUT {
+				return Err(format!("BME280 measurement timeout at addr {}", self.addr));
+			}
+			thread::sleep(CONVERSION_POLL);
+			read_reg(bus, self.addr, bme280::STATUS, &mut status)?;
+		}
+		let mut d = [0u8;8];
+		read_reg(bus, self.addr, bme280::DATA, &mut d)?;
+		let adc_p = ((d[0] as u32) << 12 | (d[1] as u32) << 4 | (d[2] as u32) >> 4) as f64;
+		let adc_t = ((d[3] as u32) << 12 | (d[4] as u32) << 4 | (d[5] as u32) >> 4) as f64;
+		let adc_h = ((d[6] as u32) << 8 | d[7] as u32) as f64;
+		let (temp, press, hum) = calib.compensate(adc_t, adc_p, adc_h);
+		Ok(vec![
+			Reading { name: format!("{}.temperature", self.name), value: temp, unit: String::from("°C") },
+			Reading { name: format!("{}.pressure", self.name), value: press, unit: String::from("hPa") },
+			Reading { name: format!("{}.humidity", self.name), value: hum, unit: String::from("%") }
+		])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::i2cmock::{MockBus, MockDevice};
+
+	/// 16-bit big endian registers selected by pointer byte, as ADS1x15 and LM75
+	#[derive(Default)]
+	struct WordRegs {
+		regs: [u16;4],
+		ptr: usize
+	}
+
+	impl MockDevice for WordRegs {
+		fn write(&mut self, data: &[u8]) {
+			self.ptr = data[0] as usize;
+			if let [_, hi, lo] = data {
+				self.regs[self.ptr] = u16::from_be_bytes([*hi, *lo]);
+			}
+		}
+
+		fn read(&mut self, buf: &mut [u8]) {
+			buf.copy_from_slice(&self.regs[self.ptr].to_be_bytes());
+		}
+	}
+
+	/// 8-bit registers with auto-increment, as BME280
+	struct ByteRegs {
+		regs: [u8;256],
+		ptr: usize
+	}
+
+	impl MockDevice for ByteRegs {
+		fn write(&mut self, data: &[u8]) {
+			self.ptr = data[0] as usize;
+			for val in &data[1..] {
+				self.regs[self.ptr] = *val;
+				self.ptr += 1;
+			}
+		}
+
+		fn read(&mut self, buf: &mut [u8]) {
+			for b in buf.iter_mut() {
+				*b = self.regs[self.ptr];
+				self.ptr += 1;
+			}
+		}
+	}
+
+	fn sensor(text: &str) -> Box<dyn Sensor> {
+		toml::from_str::<SensorConfig>(text).unwrap().driver().unwrap()
+	}
+
+	fn read_words(text: &str, regs: &[(usize, u16)]) -> (Vec<Reading>, Arc<Mutex<WordRegs>>) {
+		let chip = Arc::new(Mutex::new(WordRegs::default()));
+		for (reg, val) in regs {
+			chip.lock().unwrap().regs[*reg] = *val;
+		}
+		let mut bus = MockBus::new();
+		bus.attach(0x48, &chip);
+		let mut sensor = sensor(text);
+		sensor.init(&mut bus).unwrap();
+		(sensor.read(&mut bus).unwrap(), chip)
+	}
+
+	const ADC: &str = r#"
+		name = "adc"
+		chip = "ADS1115"
+		addr = 0x48
+		channels = [{ name = "pressure", input = "A0", fsr_mv = 4096, scale = 2.5, offset = -1.25, unit = "bar" }]
+	"#;
+
+	#[test]
+	fn ads1115_single_shot_is_scaled() {
+		let (r, chip) = read_words(ADC, &[(ads1x15::CONVERSION as usize, 0x4000)]);
+		// OS, A0 to GND, 4.096 V, single shot, default rate, no comparator
+		assert_eq!(chip.lock().unwrap().regs[ads1x15::CONFIG as usize], 0xC383);
+		assert_eq!(r[0].name, "adc.pressure");
+		assert!((r[0].value - 3.87).abs() < 1e-9);
+		assert_eq!(r[0].unit, "bar");
+	}
+
+	#[test]
+	fn ads1015_drops_low_nibble() {
+		let text = ADC.replace("ADS1115", "ADS1015");
+		let (r, _) = read_words(&text, &[(ads1x15::CONVERSION as usize, 0x7FF0)]);
+		assert!((r[0].value - (2047.0 * 4.096 / 2048.0 * 2.5 - 1.25)).abs() < 1e-9);
+		let (r, _) = read_words(&text, &[(ads1x15::CONVERSION as usize, 0xFFF0)]);
+		assert!((r[0].value - (-0.002 * 2.5 - 1.25)).abs() < 1e-9);
+	}
+
+	#[test]
+	fn thermometers_convert_twos_complement() {
+		let lm75 = "name = \"t\"\nchip = \"LM75\"\naddr = 0x48";
+		assert_eq!(read_words(lm75, &[(0, 0x1980)]).0[0].value, 25.5);
+		assert_eq!(read_words(lm75, &[(0, 0xE700)]).0[0].value, -25.0);
+		let tmp102 = lm75.replace("LM75", "TMP102");
+		assert_eq!(read_words(&tmp102, &[(0, 0x1910)]).0[0].value, 25.0625);
+		assert_eq!(read_words(&tmp102, &[(0, 0xE700)]).0[0].value, -25.0);
+	}
+
+	#[test]
+	fn bme280_compensation_matches_datasheet_example() {
+		let mut regs = [0u8;256];
+		regs[bme280::CHIP_ID as usize] = bme280::CHIP_ID_VAL;
+		let calib: [i32;12] = [27504, 26435, -1000, 36477, -10685, 3024, 2855, 140, -7, 15500, -14600, 6000];
+		for (i, val) in calib.iter().enumerate() {
+			let reg = bme280::CALIB_00 as usize + i * 2;
+			regs[reg..reg + 2].copy_from_slice(&(*val as u16).to_le_bytes());
+		}
+		// H1 = 75, H2 = 362, H3 = 0, H4 = 325 and H5 = 50 share nibbles of 0xE5, H6 = 30
+		regs[0xA1] = 75;
+		regs[0xE1..0xE8].copy_from_slice(&[0x6A, 0x01, 0x00, 0x14, 0x25, 0x03, 0x1E]);
+		// adc_P = 415148, adc_T = 519888, adc_H = 0x6C00
+		regs[bme280::DATA as usize..bme280::DATA as usize + 8]
+			.copy_from_slice(&[0x65, 0x5A, 0xC0, 0x7E, 0xED, 0x00, 0x6C, 0x00]);
+		let chip = Arc::new(Mutex::new(ByteRegs { regs, ptr: 0 }));
+		let mut bus = MockBus::new();
+		bus.attach(0x76, &chip);
+		let mut sensor = sensor("name = \"cab\"\nchip = \"BME280\"\naddr = 0x76");
+		sensor.init(&mut bus).unwrap();
+		let r = sensor.read(&mut bus).unwrap();
+		assert!((r[0].value - 25.0825).abs() < 1e-3);
+		assert!((r[1].value - 1006.5327).abs() < 1e-3);
+		assert!((r[2].value - 37.6043).abs() < 1e-3);
+		assert_eq!(chip.lock().unwrap().regs[bme280::CTRL_MEAS as usize], bme280::MEAS_FORCED_X1);
+	}
+}
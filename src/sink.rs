@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::framebuffer::{Framebuffer, LEVEL_MAX};
+use crate::frameformat::FrameFormat;
+
+/// Pixels of PNG snapshot per LED
+const PNG_SCALE: u32 = 8;
+/// Radius of LED dot in PNG, relative to cell size
+const PNG_DOT_RADIUS: f32 = 0.4;
+/// Color of LED which is off, so the dot grid stays visible
+const PNG_OFF_COLOR: [u8;3] = [24, 24, 24];
+const PNG_BACKGROUND: [u8;3] = [0, 0, 0];
+
+/// Receiver of frames. Takes the same draw data `send_draw_data` sends after the break.
+pub trait FrameSink: Send {
+	fn send(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+/// Color of LED at brightness level
+fn led_color(level: u8) -> [u8;3] {
+	let v = (level as u32 * 255 / LEVEL_MAX as u32) as u8;
+	[v, v, v]
+}
+
+/// Write frame as PNG, every LED is a round dot on black background
+pub fn write_png(fb: &Framebuffer, path: &str) -> Result<(), String> {
+	let (w, h) = (fb.width() * PNG_SCALE, fb.height() * PNG_SCALE);
+	let mut rgb = Vec::with_capacity((w * h * 3) as usize);
+	let r2 = (PNG_DOT_RADIUS * PNG_SCALE as f32).powi(2);
+	let center = PNG_SCALE as f32 / 2.0;
+	for y in 0..h {
+		for x in 0..w {
+			let dx = (x % PNG_SCALE) as f32 + 0.5 - center;
+			let dy = (y % PNG_SCALE) as f32 + 0.5 - center;
+			let color = if dx * dx + dy * dy > r2 {
+				PNG_BACKGROUND
+			} else {
+				match fb.pixel((x / PNG_SCALE) as i32, (y / PNG_SCALE) as i32) {
+					0 => PNG_OFF_COLOR,
+					level => led_color(level)
+				}
+			};
+			rgb.extend_from_slice(&color);
+		}
+	}
+	let file = File::create(path).map_err(|e| format!("Fail to create {}: {}", path, e))?;
+	let mut encoder = png::Encoder::new(BufWriter::new(file), w, h);
+	encoder.set_color(png::ColorType::Rgb);
+	encoder.set_depth(png::BitDepth::Eight);
+	encoder.write_header()
+		.and_then(|mut writer| writer.write_image_data(&rgb))
+		.map_err(|e| format!("Fail to write PNG {}: {}", path, e))
+}
+
+/// Frame as ANSI truecolor text, one character is two pixel rows drawn by upper half block
+pub fn ansi_frame(fb: &Framebuffer) -> String {
+	let mut out = String::new();
+	for y in (0..fb.height() as i32).step_by(2) {
+		for x in 0..fb.width() as i32 {
+			let [tr, tg, tb] = led_color(fb.pixel(x, y));
+			let [br, bg, bb] = led_color(fb.pixel(x, y + 1));
+			out.push_str(&format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}", tr, tg, tb, br, bg, bb));
+		}
+		out.push_str("\x1b[0m\n");
+	}
+	out
+}
+
+/// PNG snapshot of every frame. `{}` in path is replaced by frame number,
+/// without it the file always holds the last frame.
+pub struct PngSink {
+	path: String,
+	format: FrameFormat,
+	count: u64
+}
+
+impl PngSink {
+	pub fn new(path: &str, format: FrameFormat) -> Self {
+		Self { path: path.to_string(), format, count: 0 }
+	}
+}
+
+impl FrameSink for PngSink {
+	fn send(&mut self, data: &[u8]) -> Result<(), String> {
+		let fb = self.format.decode(data)?;
+		let path = self.path.replace("{}", &format!("{:06}", self.count));
+		self.count += 1;
+		write_png(&fb, &path)
+	}
+}
+
+/// Live preview in terminal, frame is redrawn in the top left corner
+pub struct TermSink {
+	format: FrameFormat,
+	cleared: bool
+}
+
+impl TermSink {
+	pub fn new(format: FrameFormat) -> Self {
+		Self { format, cleared: false }
+	}
+}
+
+impl FrameSink for TermSink {
+	fn send(&mut self, data: &[u8]) -> Result<(), String> {
+		let fb = self.format.decode(data)?;
+		let mut out = std::io::stdout().lock();
+		let res = if self.cleared {
+			// redraw in place, other output continues where it was
+			write!(out, "\x1b[s\x1b[H{}\x1b[u", ansi_frame(&fb))
+		} else {
+			// first frame leaves cursor below the preview
+			self.cleared = true;
+			write!(out, "\x1b[2J\x1b[H{}", ansi_frame(&fb))
+		};
+		res.and_then(|_| out.flush()).map_err(|e| format!("Fail to write to terminal: {}", e))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::BufReader;
+
+	use super::*;
+
+	/// 2x2 panel: full, off / off, level 5
+	fn draw_data(format: &FrameFormat) -> Vec<u8> {
+		let mut fb = format.frame();
+		fb.set_pixel(0, 0, LEVEL_MAX);
+		fb.set_pixel(1, 1, 5);
+		format.encode(&fb)
+	}
+
+	#[test]
+	fn png_and_ansi_golden() {
+		let format = FrameFormat { panel_width: 2, panel_height: 2, ..Default::default() };
+		let data = draw_data(&format);
+
+		let path = std::env::temp_dir().join(format!("wshmch-sink-{}.png", std::process::id()));
+		PngSink::new(path.to_str().unwrap(), format.clone()).send(&data).unwrap();
+		let decoder = png::Decoder::new(BufReader::new(File::open(&path).unwrap()));
+		let mut reader = decoder.read_info().unwrap();
+		let mut rgb = vec![0; reader.output_buffer_size().unwrap()];
+		let info = reader.next_frame(&mut rgb).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!((info.width, info.height), (2 * PNG_SCALE, 2 * PNG_SCALE));
+		let at = |x: u32, y: u32| -> [u8;3] {
+			let i = ((y * info.width + x) * 3) as usize;
+			[rgb[i], rgb[i + 1], rgb[i + 2]]
+		};
+		let mid = PNG_SCALE / 2;
+		assert_eq!(at(mid, mid), [255, 255, 255]);
+		assert_eq!(at(PNG_SCALE + mid, mid), PNG_OFF_COLOR);
+		assert_eq!(at(PNG_SCALE + mid, PNG_SCALE + mid), [85, 85, 85]);
+		assert_eq!(at(0, 0), PNG_BACKGROUND);
+
+		let ansi = ansi_frame(&format.decode(&data).unwrap());
+		assert_eq!(ansi, concat!(
+			"\x1b[38;2;255;255;255m\x1b[48;2;0;0;0m\u{2580}",
+			"\x1b[38;2;0;0;0m\x1b[48;2;85;85;85m\u{2580}",
+			"\x1b[0m\n"));
+	}
+}
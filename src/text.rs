@@ -0,0 +1,110 @@
+use serde::Deserialize;
+
+use crate::font::Font;
+use crate::framebuffer::Framebuffer;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Align {
+	Left,
+	Center,
+	Right
+}
+
+/// Draw single line with pen starting at `x`, `baseline` is the lowest row above descent.
+/// Returns pen position after text.
+pub fn draw_line(fb: &mut Framebuffer, font: &Font, text: &str, x: i32, baseline: i32, level: u8) -> i32 {
+	let mut pen = x;
+	for c in text.chars() {
+		let glyph = match font.glyph(c) {
+			Some(glyph) => glyph,
+			None => continue
+		};
+		let top = baseline - (glyph.y_off + glyph.height as i32 - 1);
+		for gy in 0..glyph.height {
+			for gx in 0..glyph.width {
+				if glyph.pixel(gx, gy) {
+					fb.set_pixel(pen + glyph.x_off + gx as i32, top + gy as i32, level);
+				}
+			}
+		}
+		pen += glyph.advance;
+	}
+	pen
+}
+
+/// Split text by '\n' and wrap words of lines longer than `width`.
+/// Word longer than `width` is left on its own line.
+pub fn wrap(font: &Font, text: &str, width: i32) -> Vec<String> {
+	let mut lines = Vec::new();
+	for para in text.split('\n') {
+		let mut line = String::new();
+		for word in para.split(' ') {
+			let candidate = if line.is_empty() { word.to_string() } else { format!("{} {}", line, word) };
+			if !line.is_empty() && font.text_width(&candidate) > width {
+				lines.push(std::mem::replace(&mut line, word.to_string()));
+			} else {
+				line = candidate;
+			}
+		}
+		lines.push(line);
+	}
+	lines
+}
+
+/// Height of `lines` lines of text
+pub fn text_height(font: &Font, lines: usize) -> i32 {
+	lines as i32 * font.line_height()
+}
+
+/// Draw lines from `top`, each line is aligned across frame width
+pub fn draw_lines(fb: &mut Framebuffer, font: &Font, lines: &[String], top: i32, align: Align, level: u8) {
+	let width = fb.width() as i32;
+	for (i, line) in lines.iter().enumerate() {
+		let w = font.text_width(line);
+		let left = match align {
+			Align::Left => 0,
+			Align::Center => (width - w) / 2,
+			Align::Right => width - w
+		};
+		let baseline = top + i as i32 * font.line_height() + font.ascent - 1;
+		draw_line(fb, font, line, left, baseline, level);
+	}
+}
+
+/// Wrap text to frame width and draw it centred vertically
+pub fn draw_text(fb: &mut Framebuffer, font: &Font, text: &str, align: Align, level: u8) {
+	let lines = wrap(font, text, fb.width() as i32);
+	let top = (fb.height() as i32 - text_height(font, lines.len())) / 2;
+	draw_lines(fb, font, &lines, top, align, level);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn words_wrap_at_width() {
+		let font = Font::builtin("4x6").unwrap();
+		assert_eq!(wrap(&font, "ab cd efghij\nx y", 16), vec!["ab", "cd", "efghij", "x y"]);
+		assert_eq!(wrap(&font, "", 16), vec![""]);
+	}
+
+	#[test]
+	fn alignment_shifts_line() {
+		let font = Font::builtin("4x6").unwrap();
+		let lines = vec![String::from("ab")];
+		let render = |align| {
+			let mut fb = Framebuffer::new(16, 6);
+			draw_lines(&mut fb, &font, &lines, 0, align, 1);
+			fb
+		};
+		let (left, center, right) = (render(Align::Left), render(Align::Center), render(Align::Right));
+		assert!((0..6).any(|y| (0..8).any(|x| left.pixel(x, y) != 0)));
+		for y in 0..6 {
+			for x in 0..8 {
+				assert_eq!(left.pixel(x, y), center.pixel(x + 4, y));
+				assert_eq!(left.pixel(x, y), right.pixel(x + 8, y));
+			}
+		}
+	}
+}
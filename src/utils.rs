@@ -75,6 +75,7 @@ fn incontroller_handler(tx: Sender<char>) {
 	let mut stdin = std::io::stdin().lock();
 	let mut buf = String::new();
 	loop {
+		buf.clear();
 		stdin.read_line(&mut buf).unwrap();
 		let c = buf.chars().next().unwrap();
 		tx.send(c).unwrap();
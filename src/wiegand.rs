@@ -1,7 +1,8 @@
 use std::time::{Duration, Instant};
 
-use gpio::sysfs::SysFsGpioInput;
-use gpio::{GpioIn, GpioValue};
+use gpio::GpioValue;
+
+use crate::gpioline::{self, InputPin, PinConfig};
 
 const CUTOFF_DEF: Duration = Duration::from_millis(25);
 const POLL_DERIOD_DEF: Duration = Duration::from_micros(500);
@@ -15,8 +16,8 @@ pub struct WiegandMsg {
 }
 
 pub struct Wiegand {
-	pin_0: SysFsGpioInput,
-	pin_1: SysFsGpioInput,
+	pin_0: Box<dyn InputPin>,
+	pin_1: Box<dyn InputPin>,
 
 	cutoff: Duration,
 	poll_period: Duration,
@@ -33,10 +34,10 @@ pub struct Wiegand {
 }
 
 impl Wiegand {
-	pub fn new(pin_0: u16, pin_1: u16) -> Result<Self, std::io::Error> {
+	pub fn new(pin_0: &PinConfig, pin_1: &PinConfig) -> Result<Self, std::io::Error> {
 		Ok(Self {
-			pin_0: SysFsGpioInput::open(pin_0)?,
-			pin_1: SysFsGpioInput::open(pin_1)?,
+			pin_0: gpioline::open_input(pin_0)?,
+			pin_1: gpioline::open_input(pin_1)?,
 
 			cutoff: CUTOFF_DEF,
 			poll_period: POLL_DERIOD_DEF,
@@ -81,7 +82,7 @@ impl Wiegand {
 	pub fn poll(&mut self) -> Option<WiegandMsg> {
 		if self.tl_poll.elapsed() >= self.poll_period {
 			self.tl_poll = Instant::now();
-			if self.pin_0.read_value().unwrap() == self.active_level && self.pin_0_release {
+			if self.pin_0.read().unwrap() == self.active_level && self.pin_0_release {
 				self.tl_active = Instant::now();
 				self.pin_0_release = false;
 				if self.pos < self.max_order {
@@ -91,7 +92,7 @@ impl Wiegand {
 				None
 			} else {
 				self.pin_0_release = true;
-				if self.pin_1.read_value().unwrap() == self.active_level {
+				if self.pin_1.read().unwrap() == self.active_level {
 					self.tl_active = Instant::now();
 					self.pin_1_release = false;
 					if self.pos < self.max_order {
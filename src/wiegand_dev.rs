@@ -4,11 +4,12 @@ use serde::Deserialize;
 
 use crate::{utils, wiegand::Wiegand};
 use crate::intio::PinLevel;
+use crate::gpioline::PinConfig;
 
 #[derive(Deserialize)]
 pub struct WiegandConfig {
-	pin_0: u16,
-	pin_1: u16,
+	pin_0: PinConfig,
+	pin_1: PinConfig,
 	poll_delay_us: u64,
 	cutoff_time_ms: u64,
 	active_level: PinLevel
@@ -17,7 +18,7 @@ pub struct WiegandConfig {
 pub fn test(config: &WiegandConfig) -> Result<(), String> {
 	println!("\n[WIEGAND] Test begin..");
 	println!("lean the card to reader, in cosole should be its number..");
-	let mut wg = Wiegand::new(config.pin_0, config.pin_1).unwrap();
+	let mut wg = Wiegand::new(&config.pin_0, &config.pin_1).unwrap();
 	wg.set_cutoff(Duration::from_millis(config.cutoff_time_ms));
 	wg.set_poll_period(Duration::from_micros(config.poll_delay_us));
 	wg.set_active_level(config.active_level.as_gpioval());